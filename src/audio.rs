@@ -0,0 +1,234 @@
+// Reads tags, duration and embedded cover art from uploaded audio (ID3, Vorbis comments and
+// FLAC metadata blocks all come through symphonia's metadata API), and decodes it to build
+// waveform peaks for the player.
+use std::fs::File;
+use std::path::Path;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey, StandardVisualKey};
+use symphonia::core::probe::Hint;
+
+// Number of peaks in each waveform resolution, coarsest first. The page picks whichever
+// suits the width it draws at.
+const WAVEFORM_RESOLUTIONS: [usize; 3] = [64, 256, 1024];
+
+// Frames folded into each peak while decoding, before the peaks are resampled to the
+// resolutions above
+const PEAK_BLOCK_FRAMES: usize = 256;
+
+#[derive(Default)]
+pub struct AudioDetails {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track_number: Option<u32>,
+    // Length of the track in seconds
+    pub duration: Option<f64>,
+    pub cover_art: Option<CoverArt>,
+}
+
+pub struct CoverArt {
+    pub data: Vec<u8>,
+    // File extension matching the image's MIME type
+    pub extension: &'static str,
+}
+
+// Read whatever details the file carries. Missing or unreadable tags are simply left empty.
+fn probe(path: &Path, extension: &str) -> Result<symphonia::core::probe::ProbeResult, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+
+    // Uploads sit in temporary files, so the extension comes from the original filename
+    let mut hint = Hint::new();
+    hint.with_extension(extension);
+
+    symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|e| e.to_string())
+}
+
+pub fn read_details(path: &Path, extension: &str) -> Result<AudioDetails, String> {
+    let mut probed = probe(path, extension)?;
+
+    let mut details = AudioDetails::default();
+
+    // Tags can come before the container (ID3v2 on mp3) or inside it (Vorbis comments, FLAC)
+    if let Some(metadata) = probed.metadata.get() {
+        if let Some(revision) = metadata.current() {
+            apply_revision(&mut details, revision);
+        }
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        apply_revision(&mut details, revision);
+    }
+
+    if let Some(track) = probed.format.default_track() {
+        let params = &track.codec_params;
+        let track_id = track.id;
+        if let (Some(frames), Some(rate)) = (params.n_frames, params.sample_rate) {
+            details.duration = Some(frames as f64 / rate as f64);
+        } else if let Some(time_base) = params.time_base {
+            // Without a frame count in the header (e.g. mp3 without a Xing frame), add up
+            // packet durations. Packets are only read, not decoded.
+            let mut total = 0;
+            while let Ok(packet) = probed.format.next_packet() {
+                if packet.track_id() == track_id {
+                    total += packet.dur;
+                }
+            }
+            let time = time_base.calc_time(total);
+            details.duration = Some(time.seconds as f64 + time.frac);
+        }
+    }
+
+    Ok(details)
+}
+
+fn apply_revision(details: &mut AudioDetails, revision: &MetadataRevision) {
+    for tag in revision.tags() {
+        let value = tag.value.to_string().trim().to_string();
+        if value.is_empty() {
+            continue;
+        }
+        match tag.std_key {
+            Some(StandardTagKey::TrackTitle) => details.title = Some(value),
+            Some(StandardTagKey::Artist) => details.artist = Some(value),
+            Some(StandardTagKey::AlbumArtist) if details.artist.is_none() => {
+                details.artist = Some(value)
+            }
+            Some(StandardTagKey::Album) => details.album = Some(value),
+            // Often written as "3/12"
+            Some(StandardTagKey::TrackNumber) => {
+                details.track_number = value
+                    .split('/')
+                    .next()
+                    .and_then(|number| number.trim().parse().ok())
+            }
+            _ => {}
+        }
+    }
+
+    // Prefer the front cover, but take any picture if that is all there is
+    let visuals = revision.visuals();
+    let visual = visuals
+        .iter()
+        .find(|visual| visual.usage == Some(StandardVisualKey::FrontCover))
+        .or_else(|| visuals.first());
+    if let Some(visual) = visual {
+        let extension = match visual.media_type.to_lowercase().as_str() {
+            "image/jpeg" | "image/jpg" => Some("jpg"),
+            "image/png" => Some("png"),
+            "image/gif" => Some("gif"),
+            "image/webp" => Some("webp"),
+            _ => None,
+        };
+        if let Some(extension) = extension {
+            details.cover_art = Some(CoverArt {
+                data: visual.data.to_vec(),
+                extension,
+            });
+        }
+    }
+}
+
+// Decode the whole file and return its peaks at each of WAVEFORM_RESOLUTIONS, scaled to
+// 0-255 relative to the loudest point
+pub fn compute_waveform(path: &Path, extension: &str) -> Result<Vec<Vec<u8>>, String> {
+    let mut format = probe(path, extension)?.format;
+    let track = format
+        .default_track()
+        .ok_or_else(|| "No audio track found.".to_string())?;
+    let track_id = track.id;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|e| e.to_string())?;
+
+    let blocks = decode_peaks(format.as_mut(), decoder.as_mut(), track_id);
+    if blocks.is_empty() {
+        return Err("No audio could be decoded.".to_string());
+    }
+
+    let loudest = blocks.iter().cloned().fold(0.0, f32::max);
+    Ok(WAVEFORM_RESOLUTIONS
+        .iter()
+        .map(|&resolution| {
+            (0..resolution)
+                .map(|i| {
+                    let start = i * blocks.len() / resolution;
+                    let end = ((i + 1) * blocks.len() / resolution).max(start + 1);
+                    let peak = blocks[start..end.min(blocks.len())]
+                        .iter()
+                        .cloned()
+                        .fold(0.0, f32::max);
+                    if loudest > 0.0 {
+                        (peak / loudest * 255.0).round() as u8
+                    } else {
+                        0
+                    }
+                })
+                .collect()
+        })
+        .collect())
+}
+
+// Peak amplitude of every PEAK_BLOCK_FRAMES frames, across all channels
+fn decode_peaks(
+    format: &mut dyn FormatReader,
+    decoder: &mut dyn symphonia::core::codecs::Decoder,
+    track_id: u32,
+) -> Vec<f32> {
+    let mut blocks = Vec::new();
+    let mut block_peak = 0.0f32;
+    let mut block_frames = 0;
+    let mut samples: Option<SampleBuffer<f32>> = None;
+
+    // Reading stops at the end of the stream or at the first unreadable packet
+    while let Ok(packet) = format.next_packet() {
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // Skip over corrupt packets
+            Err(symphonia::core::errors::Error::DecodeError(_)) => continue,
+            Err(_) => break,
+        };
+
+        let spec = *decoded.spec();
+        let channels = spec.channels.count().max(1);
+        let needed = decoded.capacity() * channels;
+        if samples
+            .as_ref()
+            .map(|s| s.capacity() < needed)
+            .unwrap_or(true)
+        {
+            samples = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+        }
+        let buffer = samples.as_mut().unwrap();
+        buffer.copy_interleaved_ref(decoded);
+
+        for frame in buffer.samples().chunks(channels) {
+            for sample in frame {
+                block_peak = block_peak.max(sample.abs());
+            }
+            block_frames += 1;
+            if block_frames == PEAK_BLOCK_FRAMES {
+                blocks.push(block_peak);
+                block_peak = 0.0;
+                block_frames = 0;
+            }
+        }
+    }
+
+    if block_frames > 0 {
+        blocks.push(block_peak);
+    }
+    blocks
+}
@@ -0,0 +1,237 @@
+// Content-addressed media storage. Every uploaded media file is stored once per user under
+// its SHA-256 hash, and galleries, films and audio point at those blobs. A reference count
+// in the media_blobs table decides when a blob can be removed.
+use crate::filetype::MediaKind;
+use crate::storage::Storage;
+use crate::upload::TempUpload;
+use actix_web::{web, HttpResponse};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tokio::sync::{Mutex, MutexGuard};
+
+// Storing and releasing the same blob are serialised by one of these locks, chosen by the
+// blob's hash, so a release never deletes a file a concurrent store has just counted on
+const BLOB_LOCKS: usize = 64;
+
+lazy_static! {
+    static ref LOCKS: Vec<Mutex<()>> = (0..BLOB_LOCKS).map(|_| Mutex::new(())).collect();
+}
+
+async fn lock_blob(username: &str, hash: &str) -> MutexGuard<'static, ()> {
+    let digest = Sha256::digest(format!("{}/{}", username, hash).as_bytes());
+    LOCKS[digest[0] as usize % BLOB_LOCKS].lock().await
+}
+
+// Storage key of a blob, relative to the user_pages folder
+fn blob_key(username: &str, file_name: &str) -> String {
+    format!("{}/blobs/{}", username, file_name)
+}
+
+fn kind_name(kind: MediaKind) -> &'static str {
+    match kind {
+        MediaKind::Image => "image",
+        MediaKind::Video => "video",
+        MediaKind::Audio => "audio",
+    }
+}
+
+fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Store a received file as a blob, or add a reference to an identical blob the user already
+// has. Returns the URL path items should use to refer to the blob.
+pub async fn store(
+    pool: &SqlitePool,
+    storage: &dyn Storage,
+    username: &str,
+    upload: TempUpload,
+    filename: &str,
+    kind: MediaKind,
+) -> Result<String, HttpResponse> {
+    let extension = filename
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase())
+        .unwrap_or_default();
+
+    let (upload, hash, size) = match web::block(move || {
+        let hash = hash_file(upload.path())?;
+        let size = fs::metadata(upload.path())?.len();
+        Ok::<_, std::io::Error>((upload, hash, size))
+    })
+    .await
+    {
+        Ok(Ok(hashed)) => hashed,
+        _ => return Err(HttpResponse::InternalServerError().body("Error saving file.")),
+    };
+
+    let _lock = lock_blob(username, &hash).await;
+
+    // Reuse the existing blob if the same content was uploaded before
+    let existing = sqlx::query_scalar::<_, String>(
+        "UPDATE media_blobs SET ref_count = ref_count + 1
+         WHERE username = ? AND hash = ? RETURNING file_name",
+    )
+    .bind(username)
+    .bind(&hash)
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| HttpResponse::InternalServerError().body(format!("Database error: {}", e)))?;
+
+    let file_name = match existing {
+        // The duplicate upload is dropped, which deletes its temporary file
+        Some(file_name) => file_name,
+        None => {
+            let file_name = format!("{}.{}", hash, extension);
+            if let Err(e) = storage.put(&blob_key(username, &file_name), upload).await {
+                eprintln!("Failed to store blob {}: {}", file_name, e);
+                return Err(HttpResponse::InternalServerError().body("Error saving file."));
+            }
+            let inserted = sqlx::query(
+                "INSERT INTO media_blobs (username, hash, file_name, kind, size, ref_count)
+                 VALUES (?, ?, ?, ?, ?, 1)",
+            )
+            .bind(username)
+            .bind(&hash)
+            .bind(&file_name)
+            .bind(kind_name(kind))
+            .bind(size as i64)
+            .execute(pool)
+            .await;
+            if let Err(e) = inserted {
+                let _ = storage.delete(&blob_key(username, &file_name)).await;
+                return Err(
+                    HttpResponse::InternalServerError().body(format!("Database error: {}", e))
+                );
+            }
+            file_name
+        }
+    };
+
+    Ok(format!("/user_pages/{}/blobs/{}", username, file_name))
+}
+
+// Drop one reference to the media at `url_path`, deleting the blob once nothing refers to
+// it any more. Files stored before blobs existed are deleted directly.
+pub async fn release(pool: &SqlitePool, storage: &dyn Storage, username: &str, url_path: &str) {
+    let prefix = format!("/user_pages/{}/", username);
+    let relative = match url_path.strip_prefix(&prefix) {
+        Some(relative) if !relative.contains("..") => relative,
+        _ => return,
+    };
+
+    let file_name = match relative.strip_prefix("blobs/") {
+        Some(file_name) => file_name,
+        None => {
+            let _ = fs::remove_file(format!("./user_pages/{}/{}", username, relative));
+            return;
+        }
+    };
+
+    let hash = file_name.split('.').next().unwrap_or_default();
+    let _lock = lock_blob(username, hash).await;
+
+    // The count is dropped and the row removed together, so the blob is deleted only by the
+    // release that took its count to zero
+    let unreferenced = async {
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            "UPDATE media_blobs SET ref_count = ref_count - 1 WHERE username = ? AND file_name = ?",
+        )
+        .bind(username)
+        .bind(file_name)
+        .execute(&mut tx)
+        .await?;
+        let deleted = sqlx::query(
+            "DELETE FROM media_blobs WHERE username = ? AND file_name = ? AND ref_count <= 0",
+        )
+        .bind(username)
+        .bind(file_name)
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+        tx.commit().await?;
+        Ok::<_, sqlx::Error>(deleted > 0)
+    }
+    .await;
+
+    match unreferenced {
+        Ok(true) => {
+            if let Err(e) = storage.delete(&blob_key(username, file_name)).await {
+                eprintln!("Failed to delete blob {}: {}", file_name, e);
+            }
+        }
+        Ok(false) => {}
+        Err(e) => eprintln!("Failed to release blob {}: {}", file_name, e),
+    }
+}
+
+// Bytes of blob storage used by a user for one kind of media
+pub async fn usage(pool: &SqlitePool, username: &str, kind: MediaKind) -> u64 {
+    sqlx::query_scalar::<_, i64>(
+        "SELECT COALESCE(SUM(size), 0) FROM media_blobs WHERE username = ? AND kind = ?",
+    )
+    .bind(username)
+    .bind(kind_name(kind))
+    .fetch_one(pool)
+    .await
+    .unwrap_or(0)
+    .max(0) as u64
+}
+
+// A local copy of the media at `url_path`, fetching it from the storage backend if needed
+pub async fn local_path(
+    storage: &dyn Storage,
+    username: &str,
+    url_path: &str,
+) -> std::io::Result<PathBuf> {
+    let relative = url_path
+        .strip_prefix(&format!("/user_pages/{}/", username))
+        .filter(|relative| !relative.contains(".."))
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Not a media path"))?;
+
+    match relative.strip_prefix("blobs/") {
+        Some(file_name) => storage.local_path(&blob_key(username, file_name)).await,
+        None => Ok(PathBuf::from(format!(
+            "./user_pages/{}/{}",
+            username, relative
+        ))),
+    }
+}
+
+// Size in bytes of the media at `url_path`, whether it is a blob or an older plain file
+pub async fn size(pool: &SqlitePool, username: &str, url_path: &str) -> Option<u64> {
+    let relative = url_path
+        .strip_prefix(&format!("/user_pages/{}/", username))
+        .filter(|relative| !relative.contains(".."))?;
+
+    match relative.strip_prefix("blobs/") {
+        Some(file_name) => sqlx::query_scalar::<_, i64>(
+            "SELECT size FROM media_blobs WHERE username = ? AND file_name = ?",
+        )
+        .bind(username)
+        .bind(file_name)
+        .fetch_optional(pool)
+        .await
+        .ok()
+        .flatten()
+        .map(|size| size.max(0) as u64),
+        None => fs::metadata(format!("./user_pages/{}/{}", username, relative))
+            .ok()
+            .map(|metadata| metadata.len()),
+    }
+}
@@ -0,0 +1,104 @@
+// Folders of files derived from user media, such as resized images. Anything in them can be
+// made again, so each folder is kept under a size limit by removing the least recently used
+// files. Files are touched whenever they are used, so their modification time says when they
+// were last needed.
+use chrono::Utc;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::SystemTime;
+
+// A folder is checked at most this often, in seconds
+const PRUNE_INTERVAL: i64 = 60;
+
+pub struct CacheFolder {
+    pub path: &'static str,
+    // Environment variable holding the limit in MB, and the limit when it is not set
+    limit_var: &'static str,
+    default_limit_mb: u64,
+    last_pruned: AtomicI64,
+}
+
+impl CacheFolder {
+    pub const fn new(path: &'static str, limit_var: &'static str, default_limit_mb: u64) -> Self {
+        CacheFolder {
+            path,
+            limit_var,
+            default_limit_mb,
+            last_pruned: AtomicI64::new(0),
+        }
+    }
+
+    fn limit(&self) -> u64 {
+        std::env::var(self.limit_var)
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(self.default_limit_mb)
+            .saturating_mul(1024 * 1024)
+    }
+
+    // Mark a cached file as just used
+    pub fn touch(&self, file: &Path) {
+        if let Ok(file) = fs::File::options().write(true).open(file) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+    }
+
+    // Called after adding a file. Prunes the folder if it has not been checked for a while.
+    // Blocks on the file system, so async code should run it with spawn_blocking.
+    pub fn added(&self) {
+        let now = Utc::now().timestamp();
+        let last = self.last_pruned.load(Ordering::Relaxed);
+        if now - last < PRUNE_INTERVAL
+            || self
+                .last_pruned
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        if let Err(e) = self.prune() {
+            eprintln!("Failed to prune {}: {}", self.path, e);
+        }
+    }
+
+    // Remove the least recently used files until the folder is under its limit
+    fn prune(&self) -> io::Result<()> {
+        let mut files = Vec::new();
+        let mut total = 0;
+        for entry in fs::read_dir(self.path)? {
+            let entry = entry?;
+            // Files still being written are left alone
+            if entry.file_name().to_string_lossy().ends_with(".tmp") {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() {
+                continue;
+            }
+            total += metadata.len();
+            files.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+
+        let limit = self.limit();
+        if total <= limit {
+            return Ok(());
+        }
+
+        // Prune down to 90% of the limit so the next few additions do not prune again
+        let target = limit / 10 * 9;
+        files.sort_by_key(|(modified, _, _)| *modified);
+        for (_, size, path) in files {
+            if total <= target {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => total -= size,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => total -= size,
+                Err(e) => eprintln!("Failed to remove {}: {}", path.display(), e),
+            }
+        }
+        Ok(())
+    }
+}
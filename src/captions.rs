@@ -0,0 +1,257 @@
+// Caption tracks for films. Uploads may be WebVTT or SRT; both are parsed, checked and
+// written back out as WebVTT, which is the only format browsers play in a <track>.
+use serde::{Deserialize, Serialize};
+
+// Largest caption file accepted
+pub const MAX_CAPTION_SIZE: usize = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CaptionTrack {
+    // BCP 47 language tag, e.g. "en" or "pt-BR"
+    pub language: String,
+    // Shown in the player's captions menu
+    pub label: String,
+    pub path: String,
+}
+
+struct Cue {
+    id: Option<String>,
+    // Start and end in milliseconds
+    start: u64,
+    end: u64,
+    // WebVTT cue settings such as "line:0 align:start"
+    settings: String,
+    text: Vec<String>,
+}
+
+pub fn is_valid_language(language: &str) -> bool {
+    let mut subtags = language.split('-');
+    let primary = subtags.next().unwrap_or_default();
+    (2..=3).contains(&primary.len())
+        && primary.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        })
+}
+
+pub fn is_valid_label(label: &str) -> bool {
+    !label.is_empty() && label.chars().count() <= 50 && !label.contains(['<', '>'])
+}
+
+// Parse an uploaded .vtt or .srt file and return it as WebVTT
+pub fn to_webvtt(data: &[u8], extension: &str) -> Result<String, String> {
+    let text = std::str::from_utf8(data).map_err(|_| "Captions must be UTF-8 text.".to_string())?;
+    let text = text
+        .trim_start_matches('\u{feff}')
+        .replace("\r\n", "\n")
+        .replace('\r', "\n");
+
+    let cues = match extension.to_lowercase().as_str() {
+        "vtt" => parse_webvtt(&text)?,
+        "srt" => parse_srt(&text)?,
+        _ => return Err("Captions must be a .vtt or .srt file.".to_string()),
+    };
+    if cues.is_empty() {
+        return Err("No captions found in the file.".to_string());
+    }
+
+    let mut output = String::from("WEBVTT\n");
+    for cue in cues {
+        output.push('\n');
+        if let Some(id) = cue.id {
+            output += &format!("{}\n", id);
+        }
+        output += &format!(
+            "{} --> {}",
+            format_timestamp(cue.start),
+            format_timestamp(cue.end)
+        );
+        if !cue.settings.is_empty() {
+            output += &format!(" {}", cue.settings);
+        }
+        output.push('\n');
+        for line in cue.text {
+            output += &format!("{}\n", line);
+        }
+    }
+    Ok(output)
+}
+
+// Groups of consecutive non-empty lines
+fn blocks(text: &str) -> Vec<Vec<&str>> {
+    let mut blocks = Vec::new();
+    let mut block = Vec::new();
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            if !block.is_empty() {
+                blocks.push(std::mem::take(&mut block));
+            }
+        } else {
+            block.push(line);
+        }
+    }
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+    blocks
+}
+
+fn parse_webvtt(text: &str) -> Result<Vec<Cue>, String> {
+    let blocks = blocks(text);
+    let header = blocks.first().and_then(|block| block.first()).copied();
+    match header {
+        Some(line)
+            if line == "WEBVTT" || line.starts_with("WEBVTT ") || line.starts_with("WEBVTT\t") => {}
+        _ => return Err("WebVTT files must start with \"WEBVTT\".".to_string()),
+    }
+
+    let mut cues = Vec::new();
+    for block in &blocks[1..] {
+        // Comments, style sheets and regions are left out
+        if ["NOTE", "STYLE", "REGION"]
+            .iter()
+            .any(|keyword| block[0] == *keyword || block[0].starts_with(&format!("{} ", keyword)))
+        {
+            continue;
+        }
+        cues.push(parse_cue(block, true)?);
+    }
+    Ok(cues)
+}
+
+fn parse_srt(text: &str) -> Result<Vec<Cue>, String> {
+    let mut cues = Vec::new();
+    for block in blocks(text) {
+        cues.push(parse_cue(&block, false)?);
+    }
+    Ok(cues)
+}
+
+// A cue block is an optional identifier (the counter in SRT), a timing line and the text
+fn parse_cue(block: &[&str], allow_settings: bool) -> Result<Cue, String> {
+    let (id, timing, text) = if block[0].contains("-->") {
+        (None, block[0], &block[1..])
+    } else if block.len() > 1 && block[1].contains("-->") {
+        (Some(block[0].trim().to_string()), block[1], &block[2..])
+    } else {
+        return Err(format!("Missing cue timing after \"{}\".", block[0]));
+    };
+
+    let invalid = || format!("Invalid cue timing: \"{}\".", timing.trim());
+
+    let (start, rest) = timing.split_once("-->").ok_or_else(invalid)?;
+    let mut rest = rest.split_whitespace();
+    let start = parse_timestamp(start.trim()).ok_or_else(invalid)?;
+    let end = rest.next().and_then(parse_timestamp).ok_or_else(invalid)?;
+    if end < start {
+        return Err(invalid());
+    }
+    // SRT has no cue settings, but some files carry display coordinates after the timing
+    let settings = if allow_settings {
+        rest.collect::<Vec<_>>().join(" ")
+    } else {
+        String::new()
+    };
+
+    if let Some(line) = text.iter().find(|line| line.contains("-->")) {
+        return Err(format!("Unexpected cue timing: \"{}\".", line.trim()));
+    }
+
+    Ok(Cue {
+        id,
+        start,
+        end,
+        settings,
+        text: text.iter().map(|line| line.to_string()).collect(),
+    })
+}
+
+// "[hh:]mm:ss.mmm", with a comma instead of the dot in SRT
+fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let (clock, millis) = timestamp.split_once(['.', ','])?;
+    if millis.len() != 3 || !millis.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let parts: Vec<&str> = clock.split(':').collect();
+    let (hours, minutes, seconds) = match parts.as_slice() {
+        [hours, minutes, seconds] => (hours.parse::<u64>().ok()?, *minutes, *seconds),
+        [minutes, seconds] => (0, *minutes, *seconds),
+        _ => return None,
+    };
+    if minutes.len() != 2 || seconds.len() != 2 {
+        return None;
+    }
+    let minutes = minutes.parse::<u64>().ok()?;
+    let seconds = seconds.parse::<u64>().ok()?;
+    if minutes > 59 || seconds > 59 {
+        return None;
+    }
+    // The hours are unbounded, so a huge value must not overflow into a small timestamp
+    hours
+        .checked_mul(60)?
+        .checked_add(minutes)?
+        .checked_mul(60)?
+        .checked_add(seconds)?
+        .checked_mul(1000)?
+        .checked_add(millis.parse::<u64>().ok()?)
+}
+
+fn format_timestamp(millis: u64) -> String {
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        (millis / 60_000) % 60,
+        (millis / 1000) % 60,
+        millis % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srt_is_converted_to_webvtt() {
+        let srt = "\u{feff}1\r\n00:00:01,500 --> 00:00:04,000\r\nHello\r\nthere\r\n\r\n\
+                   2\r\n01:02:03,004 --> 01:02:05,000 X1:10 X2:20\r\nBye\r\n";
+        assert_eq!(
+            to_webvtt(srt.as_bytes(), "SRT").unwrap(),
+            "WEBVTT\n\n1\n00:00:01.500 --> 00:00:04.000\nHello\nthere\n\n\
+             2\n01:02:03.004 --> 01:02:05.000\nBye\n"
+        );
+    }
+
+    #[test]
+    fn webvtt_cue_settings_are_kept() {
+        let vtt = "WEBVTT - Film\n\nintro\n00:01.000 --> 00:02.000 line:0 align:start\nHi\n";
+        assert_eq!(
+            to_webvtt(vtt.as_bytes(), "vtt").unwrap(),
+            "WEBVTT\n\nintro\n00:00:01.000 --> 00:00:02.000 line:0 align:start\nHi\n"
+        );
+    }
+
+    #[test]
+    fn notes_and_style_blocks_are_skipped() {
+        let vtt = "WEBVTT\n\nNOTE written by hand\nsecond line\n\n\
+                   STYLE\n::cue { color: red }\n\n00:01.000 --> 00:02.000\nHi\n";
+        assert_eq!(
+            to_webvtt(vtt.as_bytes(), "vtt").unwrap(),
+            "WEBVTT\n\n00:00:01.000 --> 00:00:02.000\nHi\n"
+        );
+    }
+
+    #[test]
+    fn cues_ending_before_they_start_are_refused() {
+        let vtt = "WEBVTT\n\n00:05.000 --> 00:02.000\nHi\n";
+        assert!(to_webvtt(vtt.as_bytes(), "vtt").is_err());
+    }
+
+    #[test]
+    fn huge_hours_are_refused_rather_than_overflowing() {
+        assert_eq!(parse_timestamp("5124095576031:00:00.000"), None);
+        assert_eq!(parse_timestamp(&format!("{}:00:00.000", u64::MAX)), None);
+        let srt = "1\n18446744073709551615:00:00,000 --> 18446744073709551615:00:01,000\nHi\n";
+        assert!(to_webvtt(srt.as_bytes(), "srt").is_err());
+        assert_eq!(parse_timestamp("100:00:00.001"), Some(360_000_001));
+    }
+}
@@ -1,21 +1,42 @@
-use actix_multipart::Multipart;
+use crate::audio::{self, AudioDetails, CoverArt};
+use crate::blobs;
+use crate::captions::{self, CaptionTrack};
+use crate::filetype::{self, MediaKind};
+use crate::hls;
+use crate::images::{self, CameraDetails};
+use crate::page;
+use crate::quota;
+use crate::settings::{self, MetadataSettings};
+use crate::snapshots;
+use crate::storage::Storage;
+use crate::styles::{self, CustomCssData, CustomCssVersion, RollbackCssData};
+use crate::upload::{self, TempUpload};
+use crate::video::{self, ToolError};
+use actix_multipart::{Field, Multipart};
 use actix_web::{web, HttpRequest, HttpResponse};
 use chrono::Utc;
-use futures::{StreamExt, TryStreamExt};
-use kuchiki::traits::*;
-use kuchiki::NodeRef;
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
 use std::fs;
-use std::io::Write;
 use std::path::Path;
 
+// Size limits for each kind of uploaded file
+pub const MAX_AUDIO_SIZE: u64 = 50 * 1024 * 1024;
+pub const MAX_FILM_SIZE: u64 = 200 * 1024 * 1024;
+pub const MAX_IMAGE_SIZE: u64 = 10 * 1024 * 1024;
+
+// Most tracks a single album upload may contain
+const MAX_ALBUM_TRACKS: usize = 30;
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
-enum ContentItem {
+pub enum ContentItem {
     Gallery(Gallery),
     TextPost(TextPost),
     Film(Film),
     Audio(Audio),
+    Album(Album),
 }
 
 #[derive(Deserialize)]
@@ -25,25 +46,124 @@ pub struct SaveChangesData {
     pub main_title: String,
 }
 
+#[derive(Deserialize)]
+pub struct ThemeData {
+    pub theme: String,
+}
+
+#[derive(Serialize)]
+struct ThemeOption {
+    name: &'static str,
+    label: &'static str,
+}
+
+#[derive(Serialize)]
+struct ThemeList {
+    current: String,
+    themes: Vec<ThemeOption>,
+}
+
+#[derive(Serialize)]
+struct CustomCss {
+    css: String,
+    versions: Vec<CustomCssVersion>,
+}
+
 #[derive(Serialize, Deserialize)]
-struct Gallery {
-    title: String,
-    images: Vec<String>,
-    timestamp: String,
+pub struct Gallery {
+    pub title: String,
+    pub images: Vec<String>,
+    pub timestamp: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub photo_details: Vec<PhotoDetails>,
+    // Public items can be seen by anyone, the rest only by friends
+    #[serde(default)]
+    pub public: bool,
 }
 
+// Camera details the owner chose to keep after the photo's metadata was stripped
 #[derive(Serialize, Deserialize)]
-struct Film {
-    title: String,
-    video_path: String,
-    timestamp: String,
+pub struct PhotoDetails {
+    pub image: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capture_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_model: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
-struct Audio {
-    title: String,
-    audio_path: String,
-    timestamp: String,
+pub struct Film {
+    pub title: String,
+    pub video_path: String,
+    pub timestamp: String,
+    #[serde(default)]
+    pub public: bool,
+    // Caption tracks, converted to WebVTT
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub captions: Vec<CaptionTrack>,
+    // Read with ffprobe after upload, when it is installed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video_codec: Option<String>,
+    // Frame grabbed with ffmpeg, stored as an image blob
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poster: Option<String>,
+    // Master playlist of the HLS renditions, once transcoding has finished
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hls: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Audio {
+    pub title: String,
+    pub audio_path: String,
+    pub timestamp: String,
+    // Read from the file's tags when it was uploaded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track_number: Option<u32>,
+    // Length in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    // Embedded cover art, stored as an image blob
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cover_art: Option<String>,
+    // Waveform peaks (0-255) at a few resolutions, coarsest first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub waveform: Option<Vec<Vec<u8>>>,
+    // Tracks of an album follow the album's visibility
+    #[serde(default)]
+    pub public: bool,
+}
+
+// An album or playlist: several tracks published as a single item, played in order
+#[derive(Serialize, Deserialize)]
+pub struct Album {
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    // Uploaded album artwork; pages fall back to the first track's embedded cover
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cover_art: Option<String>,
+    pub tracks: Vec<Audio>,
+    pub timestamp: String,
+    #[serde(default)]
+    pub public: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ReorderAlbumData {
+    pub timestamp: String,
+    // New order of the tracks, as their current positions
+    pub order: Vec<usize>,
 }
 
 #[derive(Deserialize)]
@@ -57,9 +177,15 @@ pub struct TextPost {
     pub title: String,
     pub content: String,
     pub timestamp: String,
+    #[serde(default)]
+    pub public: bool,
 }
 
-pub async fn save_changes(data: web::Json<SaveChangesData>, req: HttpRequest) -> HttpResponse {
+pub async fn save_changes(
+    data: web::Json<SaveChangesData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
     // Extract the username from the cookie
     let username = match req.cookie("username") {
         Some(cookie) => cookie.value().to_string(),
@@ -77,89 +203,150 @@ pub async fn save_changes(data: web::Json<SaveChangesData>, req: HttpRequest) ->
         return HttpResponse::BadRequest().body("Invalid input detected");
     }
 
-    // Path to the user's HTML file
-    let user_page_path = format!("./user_pages/{}/my_page.html", username);
+    snapshots::record(pool.get_ref(), &username, "titles").await;
 
-    // Check if the user's page exists
-    if !Path::new(&user_page_path).exists() {
-        return HttpResponse::NotFound().body("User page not found");
+    // The titles are stored with the page settings and filled in when the page is rendered
+    match page::save_titles(
+        pool.get_ref(),
+        &username,
+        &data.exhibit_title,
+        &data.main_title,
+    )
+    .await
+    {
+        Ok(()) => HttpResponse::Ok().body("Changes saved successfully"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
     }
+}
 
-    // Read the current content of the HTML file
-    let html_content = match fs::read_to_string(&user_page_path) {
-        Ok(content) => content,
-        Err(_) => {
-            return HttpResponse::InternalServerError().body("Failed to read user page");
+// The themes a page can use, and the one the user has chosen
+pub async fn get_themes(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().body("User not authenticated");
         }
     };
 
-    // Replace the titles in the HTML content
-    let updated_html =
-        match update_html_titles(&html_content, &data.exhibit_title, &data.main_title) {
-            Ok(html) => html,
-            Err(e) => {
-                return HttpResponse::InternalServerError()
-                    .body(format!("Failed to update HTML: {}", e));
-            }
-        };
+    let settings = page::load_page_settings(pool.get_ref(), &username).await;
+    HttpResponse::Ok().json(ThemeList {
+        current: settings.theme,
+        themes: page::THEMES
+            .iter()
+            .map(|(name, label)| ThemeOption { name, label })
+            .collect(),
+    })
+}
+
+// Switch the page to another theme. The user's custom styles still apply on top of it.
+pub async fn set_theme(
+    data: web::Json<ThemeData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().body("User not authenticated");
+        }
+    };
 
-    // Write the updated content back to the file
-    match fs::write(&user_page_path, updated_html) {
-        Ok(_) => HttpResponse::Ok().body("Changes saved successfully"),
-        Err(_) => HttpResponse::InternalServerError().body("Failed to write to user page"),
+    if !page::is_theme(&data.theme) {
+        return HttpResponse::BadRequest().body("Unknown theme");
     }
-}
 
-// Function to update the titles in the HTML content
-fn update_html_titles(
-    html_content: &str,
-    new_exhibit_title: &str,
-    new_main_title: &str,
-) -> Result<String, Box<dyn std::error::Error>> {
-    // Parse the HTML content
-    let document = kuchiki::parse_html().one(html_content);
+    snapshots::record(pool.get_ref(), &username, "theme").await;
+    match page::save_theme(pool.get_ref(), &username, &data.theme).await {
+        Ok(()) => HttpResponse::Ok().body("Theme saved successfully"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
 
-    // Update the <title> element
-    if let Ok(title_element) = document.select_first("title") {
-        let node = title_element.as_node();
-        // Collect the children into a vector to avoid borrowing issues
-        let children: Vec<_> = node.children().collect();
-        // Detach all children
-        for child in children {
-            child.detach();
+async fn custom_css_response(pool: &SqlitePool, username: &str) -> HttpResponse {
+    let css = match styles::current(pool, username).await {
+        Ok(css) => css.unwrap_or_default(),
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Database error: {}", e))
         }
-        // Append the new text
-        node.append(NodeRef::new_text(new_exhibit_title));
+    };
+    match styles::list_versions(pool, username).await {
+        Ok(versions) => HttpResponse::Ok().json(CustomCss { css, versions }),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
     }
+}
 
-    // Update the <header><h1> element
-    if let Ok(header_h1_element) = document.select_first("header h1") {
-        let node = header_h1_element.as_node();
-        let children: Vec<_> = node.children().collect();
-        for child in children {
-            child.detach();
+// The user's current custom CSS and the versions they can roll back to
+pub async fn get_custom_css(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().body("User not authenticated");
         }
-        node.append(NodeRef::new_text(new_exhibit_title));
-    }
+    };
 
-    // Update the <main><h2> element
-    if let Ok(main_h2_element) = document.select_first("main h2") {
-        let node = main_h2_element.as_node();
-        let children: Vec<_> = node.children().collect();
-        for child in children {
-            child.detach();
+    custom_css_response(pool.get_ref(), &username).await
+}
+
+// Sanitize and save new custom CSS. The response holds the CSS as it was stored.
+pub async fn save_custom_css(
+    data: web::Json<CustomCssData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().body("User not authenticated");
         }
-        node.append(NodeRef::new_text(new_main_title));
+    };
+
+    let css = match styles::sanitize(&data.css) {
+        Ok(css) => css,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    snapshots::record(pool.get_ref(), &username, "custom CSS").await;
+    if let Err(e) = styles::save_version(pool.get_ref(), &username, &css).await {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
     }
+    custom_css_response(pool.get_ref(), &username).await
+}
 
-    // Serialize the updated HTML content back to a string
-    let mut updated_html = Vec::new();
-    document.serialize(&mut updated_html)?;
+// Make an earlier version current again. It is saved as a new version, so the rollback can
+// itself be undone.
+pub async fn rollback_custom_css(
+    data: web::Json<RollbackCssData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().body("User not authenticated");
+        }
+    };
+
+    let css = match styles::find_version(pool.get_ref(), &username, data.version).await {
+        Ok(Some(css)) => css,
+        Ok(None) => return HttpResponse::NotFound().body("Version not found"),
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Database error: {}", e))
+        }
+    };
 
-    Ok(String::from_utf8(updated_html)?)
+    snapshots::record(pool.get_ref(), &username, "custom CSS").await;
+    if let Err(e) = styles::save_version(pool.get_ref(), &username, &css).await {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
+    custom_css_response(pool.get_ref(), &username).await
 }
 
-pub async fn upload_audio(mut payload: Multipart, req: HttpRequest) -> HttpResponse {
+pub async fn upload_audio(
+    mut payload: Multipart,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    storage: web::Data<dyn Storage>,
+) -> HttpResponse {
     // Extract the username from the cookie
     let username = match req.cookie("username") {
         Some(cookie) => cookie.value().to_string(),
@@ -167,11 +354,7 @@ pub async fn upload_audio(mut payload: Multipart, req: HttpRequest) -> HttpRespo
     };
 
     let mut audio_title = String::new();
-    let mut audio_path = String::new();
-    let mut audio_uploaded = false;
-
-    // Get the current timestamp for unique naming
-    let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
+    let mut audio_file = None;
 
     // Path to the audios folder
     let audios_folder = format!("./user_pages/{}/audios", username);
@@ -200,7 +383,7 @@ pub async fn upload_audio(mut payload: Multipart, req: HttpRequest) -> HttpRespo
                 audio_title = String::from_utf8(data).unwrap_or_default();
             } else if name == "audio" {
                 // Ensure only one audio file is uploaded
-                if audio_uploaded {
+                if audio_file.is_some() {
                     return HttpResponse::BadRequest().body("Only one audio file is allowed.");
                 }
 
@@ -208,109 +391,1037 @@ pub async fn upload_audio(mut payload: Multipart, req: HttpRequest) -> HttpRespo
                 let filename = content_disposition
                     .unwrap()
                     .get_filename()
-                    .map(|f| sanitize_filename::sanitize(f))
-                    .unwrap_or_else(|| format!("audio_{}.mp3", timestamp));
+                    .map(sanitize_filename::sanitize)
+                    .unwrap_or_else(|| "audio.mp3".to_string());
+
+                // Stream the file to disk (50MB limit), checking it really is an audio file
+                let upload = match upload::receive_file(
+                    &mut field,
+                    &audios_folder,
+                    &filename,
+                    MediaKind::Audio,
+                    MAX_AUDIO_SIZE,
+                    quota::remaining_quota(pool.get_ref(), &username).await,
+                )
+                .await
+                {
+                    Ok(upload) => upload,
+                    Err(response) => return response,
+                };
+
+                audio_file = Some((upload, filename));
+            }
+        }
+    }
+
+    // Validate that an audio file was uploaded
+    let (upload, filename) = match audio_file {
+        Some(audio_file) => audio_file,
+        None => return HttpResponse::BadRequest().body("Please upload an audio file."),
+    };
+
+    let timestamp = match claim_metadata_file(&audios_folder) {
+        Ok(timestamp) => timestamp,
+        Err(_) => return HttpResponse::InternalServerError().body("Error saving audio file."),
+    };
 
-                // Check file size limit (50MB)
-                let mut data = web::BytesMut::new();
+    match save_audio(
+        pool.get_ref(),
+        storage.get_ref(),
+        &username,
+        &audio_title,
+        &filename,
+        upload,
+        &timestamp,
+    )
+    .await
+    {
+        Ok(()) => HttpResponse::Ok().body("Audio uploaded successfully."),
+        Err(response) => {
+            unclaim_metadata_file(&audios_folder, &timestamp);
+            response
+        }
+    }
+}
+
+// Move a received audio file into the user's audios folder and save its metadata.
+// Shared by /upload_audio and resumable uploads.
+pub async fn save_audio(
+    pool: &SqlitePool,
+    storage: &dyn Storage,
+    username: &str,
+    title: &str,
+    filename: &str,
+    upload: TempUpload,
+    timestamp: &str,
+) -> Result<(), HttpResponse> {
+    let audio_metadata =
+        store_audio(pool, storage, username, title, filename, upload, timestamp).await?;
+
+    // Save audio metadata (could be saved in a database; for now, we'll save in a JSON file)
+    let metadata_path = format!("./user_pages/{}/audios/{}.json", username, timestamp);
+    if fs::write(
+        &metadata_path,
+        serde_json::to_string(&audio_metadata).unwrap(),
+    )
+    .is_err()
+    {
+        release_audio(pool, storage, username, &audio_metadata).await;
+        return Err(HttpResponse::InternalServerError().body("Error saving audio metadata."));
+    }
+
+    Ok(())
+}
+
+// Read a received audio file's tags and waveform, then store it and any embedded cover art
+// as blobs. Shared by single audio uploads and album tracks.
+async fn store_audio(
+    pool: &SqlitePool,
+    storage: &dyn Storage,
+    username: &str,
+    title: &str,
+    filename: &str,
+    upload: TempUpload,
+    timestamp: &str,
+) -> Result<Audio, HttpResponse> {
+    let audios_folder = format!("./user_pages/{}/audios", username);
+    if fs::create_dir_all(&audios_folder).is_err() {
+        return Err(HttpResponse::InternalServerError().body("Error saving audio file."));
+    }
+
+    // Read the tags and waveform while the file is still on local disk
+    let audio_file = upload.path().to_path_buf();
+    let extension = filename
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase())
+        .unwrap_or_default();
+    let analysis = web::block(move || {
+        (
+            audio::read_details(&audio_file, &extension),
+            audio::compute_waveform(&audio_file, &extension),
+        )
+    })
+    .await;
+    let (mut details, waveform) = match analysis {
+        Ok((details, waveform)) => {
+            let details = details.unwrap_or_else(|e| {
+                eprintln!("Failed to read audio details for {}: {}", filename, e);
+                AudioDetails::default()
+            });
+            let waveform = waveform
+                .map_err(|e| eprintln!("Failed to compute waveform for {}: {}", filename, e))
+                .ok();
+            (details, waveform)
+        }
+        Err(_) => (AudioDetails::default(), None),
+    };
+
+    let audio_path =
+        blobs::store(pool, storage, username, upload, filename, MediaKind::Audio).await?;
+
+    let cover_art = match details.cover_art.take() {
+        Some(cover) => store_cover_art(pool, storage, username, &audios_folder, cover).await,
+        None => None,
+    };
+
+    // Fall back to the title in the file's tags if none was given
+    let title = match title.trim() {
+        "" => details.title.unwrap_or_default(),
+        title => title.to_string(),
+    };
+
+    Ok(Audio {
+        title,
+        audio_path,
+        timestamp: timestamp.to_string(),
+        artist: details.artist,
+        album: details.album,
+        track_number: details.track_number,
+        duration: details.duration,
+        cover_art,
+        waveform,
+        public: false,
+    })
+}
+
+// Drop the blobs an audio item refers to
+async fn release_audio(pool: &SqlitePool, storage: &dyn Storage, username: &str, audio: &Audio) {
+    blobs::release(pool, storage, username, &audio.audio_path).await;
+    if let Some(cover_art) = &audio.cover_art {
+        blobs::release(pool, storage, username, cover_art).await;
+    }
+}
+
+// Check and strip embedded cover art like any other uploaded image, then store it as a blob.
+// Cover art is optional, so problems are logged rather than failing the upload.
+async fn store_cover_art(
+    pool: &SqlitePool,
+    storage: &dyn Storage,
+    username: &str,
+    folder: &str,
+    cover: CoverArt,
+) -> Option<String> {
+    let filename = format!("cover.{}", cover.extension);
+    let upload =
+        TempUpload::from_path(Path::new(folder).join(format!(".{}.upload", uuid::Uuid::new_v4())));
+
+    let image_filename = filename.clone();
+    let result = web::block(move || {
+        filetype::validate_image(&image_filename, &cover.data)?;
+        let (data, _) = images::strip_metadata(cover.data.into())?;
+        fs::write(upload.path(), data).map_err(|e| e.to_string())?;
+        Ok::<_, String>(upload)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(upload)) => {
+            blobs::store(pool, storage, username, upload, &filename, MediaKind::Image)
+                .await
+                .ok()
+        }
+        Ok(Err(e)) => {
+            eprintln!("Ignoring cover art: {}", e);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+pub async fn get_audios(req: HttpRequest) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    // Sort audios by title or any other criteria if needed
+    // For now, we'll leave them in the order they were read
+    HttpResponse::Ok().json(load_audios(&username))
+}
+
+// Read a user's audio posts from their metadata files
+pub fn load_audios(username: &str) -> Vec<Audio> {
+    let audios_folder = format!("./user_pages/{}/audios", username);
+    let mut audios = Vec::new();
+
+    if let Ok(entries) = fs::read_dir(&audios_folder) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_file() && path.extension().unwrap_or_default() == "json" {
+                if let Ok(data) = fs::read_to_string(&path) {
+                    if let Ok(audio) = serde_json::from_str::<Audio>(&data) {
+                        audios.push(audio);
+                    }
+                }
+            }
+        }
+    }
+
+    audios
+}
+
+// Drop every blob an album refers to
+async fn release_album(pool: &SqlitePool, storage: &dyn Storage, username: &str, album: &Album) {
+    for track in &album.tracks {
+        release_audio(pool, storage, username, track).await;
+    }
+    if let Some(cover_art) = &album.cover_art {
+        blobs::release(pool, storage, username, cover_art).await;
+    }
+}
+
+// Upload an album or playlist: a title, description, optional cover image and several audio
+// tracks in one request. Tracks keep the order they were sent in.
+pub async fn upload_album(
+    mut payload: Multipart,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    storage: web::Data<dyn Storage>,
+) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    let mut album_title = String::new();
+    let mut description = String::new();
+    let mut cover = None;
+    let mut tracks = Vec::new();
+
+    // Tracks are received next to the single audio uploads
+    let audios_folder = format!("./user_pages/{}/audios", username);
+    fs::create_dir_all(&audios_folder).unwrap();
+
+    let mut quota_remaining = quota::remaining_quota(pool.get_ref(), &username).await;
+
+    // Process the multipart form data
+    while let Some(item) = payload.next().await {
+        let mut field = match item {
+            Ok(field) => field,
+            Err(_) => continue,
+        };
+
+        let content_disposition = field.content_disposition().cloned();
+        let name = match content_disposition.as_ref().and_then(|cd| cd.get_name()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+
+        if name == "albumTitle" || name == "description" {
+            let mut data = Vec::new();
+            while let Some(chunk) = field.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(_) => continue,
+                };
+                data.extend_from_slice(&chunk);
+            }
+            let value = String::from_utf8(data).unwrap_or_default();
+            if name == "albumTitle" {
+                album_title = value;
+            } else {
+                description = value;
+            }
+        } else if name == "cover" {
+            if cover.is_some() {
+                return HttpResponse::BadRequest().body("Only one cover image is allowed.");
+            }
+
+            let filename = content_disposition
+                .as_ref()
+                .and_then(|cd| cd.get_filename())
+                .map(sanitize_filename::sanitize)
+                .unwrap_or_else(|| "cover.png".to_string());
+
+            let upload = match upload::receive_file(
+                &mut field,
+                &audios_folder,
+                &filename,
+                MediaKind::Image,
+                MAX_IMAGE_SIZE,
+                quota_remaining,
+            )
+            .await
+            {
+                Ok(upload) => upload,
+                Err(response) => return response,
+            };
+            quota_remaining = quota_remaining.saturating_sub(upload.size());
+
+            match prepare_gallery_image(upload, filename).await {
+                Ok(image) => cover = Some(image),
+                Err(response) => return response,
+            }
+        } else if name == "tracks" {
+            if tracks.len() >= MAX_ALBUM_TRACKS {
+                return HttpResponse::BadRequest()
+                    .body(format!("Maximum of {} tracks allowed.", MAX_ALBUM_TRACKS));
+            }
+
+            let filename = content_disposition
+                .as_ref()
+                .and_then(|cd| cd.get_filename())
+                .map(sanitize_filename::sanitize)
+                .unwrap_or_else(|| format!("track_{}.mp3", tracks.len() + 1));
+
+            // Stream the file to disk (50MB limit per track)
+            let upload = match upload::receive_file(
+                &mut field,
+                &audios_folder,
+                &filename,
+                MediaKind::Audio,
+                MAX_AUDIO_SIZE,
+                quota_remaining,
+            )
+            .await
+            {
+                Ok(upload) => upload,
+                Err(response) => return response,
+            };
+            quota_remaining = quota_remaining.saturating_sub(upload.size());
+
+            tracks.push((upload, filename));
+        }
+    }
+
+    if album_title.trim().is_empty() {
+        return HttpResponse::BadRequest().body("Please enter an album title.");
+    }
+    if tracks.is_empty() {
+        return HttpResponse::BadRequest().body("Please upload at least one track.");
+    }
+
+    let albums_folder = format!("./user_pages/{}/albums", username);
+    let timestamp = match claim_metadata_file(&albums_folder) {
+        Ok(timestamp) => timestamp,
+        Err(_) => return HttpResponse::InternalServerError().body("Error saving album metadata."),
+    };
+
+    let mut album = Album {
+        title: album_title.trim().to_string(),
+        description: description.trim().to_string(),
+        cover_art: None,
+        tracks: Vec::new(),
+        timestamp: timestamp.clone(),
+        public: false,
+    };
+
+    for (upload, filename) in tracks {
+        match store_audio(
+            pool.get_ref(),
+            storage.get_ref(),
+            &username,
+            "",
+            &filename,
+            upload,
+            &timestamp,
+        )
+        .await
+        {
+            Ok(mut track) => {
+                // Untagged tracks are named after their file
+                if track.title.is_empty() {
+                    track.title = filename
+                        .rsplit_once('.')
+                        .map(|(stem, _)| stem.to_string())
+                        .unwrap_or(filename);
+                }
+                album.tracks.push(track);
+            }
+            Err(response) => {
+                // Give back the tracks stored so far so their blobs are not leaked
+                release_album(pool.get_ref(), storage.get_ref(), &username, &album).await;
+                unclaim_metadata_file(&albums_folder, &timestamp);
+                return response;
+            }
+        }
+    }
+
+    if let Some(image) = cover {
+        match blobs::store(
+            pool.get_ref(),
+            storage.get_ref(),
+            &username,
+            image.upload,
+            &image.filename,
+            MediaKind::Image,
+        )
+        .await
+        {
+            Ok(cover_art) => album.cover_art = Some(cover_art),
+            Err(response) => {
+                release_album(pool.get_ref(), storage.get_ref(), &username, &album).await;
+                unclaim_metadata_file(&albums_folder, &timestamp);
+                return response;
+            }
+        }
+    }
+
+    let metadata_path = format!("{}/{}.json", albums_folder, timestamp);
+    if fs::write(&metadata_path, serde_json::to_string(&album).unwrap()).is_err() {
+        release_album(pool.get_ref(), storage.get_ref(), &username, &album).await;
+        unclaim_metadata_file(&albums_folder, &timestamp);
+        return HttpResponse::InternalServerError().body("Error saving album metadata.");
+    }
+
+    HttpResponse::Ok().body("Album uploaded successfully.")
+}
+
+// Change the order an album's tracks play in
+pub async fn reorder_album(data: web::Json<ReorderAlbumData>, req: HttpRequest) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    let albums_folder = format!("./user_pages/{}/albums", username);
+    let (path, mut album) =
+        match find_content::<Album>(&albums_folder, &data.timestamp, |a| &a.timestamp) {
+            Some(found) => found,
+            None => return HttpResponse::NotFound().body("Album not found."),
+        };
+
+    // The new order must name every current track exactly once
+    let mut sorted = data.order.clone();
+    sorted.sort_unstable();
+    if sorted != (0..album.tracks.len()).collect::<Vec<_>>() {
+        return HttpResponse::BadRequest().body("Order must list every track exactly once.");
+    }
+
+    let mut tracks: Vec<Option<Audio>> = album.tracks.into_iter().map(Some).collect();
+    album.tracks = data
+        .order
+        .iter()
+        .filter_map(|&index| tracks[index].take())
+        .collect();
+
+    match fs::write(&path, serde_json::to_string(&album).unwrap()) {
+        Ok(_) => HttpResponse::Ok().body("Album reordered successfully."),
+        Err(_) => HttpResponse::InternalServerError().body("Error saving album metadata."),
+    }
+}
+
+pub async fn upload_film(
+    mut payload: Multipart,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    storage: web::Data<dyn Storage>,
+) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    let mut film_title = String::new();
+    let mut video_file = None;
+    let mut caption_uploads = CaptionUploads::default();
+
+    // Path to the films folder
+    let films_folder = format!("./user_pages/{}/films", username);
+    fs::create_dir_all(&films_folder).unwrap();
+
+    // Process the multipart form data
+    while let Some(item) = payload.next().await {
+        let mut field = match item {
+            Ok(field) => field,
+            Err(_) => continue,
+        };
+
+        let content_disposition = field.content_disposition();
+
+        if let Some(name) = content_disposition.unwrap().get_name() {
+            if name == "filmTitle" {
+                // Read the film title
+                let mut data = Vec::new();
                 while let Some(chunk) = field.next().await {
                     let chunk = match chunk {
                         Ok(chunk) => chunk,
                         Err(_) => continue,
                     };
-                    if (data.len() + chunk.len()) > 50 * 1024 * 1024 {
-                        return HttpResponse::BadRequest()
-                            .body("File size too big (must be under 50MB).");
-                    }
                     data.extend_from_slice(&chunk);
                 }
+                film_title = String::from_utf8(data).unwrap_or_default();
+            } else if name == "video" {
+                // Ensure only one video is uploaded
+                if video_file.is_some() {
+                    return HttpResponse::BadRequest().body("Only one video is allowed.");
+                }
 
-                // Save the file
-                let file_path = format!("{}/{}", audios_folder, filename);
-                let mut f = web::block(move || std::fs::File::create(&file_path))
-                    .await
+                // Get the filename
+                let filename = content_disposition
                     .unwrap()
-                    .unwrap();
-                if let Err(_) = f.write_all(&data) {
-                    return HttpResponse::InternalServerError().body("Error saving audio file.");
+                    .get_filename()
+                    .map(sanitize_filename::sanitize)
+                    .unwrap_or_else(|| "video.mp4".to_string());
+
+                // Stream the file to disk (200MB limit), checking it really is a video
+                let upload = match upload::receive_file(
+                    &mut field,
+                    &films_folder,
+                    &filename,
+                    MediaKind::Video,
+                    MAX_FILM_SIZE,
+                    quota::remaining_quota(pool.get_ref(), &username).await,
+                )
+                .await
+                {
+                    Ok(upload) => upload,
+                    Err(response) => return response,
+                };
+
+                video_file = Some((upload, filename));
+            } else {
+                let name = name.to_string();
+                if let Err(response) = caption_uploads.receive(&name, &mut field).await {
+                    return response;
                 }
-
-                // Set the audio path
-                audio_path = format!("/user_pages/{}/audios/{}", username, filename);
-                audio_uploaded = true;
             }
         }
     }
 
-    // Validate that an audio file was uploaded
-    if !audio_uploaded {
-        return HttpResponse::BadRequest().body("Please upload an audio file.");
+    // Validate that a video was uploaded
+    let (upload, filename) = match video_file {
+        Some(video_file) => video_file,
+        None => return HttpResponse::BadRequest().body("Please upload a video file."),
+    };
+
+    let captions = match caption_uploads.convert() {
+        Ok(captions) => captions,
+        Err(response) => return response,
+    };
+    let captions_size: u64 = captions
+        .iter()
+        .map(|caption| caption.vtt.len() as u64)
+        .sum();
+    // The video was held to the quota as it arrived, and is already counted in the usage
+    // while it sits in the films folder, so only the captions are left to check
+    if captions_size > quota::remaining_quota(pool.get_ref(), &username).await {
+        return HttpResponse::PayloadTooLarge().body("Storage quota exceeded.");
     }
 
-    // Save audio metadata (could be saved in a database; for now, we'll save in a JSON file)
-    let audio_metadata = Audio {
-        title: audio_title.clone(),
-        audio_path: audio_path.clone(),
-        timestamp: timestamp.clone(),
+    let timestamp = match claim_metadata_file(&films_folder) {
+        Ok(timestamp) => timestamp,
+        Err(_) => return HttpResponse::InternalServerError().body("Error saving video file."),
     };
 
-    let metadata_filename = format!("{}.json", timestamp);
-    let metadata_path = format!("{}/{}", audios_folder, metadata_filename);
-    if let Err(_) = fs::write(
+    match save_film(
+        pool.get_ref(),
+        storage.get_ref(),
+        &username,
+        &film_title,
+        &filename,
+        upload,
+        &timestamp,
+        captions,
+    )
+    .await
+    {
+        Ok(()) => {
+            process_film_in_background(
+                pool.get_ref().clone(),
+                storage.clone(),
+                username,
+                timestamp,
+            );
+            HttpResponse::Ok().body("Film uploaded successfully.")
+        }
+        Err(response) => {
+            unclaim_metadata_file(&films_folder, &timestamp);
+            response
+        }
+    }
+}
+
+// Move a received video into the user's films folder and save its metadata.
+// Shared by /upload_film and resumable uploads.
+#[allow(clippy::too_many_arguments)]
+pub async fn save_film(
+    pool: &SqlitePool,
+    storage: &dyn Storage,
+    username: &str,
+    title: &str,
+    filename: &str,
+    upload: TempUpload,
+    timestamp: &str,
+    captions: Vec<ConvertedCaption>,
+) -> Result<(), HttpResponse> {
+    let films_folder = format!("./user_pages/{}/films", username);
+    if fs::create_dir_all(&films_folder).is_err() {
+        return Err(HttpResponse::InternalServerError().body("Error saving video file."));
+    }
+    let video_path =
+        blobs::store(pool, storage, username, upload, filename, MediaKind::Video).await?;
+
+    // Save film metadata (could be saved in a database; for now, we'll save in a JSON file)
+    let mut film_metadata = Film {
+        title: title.to_string(),
+        video_path,
+        timestamp: timestamp.to_string(),
+        public: false,
+        captions: Vec::new(),
+        duration: None,
+        width: None,
+        height: None,
+        video_codec: None,
+        poster: None,
+        hls: None,
+    };
+    if let Err(response) = write_captions(username, &mut film_metadata, captions) {
+        blobs::release(pool, storage, username, &film_metadata.video_path).await;
+        return Err(response);
+    }
+
+    let metadata_path = format!("{}/{}.json", films_folder, timestamp);
+    if fs::write(
         &metadata_path,
-        serde_json::to_string(&audio_metadata).unwrap(),
-    ) {
-        return HttpResponse::InternalServerError().body("Error saving audio metadata.");
+        serde_json::to_string(&film_metadata).unwrap(),
+    )
+    .is_err()
+    {
+        blobs::release(pool, storage, username, &film_metadata.video_path).await;
+        remove_captions(username, &film_metadata);
+        return Err(HttpResponse::InternalServerError().body("Error saving film metadata."));
     }
 
-    HttpResponse::Ok().body("Audio uploaded successfully.")
+    Ok(())
 }
 
-pub async fn get_audios(req: HttpRequest) -> HttpResponse {
-    // Extract the username from the cookie
-    let username = match req.cookie("username") {
-        Some(cookie) => cookie.value().to_string(),
-        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+// Fill in a film's details and poster frame once it is saved, then transcode it for HLS
+// if that is turned on. This runs after the upload response is sent, and does nothing when
+// ffprobe and ffmpeg are not installed.
+pub fn process_film_in_background(
+    pool: SqlitePool,
+    storage: web::Data<dyn Storage>,
+    username: String,
+    timestamp: String,
+) {
+    actix_web::rt::spawn(async move {
+        probe_film(&pool, storage.get_ref(), &username, &timestamp).await;
+        if hls::is_enabled() {
+            transcode_film(&pool, storage.get_ref(), &username, &timestamp).await;
+        }
+    });
+}
+
+pub fn find_film(username: &str, timestamp: &str) -> Option<Film> {
+    let films_folder = format!("./user_pages/{}/films", username);
+    find_content::<Film>(&films_folder, timestamp, |f| &f.timestamp).map(|(_, film)| film)
+}
+
+async fn transcode_film(pool: &SqlitePool, storage: &dyn Storage, username: &str, timestamp: &str) {
+    // Waiting here, before the film and quota are looked at, so they are current when ffmpeg
+    // starts
+    let _slot = hls::transcode_slot().await;
+    let film = match find_film(username, timestamp) {
+        Some(film) => film,
+        None => return,
+    };
+    let video_file = match blobs::local_path(storage, username, &film.video_path).await {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("Failed to fetch {} for transcoding: {}", film.video_path, e);
+            return;
+        }
+    };
+
+    // The ladder counts against the owner's quota. Films it would not fit for keep playing
+    // the original file. Without a duration, assume each rendition is as large as the source.
+    let source_size = film.width.zip(film.height);
+    let estimated_size = match film.duration {
+        Some(duration) => hls::estimated_size(source_size, duration),
+        None => fs::metadata(&video_file)
+            .map(|metadata| metadata.len() * 3)
+            .unwrap_or(u64::MAX),
+    };
+    if estimated_size > quota::remaining_quota(pool, username).await {
+        eprintln!(
+            "Not transcoding {}: the renditions would exceed {}'s storage quota",
+            film.video_path, username
+        );
+        return;
+    }
+
+    let (owner, film_timestamp) = (username.to_string(), timestamp.to_string());
+    let transcoded =
+        web::block(move || hls::transcode(&video_file, &owner, &film_timestamp, source_size)).await;
+    let playlist = match transcoded {
+        Ok(Ok(playlist)) => playlist,
+        Ok(Err(ToolError::Missing(_))) => return,
+        Ok(Err(e)) => {
+            eprintln!("Failed to transcode {}: {}", film.video_path, e);
+            return;
+        }
+        Err(e) => {
+            eprintln!("Failed to transcode {}: {}", film.video_path, e);
+            return;
+        }
+    };
+
+    // Other uploads may have used up the space while ffmpeg ran
+    let usage = quota::storage_usage(pool, username).await;
+    if usage.total > usage.quota {
+        eprintln!(
+            "Discarding the renditions of {}: {} is over their storage quota",
+            film.video_path, username
+        );
+        hls::remove(username, timestamp);
+        return;
+    }
+
+    // The film may have been changed or deleted while ffmpeg ran
+    let films_folder = format!("./user_pages/{}/films", username);
+    match find_content::<Film>(&films_folder, timestamp, |f| &f.timestamp) {
+        Some((path, mut film)) => {
+            film.hls = Some(playlist);
+            if let Err(e) = fs::write(&path, serde_json::to_string(&film).unwrap()) {
+                eprintln!("Failed to write {}: {}", path.display(), e);
+            }
+        }
+        None => hls::remove(username, timestamp),
+    }
+}
+
+async fn probe_film(pool: &SqlitePool, storage: &dyn Storage, username: &str, timestamp: &str) {
+    let films_folder = format!("./user_pages/{}/films", username);
+    let film = match find_content::<Film>(&films_folder, timestamp, |f| &f.timestamp) {
+        Some((_, film)) => film,
+        None => return,
     };
+    let video_file = match blobs::local_path(storage, username, &film.video_path).await {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("Failed to fetch {} for probing: {}", film.video_path, e);
+            return;
+        }
+    };
+
+    let poster_upload = TempUpload::from_path(
+        Path::new(&films_folder).join(format!(".{}.jpg", uuid::Uuid::new_v4())),
+    );
+    let poster_file = poster_upload.path().to_path_buf();
+    let probed = web::block(move || {
+        let details = video::probe(&video_file)?;
+        // A frame a little way in is more likely to show something than the first one
+        let seconds = details
+            .duration
+            .map(|duration| (duration / 10.0).min(5.0))
+            .unwrap_or(0.0);
+        let poster = video::extract_poster(&video_file, seconds, &poster_file);
+        Ok::<_, ToolError>((details, poster))
+    })
+    .await;
+
+    let (details, poster) = match probed {
+        Ok(Ok(probed)) => probed,
+        Ok(Err(ToolError::Missing(_))) => return,
+        Ok(Err(e)) => {
+            eprintln!("Failed to probe {}: {}", film.video_path, e);
+            return;
+        }
+        Err(e) => {
+            eprintln!("Failed to probe {}: {}", film.video_path, e);
+            return;
+        }
+    };
+
+    // Poster frames count against the owner's quota like any other image
+    let poster_fits = match fs::metadata(poster_upload.path()) {
+        Ok(metadata) => metadata.len() <= quota::remaining_quota(pool, username).await,
+        Err(_) => false,
+    };
+    let poster_path = match poster {
+        Ok(()) if !poster_fits => {
+            eprintln!(
+                "Not keeping the poster frame of {}: {} is over their storage quota",
+                film.video_path, username
+            );
+            None
+        }
+        Ok(()) => {
+            match blobs::store(
+                pool,
+                storage,
+                username,
+                poster_upload,
+                "poster.jpg",
+                MediaKind::Image,
+            )
+            .await
+            {
+                Ok(path) => Some(path),
+                Err(_) => {
+                    eprintln!("Failed to store the poster frame of {}", film.video_path);
+                    None
+                }
+            }
+        }
+        Err(ToolError::Missing(_)) => None,
+        Err(e) => {
+            eprintln!(
+                "Failed to grab a poster frame from {}: {}",
+                film.video_path, e
+            );
+            None
+        }
+    };
+
+    // The film may have been changed or deleted while the tools ran
+    let (path, mut film) = match find_content::<Film>(&films_folder, timestamp, |f| &f.timestamp) {
+        Some(found) => found,
+        None => {
+            if let Some(poster_path) = poster_path {
+                blobs::release(pool, storage, username, &poster_path).await;
+            }
+            return;
+        }
+    };
+    film.duration = details.duration;
+    film.width = details.width;
+    film.height = details.height;
+    film.video_codec = details.codec;
+    if let Some(poster_path) = &poster_path {
+        if let Some(old_poster) = film.poster.replace(poster_path.clone()) {
+            blobs::release(pool, storage, username, &old_poster).await;
+        }
+        // The poster of a public film is public too
+        if film.public {
+            if let Err(e) = sqlx::query(
+                "INSERT OR IGNORE INTO public_media (username, path, item) VALUES (?, ?, ?)",
+            )
+            .bind(username)
+            .bind(poster_path)
+            .bind(format!("Film/{}", film.timestamp))
+            .execute(pool)
+            .await
+            {
+                eprintln!("Failed to publish poster frame: {}", e);
+            }
+        }
+    }
+
+    if let Err(e) = fs::write(&path, serde_json::to_string(&film).unwrap()) {
+        eprintln!("Failed to write {}: {}", path.display(), e);
+    }
+}
+
+// Caption fields of a film form. Each "captions" file is paired with the "captionLanguage"
+// and "captionLabel" fields in the same position.
+#[derive(Default)]
+struct CaptionUploads {
+    files: Vec<(Vec<u8>, String)>,
+    languages: Vec<String>,
+    labels: Vec<String>,
+}
+
+// A caption file that passed validation, ready to be written
+pub struct ConvertedCaption {
+    vtt: String,
+    language: String,
+    label: String,
+}
+
+impl CaptionUploads {
+    // Read a field if it belongs to the captions; other fields are skipped
+    async fn receive(&mut self, name: &str, field: &mut Field) -> Result<(), HttpResponse> {
+        if !["captions", "captionLanguage", "captionLabel"].contains(&name) {
+            return Ok(());
+        }
+
+        let extension = field
+            .content_disposition()
+            .and_then(|cd| cd.get_filename())
+            .and_then(|filename| filename.rsplit_once('.'))
+            .map(|(_, extension)| extension.to_lowercase())
+            .unwrap_or_default();
+
+        let mut data = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(_) => return Err(HttpResponse::BadRequest().body("Upload was interrupted.")),
+            };
+            data.extend_from_slice(&chunk);
+            if data.len() > captions::MAX_CAPTION_SIZE {
+                return Err(HttpResponse::BadRequest().body(format!(
+                    "Caption file too big (must be under {}MB).",
+                    captions::MAX_CAPTION_SIZE / (1024 * 1024)
+                )));
+            }
+        }
+
+        match name {
+            "captions" => self.files.push((data, extension)),
+            "captionLanguage" => self.languages.push(
+                String::from_utf8(data)
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+            ),
+            _ => self.labels.push(
+                String::from_utf8(data)
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+            ),
+        }
+        Ok(())
+    }
+
+    // Check the labels and convert every file to WebVTT
+    fn convert(self) -> Result<Vec<ConvertedCaption>, HttpResponse> {
+        if self.languages.len() != self.files.len() {
+            return Err(
+                HttpResponse::BadRequest().body("Please give a language for each caption file.")
+            );
+        }
+
+        let mut converted = Vec::new();
+        for (i, ((data, extension), language)) in
+            self.files.into_iter().zip(self.languages).enumerate()
+        {
+            if !captions::is_valid_language(&language) {
+                return Err(HttpResponse::BadRequest()
+                    .body(format!("Invalid caption language \"{}\".", language)));
+            }
+            // The label defaults to the language
+            let label = match self.labels.get(i) {
+                Some(label) if !label.is_empty() => label.clone(),
+                _ => language.clone(),
+            };
+            if !captions::is_valid_label(&label) {
+                return Err(HttpResponse::BadRequest().body("Invalid caption label."));
+            }
+            let vtt = match captions::to_webvtt(&data, &extension) {
+                Ok(vtt) => vtt,
+                Err(e) => return Err(HttpResponse::BadRequest().body(e)),
+            };
+            converted.push(ConvertedCaption {
+                vtt,
+                language,
+                label,
+            });
+        }
+        Ok(converted)
+    }
+}
 
-    let audios_folder = format!("./user_pages/{}/audios", username);
-    let mut audios = Vec::new();
+// Write converted captions next to the film's metadata and list them on the film
+fn write_captions(
+    username: &str,
+    film: &mut Film,
+    captions: Vec<ConvertedCaption>,
+) -> Result<(), HttpResponse> {
+    if captions.is_empty() {
+        return Ok(());
+    }
 
-    if let Ok(entries) = fs::read_dir(&audios_folder) {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if path.is_file() && path.extension().unwrap_or_default() == "json" {
-                if let Ok(data) = fs::read_to_string(&path) {
-                    if let Ok(audio) = serde_json::from_str::<Audio>(&data) {
-                        audios.push(audio);
-                    }
-                }
-            }
+    let captions_folder = format!("./user_pages/{}/films/captions", username);
+    if fs::create_dir_all(&captions_folder).is_err() {
+        return Err(HttpResponse::InternalServerError().body("Error saving captions."));
+    }
+
+    for caption in captions {
+        let file_name = format!("{}_{}.vtt", film.timestamp, uuid::Uuid::new_v4().simple());
+        if let Err(e) = fs::write(format!("{}/{}", captions_folder, file_name), caption.vtt) {
+            eprintln!("Failed to write captions: {}", e);
+            return Err(HttpResponse::InternalServerError().body("Error saving captions."));
         }
+        film.captions.push(CaptionTrack {
+            language: caption.language,
+            label: caption.label,
+            path: format!("/user_pages/{}/films/captions/{}", username, file_name),
+        });
     }
+    Ok(())
+}
 
-    // Sort audios by title or any other criteria if needed
-    // For now, we'll leave them in the order they were read
-    HttpResponse::Ok().json(audios)
+fn remove_captions(username: &str, film: &Film) {
+    let prefix = format!("/user_pages/{}/", username);
+    for track in &film.captions {
+        if let Some(relative) = track.path.strip_prefix(&prefix) {
+            let _ = fs::remove_file(format!("./user_pages/{}/{}", username, relative));
+        }
+    }
 }
 
-pub async fn upload_film(mut payload: Multipart, req: HttpRequest) -> HttpResponse {
+// Add caption tracks to a film that has already been uploaded
+pub async fn upload_captions(
+    mut payload: Multipart,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
     // Extract the username from the cookie
     let username = match req.cookie("username") {
         Some(cookie) => cookie.value().to_string(),
         None => return HttpResponse::Unauthorized().body("User not authenticated"),
     };
 
-    let mut film_title = String::new();
-    let mut video_path = String::new();
-    let mut video_uploaded = false;
-
-    // Get the current timestamp for unique naming
-    let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
-
-    // Path to the films folder
-    let films_folder = format!("./user_pages/{}/films", username);
-    fs::create_dir_all(&films_folder).unwrap();
+    let mut timestamp = String::new();
+    let mut caption_uploads = CaptionUploads::default();
 
     // Process the multipart form data
     while let Some(item) = payload.next().await {
@@ -319,86 +1430,74 @@ pub async fn upload_film(mut payload: Multipart, req: HttpRequest) -> HttpRespon
             Err(_) => continue,
         };
 
-        let content_disposition = field.content_disposition();
-
-        if let Some(name) = content_disposition.unwrap().get_name() {
-            if name == "filmTitle" {
-                // Read the film title
-                let mut data = Vec::new();
-                while let Some(chunk) = field.next().await {
-                    let chunk = match chunk {
-                        Ok(chunk) => chunk,
-                        Err(_) => continue,
-                    };
-                    data.extend_from_slice(&chunk);
-                }
-                film_title = String::from_utf8(data).unwrap_or_default();
-            } else if name == "video" {
-                // Ensure only one video is uploaded
-                if video_uploaded {
-                    return HttpResponse::BadRequest().body("Only one video is allowed.");
-                }
-
-                // Get the filename
-                let filename = content_disposition
-                    .unwrap()
-                    .get_filename()
-                    .map(|f| sanitize_filename::sanitize(f))
-                    .unwrap_or_else(|| format!("video_{}.mp4", timestamp));
-
-                // Check file size limit (200MB)
-                let mut data = web::BytesMut::new();
-                while let Some(chunk) = field.next().await {
-                    let chunk = match chunk {
-                        Ok(chunk) => chunk,
-                        Err(_) => continue,
-                    };
-                    if (data.len() + chunk.len()) > 200 * 1024 * 1024 {
-                        return HttpResponse::BadRequest()
-                            .body("File size too big (must be under 200MB).");
-                    }
-                    data.extend_from_slice(&chunk);
-                }
-
-                // Save the file
-                let file_path = format!("{}/{}", films_folder, filename);
-                let mut f = web::block(move || std::fs::File::create(&file_path))
-                    .await
-                    .unwrap()
-                    .unwrap();
-                if let Err(_) = f.write_all(&data) {
-                    return HttpResponse::InternalServerError().body("Error saving video file.");
-                }
+        let name = match field.content_disposition().and_then(|cd| cd.get_name()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
 
-                // Set the video path
-                video_path = format!("/user_pages/{}/films/{}", username, filename);
-                video_uploaded = true;
+        if name == "timestamp" {
+            let mut data = Vec::new();
+            while let Some(chunk) = field.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(_) => continue,
+                };
+                data.extend_from_slice(&chunk);
             }
+            timestamp = String::from_utf8(data).unwrap_or_default();
+        } else if let Err(response) = caption_uploads.receive(&name, &mut field).await {
+            return response;
         }
     }
 
-    // Validate that a video was uploaded
-    if !video_uploaded {
-        return HttpResponse::BadRequest().body("Please upload a video file.");
-    }
+    let folder = format!("./user_pages/{}/films", username);
+    let (path, mut film) = match find_content::<Film>(&folder, &timestamp, |f| &f.timestamp) {
+        Some(found) => found,
+        None => return HttpResponse::NotFound().body("Film not found."),
+    };
 
-    // Save film metadata (could be saved in a database; for now, we'll save in a JSON file)
-    let film_metadata = Film {
-        title: film_title.clone(),
-        video_path: video_path.clone(),
-        timestamp: timestamp.clone(),
+    let captions = match caption_uploads.convert() {
+        Ok(captions) if captions.is_empty() => {
+            return HttpResponse::BadRequest().body("Please upload a caption file.")
+        }
+        Ok(captions) => captions,
+        Err(response) => return response,
     };
+    let captions_size: u64 = captions
+        .iter()
+        .map(|caption| caption.vtt.len() as u64)
+        .sum();
+    if captions_size > quota::remaining_quota(pool.get_ref(), &username).await {
+        return HttpResponse::PayloadTooLarge().body("Storage quota exceeded.");
+    }
 
-    let metadata_filename = format!("{}.json", timestamp);
-    let metadata_path = format!("{}/{}", films_folder, metadata_filename);
-    if let Err(_) = fs::write(
-        &metadata_path,
-        serde_json::to_string(&film_metadata).unwrap(),
-    ) {
-        return HttpResponse::InternalServerError().body("Error saving film metadata.");
+    let existing = film.captions.len();
+    if let Err(response) = write_captions(&username, &mut film, captions) {
+        return response;
+    }
+
+    // Captions of a public film are public too
+    if film.public {
+        let item_key = format!("Film/{}", film.timestamp);
+        for track in &film.captions[existing..] {
+            if let Err(e) = sqlx::query(
+                "INSERT OR IGNORE INTO public_media (username, path, item) VALUES (?, ?, ?)",
+            )
+            .bind(&username)
+            .bind(&track.path)
+            .bind(&item_key)
+            .execute(pool.get_ref())
+            .await
+            {
+                eprintln!("Failed to publish captions: {}", e);
+            }
+        }
     }
 
-    HttpResponse::Ok().body("Film uploaded successfully.")
+    match fs::write(&path, serde_json::to_string(&film).unwrap()) {
+        Ok(_) => HttpResponse::Ok().body("Captions uploaded successfully."),
+        Err(_) => HttpResponse::InternalServerError().body("Error saving film metadata."),
+    }
 }
 
 pub async fn get_films(req: HttpRequest) -> HttpResponse {
@@ -429,7 +1528,12 @@ pub async fn get_films(req: HttpRequest) -> HttpResponse {
     HttpResponse::Ok().json(films)
 }
 
-pub async fn upload_gallery(mut payload: Multipart, req: HttpRequest) -> HttpResponse {
+pub async fn upload_gallery(
+    mut payload: Multipart,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    storage: web::Data<dyn Storage>,
+) -> HttpResponse {
     // Extract the username from the cookie
     let username = match req.cookie("username") {
         Some(cookie) => cookie.value().to_string(),
@@ -438,17 +1542,18 @@ pub async fn upload_gallery(mut payload: Multipart, req: HttpRequest) -> HttpRes
         }
     };
 
-    // Create a vector to hold the image paths
-    let mut image_paths = Vec::new();
-    let mut gallery_title = String::new();
-    let mut image_count = 0;
+    // Which camera details the user wants to keep once metadata is stripped
+    let metadata_settings = settings::load_metadata_settings(pool.get_ref(), &username).await;
 
-    // Get the current timestamp for the gallery folder
-    let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
+    // Create a vector to hold the received images
+    let mut gallery_images = Vec::new();
+    let mut gallery_title = String::new();
 
-    // Path to the gallery folder
-    let gallery_folder = format!("./user_pages/{}/gallery/{}", username, timestamp);
-    fs::create_dir_all(&gallery_folder).unwrap();
+    // Images are received next to the gallery folder, which is only created once every
+    // image has been accepted
+    let galleries_folder = format!("./user_pages/{}/gallery", username);
+    fs::create_dir_all(&galleries_folder).unwrap();
+    let mut quota_remaining = quota::remaining_quota(pool.get_ref(), &username).await;
 
     // Process the multipart form data
     while let Some(item) = payload.next().await {
@@ -473,7 +1578,7 @@ pub async fn upload_gallery(mut payload: Multipart, req: HttpRequest) -> HttpRes
                 gallery_title = String::from_utf8(data).unwrap_or_default();
             } else if name == "images" {
                 // Limit to 20 images
-                if image_count >= 20 {
+                if gallery_images.len() >= 20 {
                     return HttpResponse::BadRequest().body("Maximum of 20 images allowed.");
                 }
 
@@ -481,66 +1586,242 @@ pub async fn upload_gallery(mut payload: Multipart, req: HttpRequest) -> HttpRes
                 let filename = content_disposition
                     .unwrap()
                     .get_filename()
-                    .map(|f| sanitize_filename::sanitize(f))
-                    .unwrap_or_else(|| format!("image_{}.png", image_count));
-
-                // Check file size limit (10MB)
-                let mut data = web::BytesMut::new();
-                while let Some(chunk) = field.next().await {
-                    let chunk = match chunk {
-                        Ok(chunk) => chunk,
-                        Err(_) => continue,
-                    };
-                    if (data.len() + chunk.len()) > 10 * 1024 * 1024 {
-                        return HttpResponse::BadRequest()
-                            .body("File size too big (must be under 10MB).");
-                    }
-                    data.extend_from_slice(&chunk);
-                }
-
-                // Save the file
-                let file_path = format!("{}/{}", gallery_folder, filename);
-                let mut f = web::block(move || std::fs::File::create(file_path))
-                    .await
-                    .unwrap()
-                    .unwrap();
-                if let Err(_) = f.write_all(&data) {
-                    return HttpResponse::InternalServerError().body("Error saving file.");
+                    .map(sanitize_filename::sanitize)
+                    .unwrap_or_else(|| format!("image_{}.png", gallery_images.len()));
+
+                // Stream the file to disk (10MB limit)
+                let upload = match upload::receive_file(
+                    &mut field,
+                    &galleries_folder,
+                    &filename,
+                    MediaKind::Image,
+                    MAX_IMAGE_SIZE,
+                    quota_remaining,
+                )
+                .await
+                {
+                    Ok(upload) => upload,
+                    Err(response) => return response,
+                };
+
+                // Images received so far are not on the user's books yet
+                quota_remaining = quota_remaining.saturating_sub(upload.size());
+
+                match prepare_gallery_image(upload, filename).await {
+                    Ok(image) => gallery_images.push(image),
+                    Err(response) => return response,
                 }
-
-                // Add the image path to the list
-                image_paths.push(format!(
-                    "/user_pages/{}/gallery/{}/{}",
-                    username, timestamp, filename
-                ));
-                image_count += 1;
             }
         }
     }
 
     // Validate that at least one image was uploaded
-    if image_paths.is_empty() {
+    if gallery_images.is_empty() {
         return HttpResponse::BadRequest().body("Please upload at least one image.");
     }
 
-    let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
+    match save_gallery(
+        pool.get_ref(),
+        storage.get_ref(),
+        &username,
+        &gallery_title,
+        gallery_images,
+        &metadata_settings,
+    )
+    .await
+    {
+        Ok(()) => HttpResponse::Ok().body("Gallery uploaded successfully."),
+        Err(response) => response,
+    }
+}
+
+// An uploaded image that has been validated and stripped of metadata
+pub struct GalleryImage {
+    upload: TempUpload,
+    filename: String,
+    camera_details: CameraDetails,
+}
+
+// Confirm a received file is a real image, then strip EXIF/GPS metadata before it is
+// moved into a gallery
+pub async fn prepare_gallery_image(
+    upload: TempUpload,
+    filename: String,
+) -> Result<GalleryImage, HttpResponse> {
+    let image_filename = filename.clone();
+    match web::block(move || {
+        let data = fs::read(upload.path()).map_err(|e| e.to_string())?;
+        filetype::validate_image(&image_filename, &data)?;
+        let (data, camera_details) = images::strip_metadata(data.into())?;
+        fs::write(upload.path(), data).map_err(|e| e.to_string())?;
+        Ok::<_, String>(GalleryImage {
+            upload,
+            filename: image_filename,
+            camera_details,
+        })
+    })
+    .await
+    {
+        Ok(Ok(image)) => Ok(image),
+        Ok(Err(e)) => {
+            Err(HttpResponse::BadRequest().body(format!("Invalid image \"{}\": {}", filename, e)))
+        }
+        Err(_) => Err(HttpResponse::InternalServerError().body("Error processing image.")),
+    }
+}
+
+// A timestamp for new content that no other item has, claimed by creating `path(timestamp)`
+// with `create`. Uploads finishing in the same second move on to the next free second, so
+// they never share a folder or metadata file.
+pub fn claim_timestamp(
+    path: impl Fn(&str) -> String,
+    create: fn(&str) -> std::io::Result<()>,
+) -> std::io::Result<String> {
+    let mut time = Utc::now();
+    loop {
+        let timestamp = time.format("%Y%m%d%H%M%S").to_string();
+        match create(&path(&timestamp)) {
+            Ok(()) => return Ok(timestamp),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                time += chrono::Duration::seconds(1)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Create a file that must not exist yet, for claim_timestamp
+pub fn create_new_file(path: &str) -> std::io::Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map(|_| ())
+}
+
+// Claim a timestamp for an item whose metadata is saved as `{folder}/{timestamp}.json`, by
+// creating that file empty. Direct and resumable uploads both claim through here, so they
+// never write over each other's metadata.
+pub fn claim_metadata_file(folder: &str) -> std::io::Result<String> {
+    fs::create_dir_all(folder)?;
+    claim_timestamp(
+        |timestamp| format!("{}/{}.json", folder, timestamp),
+        create_new_file,
+    )
+}
+
+// Give back a timestamp from claim_metadata_file when the item could not be saved
+pub fn unclaim_metadata_file(folder: &str, timestamp: &str) {
+    let _ = fs::remove_file(format!("{}/{}.json", folder, timestamp));
+}
+
+// Move prepared images into a new gallery folder and save its metadata.
+// Shared by /upload_gallery and resumable uploads.
+pub async fn save_gallery(
+    pool: &SqlitePool,
+    storage: &dyn Storage,
+    username: &str,
+    title: &str,
+    gallery_images: Vec<GalleryImage>,
+    metadata_settings: &MetadataSettings,
+) -> Result<(), HttpResponse> {
+    // The gallery's folder is named after its timestamp, which identifies it
+    let galleries_folder = format!("./user_pages/{}/gallery", username);
+    let timestamp = match fs::create_dir_all(&galleries_folder).and_then(|_| {
+        claim_timestamp(
+            |timestamp| format!("{}/{}", galleries_folder, timestamp),
+            |path| fs::create_dir(path),
+        )
+    }) {
+        Ok(timestamp) => timestamp,
+        Err(_) => return Err(HttpResponse::InternalServerError().body("Error saving file.")),
+    };
+    let gallery_folder = format!("{}/{}", galleries_folder, timestamp);
+
+    let mut image_paths: Vec<String> = Vec::new();
+    let mut photo_details = Vec::new();
+
+    for image in gallery_images {
+        // Identical images are shared between galleries
+        let image_path = match blobs::store(
+            pool,
+            storage,
+            username,
+            image.upload,
+            &image.filename,
+            MediaKind::Image,
+        )
+        .await
+        {
+            Ok(image_path) => image_path,
+            Err(response) => {
+                discard_gallery(pool, storage, username, &image_paths, &gallery_folder).await;
+                return Err(response);
+            }
+        };
+
+        // Keep only the camera details the user opted in to
+        let details = PhotoDetails {
+            image: image_path.clone(),
+            capture_time: image
+                .camera_details
+                .capture_time
+                .filter(|_| metadata_settings.keep_capture_time),
+            camera_model: image
+                .camera_details
+                .camera_model
+                .filter(|_| metadata_settings.keep_camera_model),
+        };
+        if details.capture_time.is_some() || details.camera_model.is_some() {
+            photo_details.push(details);
+        }
+
+        image_paths.push(image_path);
+    }
 
     // Save gallery metadata (could be saved in a database; for now, we'll save in a JSON file)
     let gallery_metadata = Gallery {
-        title: gallery_title.clone(),
-        images: image_paths.clone(),
-        timestamp: timestamp.clone(),
+        title: title.to_string(),
+        images: image_paths,
+        timestamp,
+        photo_details,
+        public: false,
     };
 
     let metadata_path = format!("{}/metadata.json", gallery_folder);
-    if let Err(_) = fs::write(
+    if fs::write(
         &metadata_path,
         serde_json::to_string(&gallery_metadata).unwrap(),
-    ) {
-        return HttpResponse::InternalServerError().body("Error saving gallery metadata.");
+    )
+    .is_err()
+    {
+        discard_gallery(
+            pool,
+            storage,
+            username,
+            &gallery_metadata.images,
+            &gallery_folder,
+        )
+        .await;
+        return Err(HttpResponse::InternalServerError().body("Error saving gallery metadata."));
     }
 
-    HttpResponse::Ok().body("Gallery uploaded successfully.")
+    Ok(())
+}
+
+// Undo a gallery that could not be saved: give back the images stored for it so their blobs
+// are not leaked, and remove its folder
+async fn discard_gallery(
+    pool: &SqlitePool,
+    storage: &dyn Storage,
+    username: &str,
+    image_paths: &[String],
+    gallery_folder: &str,
+) {
+    for image_path in image_paths {
+        blobs::release(pool, storage, username, image_path).await;
+    }
+    let _ = fs::remove_dir_all(gallery_folder);
 }
 
 pub async fn get_galleries(req: HttpRequest) -> HttpResponse {
@@ -574,7 +1855,11 @@ pub async fn get_galleries(req: HttpRequest) -> HttpResponse {
     HttpResponse::Ok().json(galleries)
 }
 
-pub async fn upload_text_post(data: web::Json<TextPostInput>, req: HttpRequest) -> HttpResponse {
+pub async fn upload_text_post(
+    data: web::Json<TextPostInput>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
     // Extract the username from the cookie
     let username = match req.cookie("username") {
         Some(cookie) => cookie.value().to_string(),
@@ -590,12 +1875,18 @@ pub async fn upload_text_post(data: web::Json<TextPostInput>, req: HttpRequest)
         return HttpResponse::BadRequest().body("Invalid input detected");
     }
 
-    // Get the current timestamp for unique file naming
-    let timestamp = Utc::now().format("%Y%m%d%H%M%S").to_string();
+    // Text posts count towards the storage quota too
+    let post_size = (data.title.len() + data.content.len()) as u64;
+    if post_size > quota::remaining_quota(pool.get_ref(), &username).await {
+        return HttpResponse::PayloadTooLarge().body("Storage quota exceeded.");
+    }
 
-    // Path to the text posts folder
+    // Path to the text posts folder, where the post is named after a timestamp of its own
     let text_posts_folder = format!("./user_pages/{}/text_posts", username);
-    fs::create_dir_all(&text_posts_folder).unwrap();
+    let timestamp = match claim_metadata_file(&text_posts_folder) {
+        Ok(timestamp) => timestamp,
+        Err(_) => return HttpResponse::InternalServerError().body("Error saving text post."),
+    };
 
     // Create a TextPost object with the current timestamp
     let text_post_input = TextPostInput {
@@ -607,13 +1898,17 @@ pub async fn upload_text_post(data: web::Json<TextPostInput>, req: HttpRequest)
         title: text_post_input.title,
         content: text_post_input.content,
         timestamp: timestamp.clone(),
+        public: false,
     };
 
     // Save the text post as a JSON file
     let file_path = format!("{}/{}.json", text_posts_folder, timestamp);
     match fs::write(&file_path, serde_json::to_string(&text_post).unwrap()) {
         Ok(_) => HttpResponse::Ok().body("Text post uploaded successfully."),
-        Err(_) => HttpResponse::InternalServerError().body("Error saving text post."),
+        Err(_) => {
+            unclaim_metadata_file(&text_posts_folder, &timestamp);
+            HttpResponse::InternalServerError().body("Error saving text post.")
+        }
     }
 }
 
@@ -655,6 +1950,11 @@ pub async fn get_all_content(req: HttpRequest) -> HttpResponse {
         }
     };
 
+    HttpResponse::Ok().json(load_all_content(&username))
+}
+
+// Every item on a user's page, newest first
+pub fn load_all_content(username: &str) -> Vec<ContentItem> {
     let mut content_items: Vec<ContentItem> = Vec::new();
 
     // Get text posts
@@ -720,22 +2020,458 @@ pub async fn get_all_content(req: HttpRequest) -> HttpResponse {
         }
     }
 
+    // Get albums
+    let albums_folder = format!("./user_pages/{}/albums", username);
+    if let Ok(entries) = fs::read_dir(&albums_folder) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_file() && path.extension().unwrap_or_default() == "json" {
+                if let Ok(data) = fs::read_to_string(&path) {
+                    if let Ok(album) = serde_json::from_str::<Album>(&data) {
+                        content_items.push(ContentItem::Album(album));
+                    }
+                }
+            }
+        }
+    }
+
     // Sort content items by timestamp in descending order
-    content_items.sort_by(|a, b| {
-        let timestamp_a = match a {
-            ContentItem::TextPost(tp) => &tp.timestamp,
-            ContentItem::Gallery(g) => &g.timestamp,
-            ContentItem::Film(f) => &f.timestamp,
-            ContentItem::Audio(aud) => &aud.timestamp,
-        };
-        let timestamp_b = match b {
+    content_items.sort_by(|a, b| b.timestamp().cmp(a.timestamp()));
+    content_items
+}
+
+impl ContentItem {
+    pub fn timestamp(&self) -> &str {
+        match self {
             ContentItem::TextPost(tp) => &tp.timestamp,
             ContentItem::Gallery(g) => &g.timestamp,
             ContentItem::Film(f) => &f.timestamp,
             ContentItem::Audio(aud) => &aud.timestamp,
-        };
-        timestamp_b.cmp(timestamp_a)
-    });
+            ContentItem::Album(album) => &album.timestamp,
+        }
+    }
+
+    pub fn is_public(&self) -> bool {
+        match self {
+            ContentItem::TextPost(tp) => tp.public,
+            ContentItem::Gallery(g) => g.public,
+            ContentItem::Film(f) => f.public,
+            ContentItem::Audio(aud) => aud.public,
+            ContentItem::Album(album) => album.public,
+        }
+    }
+
+    // Same names the "type" tag uses
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ContentItem::TextPost(_) => "TextPost",
+            ContentItem::Gallery(_) => "Gallery",
+            ContentItem::Film(_) => "Film",
+            ContentItem::Audio(_) => "Audio",
+            ContentItem::Album(_) => "Album",
+        }
+    }
+
+    // Identifies the item in layouts and exhibits, as "{type_name}/{timestamp}"
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.type_name(), self.timestamp())
+    }
+
+    // URL paths of the media files the item shows
+    pub fn media_paths(&self) -> Vec<String> {
+        fn add_audio(audio: &Audio, paths: &mut Vec<String>) {
+            paths.push(audio.audio_path.clone());
+            paths.extend(audio.cover_art.clone());
+        }
+
+        let mut paths = Vec::new();
+        match self {
+            ContentItem::TextPost(_) => {}
+            ContentItem::Gallery(g) => paths.extend(g.images.iter().cloned()),
+            ContentItem::Film(f) => {
+                paths.push(f.video_path.clone());
+                paths.extend(f.poster.clone());
+                paths.extend(f.captions.iter().map(|track| track.path.clone()));
+            }
+            ContentItem::Audio(aud) => add_audio(aud, &mut paths),
+            ContentItem::Album(album) => {
+                paths.extend(album.cover_art.clone());
+                for track in &album.tracks {
+                    add_audio(track, &mut paths);
+                }
+            }
+        }
+        paths
+    }
+
+    fn set_public(&mut self, public: bool) {
+        match self {
+            ContentItem::TextPost(tp) => tp.public = public,
+            ContentItem::Gallery(g) => g.public = public,
+            ContentItem::Film(f) => f.public = public,
+            ContentItem::Audio(aud) => aud.public = public,
+            ContentItem::Album(album) => {
+                album.public = public;
+                for track in &mut album.tracks {
+                    track.public = public;
+                }
+            }
+        }
+    }
+
+    // The item's metadata file contents, which leave out the "type" tag
+    fn to_metadata_json(&self) -> serde_json::Result<String> {
+        match self {
+            ContentItem::TextPost(tp) => serde_json::to_string(tp),
+            ContentItem::Gallery(g) => serde_json::to_string(g),
+            ContentItem::Film(f) => serde_json::to_string(f),
+            ContentItem::Audio(aud) => serde_json::to_string(aud),
+            ContentItem::Album(album) => serde_json::to_string(album),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeleteContentData {
+    #[serde(rename = "type")]
+    pub content_type: String,
+    pub timestamp: String,
+}
+
+// Find the metadata file in `folder` (or its subfolders, for galleries) whose item has the
+// given timestamp
+fn find_content<T: serde::de::DeserializeOwned>(
+    folder: &str,
+    timestamp: &str,
+    timestamp_of: fn(&T) -> &str,
+) -> Option<(std::path::PathBuf, T)> {
+    for entry in fs::read_dir(folder).ok()?.flatten() {
+        let mut path = entry.path();
+        if path.is_dir() {
+            path = path.join("metadata.json");
+        } else if path.extension().unwrap_or_default() != "json" {
+            continue;
+        }
+        if let Some(item) = fs::read_to_string(&path)
+            .ok()
+            .and_then(|data| serde_json::from_str::<T>(&data).ok())
+        {
+            if timestamp_of(&item) == timestamp {
+                return Some((path, item));
+            }
+        }
+    }
+    None
+}
+
+// Find any kind of item by its "type" tag and timestamp
+fn find_item(
+    username: &str,
+    content_type: &str,
+    timestamp: &str,
+) -> Option<(std::path::PathBuf, ContentItem)> {
+    let user_folder = format!("./user_pages/{}", username);
+    match content_type {
+        "Gallery" => find_content::<Gallery>(&format!("{}/gallery", user_folder), timestamp, |g| {
+            &g.timestamp
+        })
+        .map(|(path, g)| (path, ContentItem::Gallery(g))),
+        "Film" => find_content::<Film>(&format!("{}/films", user_folder), timestamp, |f| {
+            &f.timestamp
+        })
+        .map(|(path, f)| (path, ContentItem::Film(f))),
+        "Audio" => find_content::<Audio>(&format!("{}/audios", user_folder), timestamp, |a| {
+            &a.timestamp
+        })
+        .map(|(path, a)| (path, ContentItem::Audio(a))),
+        "Album" => find_content::<Album>(&format!("{}/albums", user_folder), timestamp, |a| {
+            &a.timestamp
+        })
+        .map(|(path, a)| (path, ContentItem::Album(a))),
+        "TextPost" => {
+            find_content::<TextPost>(&format!("{}/text_posts", user_folder), timestamp, |t| {
+                &t.timestamp
+            })
+            .map(|(path, t)| (path, ContentItem::TextPost(t)))
+        }
+        _ => None,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct VisibilityData {
+    #[serde(rename = "type")]
+    pub content_type: String,
+    pub timestamp: String,
+    pub public: bool,
+}
+
+// Make an item public or friends-only. The media files of public items are listed in
+// public_media so that visitors who are not friends can load them too.
+pub async fn set_visibility(
+    data: web::Json<VisibilityData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().body("User not authenticated");
+        }
+    };
+
+    let (path, mut item) = match find_item(&username, &data.content_type, &data.timestamp) {
+        Some(found) => found,
+        None => return HttpResponse::NotFound().body("Content not found."),
+    };
+    item.set_public(data.public);
+
+    let item_key = format!("{}/{}", item.type_name(), item.timestamp());
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Database error: {}", e))
+        }
+    };
+    if let Err(e) = sqlx::query("DELETE FROM public_media WHERE username = ? AND item = ?")
+        .bind(&username)
+        .bind(&item_key)
+        .execute(&mut tx)
+        .await
+    {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
+    if data.public {
+        for media_path in item.media_paths() {
+            if let Err(e) = sqlx::query(
+                "INSERT OR IGNORE INTO public_media (username, path, item) VALUES (?, ?, ?)",
+            )
+            .bind(&username)
+            .bind(&media_path)
+            .bind(&item_key)
+            .execute(&mut tx)
+            .await
+            {
+                return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+            }
+        }
+    }
+
+    let json = match item.to_metadata_json() {
+        Ok(json) => json,
+        Err(e) => {
+            eprintln!("Failed to serialize content metadata: {}", e);
+            return HttpResponse::InternalServerError().body("Error saving content.");
+        }
+    };
+    if let Err(e) = fs::write(&path, json) {
+        eprintln!("Failed to write {}: {}", path.display(), e);
+        return HttpResponse::InternalServerError().body("Error saving content.");
+    }
+
+    if let Err(e) = tx.commit().await {
+        return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+    }
+
+    HttpResponse::Ok().body("Visibility updated successfully.")
+}
+
+// Whether a media file belongs to at least one of the user's public items
+pub async fn is_public_media(pool: &SqlitePool, username: &str, media_path: &str) -> bool {
+    sqlx::query_scalar::<_, i64>(
+        "SELECT COUNT(*) FROM public_media WHERE username = ? AND path = ?",
+    )
+    .bind(username)
+    .bind(media_path)
+    .fetch_one(pool)
+    .await
+    .unwrap_or(0)
+        > 0
+}
+
+// Delete a gallery, film, audio file or text post. Media blobs are only removed once no
+// other item refers to them.
+pub async fn delete_content(
+    data: web::Json<DeleteContentData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    storage: web::Data<dyn Storage>,
+) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            return HttpResponse::Unauthorized().body("User not authenticated");
+        }
+    };
+
+    let user_folder = format!("./user_pages/{}", username);
+    let timestamp = data.timestamp.as_str();
+
+    match data.content_type.as_str() {
+        "Gallery" => {
+            let folder = format!("{}/gallery", user_folder);
+            let (path, gallery) =
+                match find_content::<Gallery>(&folder, timestamp, |g| &g.timestamp) {
+                    Some(found) => found,
+                    None => return HttpResponse::NotFound().body("Gallery not found."),
+                };
+            for image in &gallery.images {
+                blobs::release(pool.get_ref(), storage.get_ref(), &username, image).await;
+            }
+            if let Some(gallery_folder) = path.parent() {
+                let _ = fs::remove_dir_all(gallery_folder);
+            }
+        }
+        "Film" => {
+            let folder = format!("{}/films", user_folder);
+            let (path, film) = match find_content::<Film>(&folder, timestamp, |f| &f.timestamp) {
+                Some(found) => found,
+                None => return HttpResponse::NotFound().body("Film not found."),
+            };
+            blobs::release(
+                pool.get_ref(),
+                storage.get_ref(),
+                &username,
+                &film.video_path,
+            )
+            .await;
+            if let Some(poster) = &film.poster {
+                blobs::release(pool.get_ref(), storage.get_ref(), &username, poster).await;
+            }
+            remove_captions(&username, &film);
+            hls::remove(&username, &film.timestamp);
+            let _ = fs::remove_file(path);
+        }
+        "Audio" => {
+            let folder = format!("{}/audios", user_folder);
+            let (path, audio) = match find_content::<Audio>(&folder, timestamp, |a| &a.timestamp) {
+                Some(found) => found,
+                None => return HttpResponse::NotFound().body("Audio not found."),
+            };
+            release_audio(pool.get_ref(), storage.get_ref(), &username, &audio).await;
+            let _ = fs::remove_file(path);
+        }
+        "Album" => {
+            let folder = format!("{}/albums", user_folder);
+            let (path, album) = match find_content::<Album>(&folder, timestamp, |a| &a.timestamp) {
+                Some(found) => found,
+                None => return HttpResponse::NotFound().body("Album not found."),
+            };
+            release_album(pool.get_ref(), storage.get_ref(), &username, &album).await;
+            let _ = fs::remove_file(path);
+        }
+        "TextPost" => {
+            let folder = format!("{}/text_posts", user_folder);
+            let (path, _) = match find_content::<TextPost>(&folder, timestamp, |t| &t.timestamp) {
+                Some(found) => found,
+                None => return HttpResponse::NotFound().body("Text post not found."),
+            };
+            let _ = fs::remove_file(path);
+        }
+        _ => return HttpResponse::BadRequest().body("Unknown content type."),
+    }
+
+    if let Err(e) = sqlx::query("DELETE FROM public_media WHERE username = ? AND item = ?")
+        .bind(&username)
+        .bind(format!("{}/{}", data.content_type, timestamp))
+        .execute(pool.get_ref())
+        .await
+    {
+        eprintln!("Failed to clear public media of deleted content: {}", e);
+    }
 
-    HttpResponse::Ok().json(content_items)
+    HttpResponse::Ok().body("Content deleted successfully.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+    use actix_web::cookie::Cookie;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::{test, App};
+    use sqlx::sqlite::SqlitePoolOptions;
+    use std::sync::Arc;
+
+    // The tables storage usage is worked out from, as main creates them
+    const QUOTA_TABLES: [&str; 3] = [
+        "CREATE TABLE storage_quotas (username TEXT PRIMARY KEY, quota_bytes INTEGER NOT NULL)",
+        "CREATE TABLE media_blobs (
+            username TEXT NOT NULL,
+            hash TEXT NOT NULL,
+            file_name TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            ref_count INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY(username, hash)
+        )",
+        "CREATE TABLE tus_uploads (
+            id TEXT PRIMARY KEY,
+            username TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            title TEXT NOT NULL,
+            filename TEXT NOT NULL,
+            upload_length INTEGER NOT NULL,
+            upload_offset INTEGER NOT NULL DEFAULT 0,
+            updated_at TEXT NOT NULL
+        )",
+    ];
+
+    async fn quota_pool(username: &str, quota_bytes: i64) -> SqlitePool {
+        // One connection, so every query sees the same in-memory database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for table in QUOTA_TABLES {
+            sqlx::query(table).execute(&pool).await.unwrap();
+        }
+        sqlx::query("INSERT INTO storage_quotas (username, quota_bytes) VALUES (?, ?)")
+            .bind(username)
+            .bind(quota_bytes)
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool
+    }
+
+    fn multipart_film(video: &[u8]) -> Vec<u8> {
+        let mut body = b"--X\r\nContent-Disposition: form-data; name=\"filmTitle\"\r\n\r\nTrip\r\n\
+                         --X\r\nContent-Disposition: form-data; name=\"video\"; \
+                         filename=\"trip.mp4\"\r\nContent-Type: video/mp4\r\n\r\n"
+            .to_vec();
+        body.extend_from_slice(video);
+        body.extend_from_slice(b"\r\n--X--\r\n");
+        body
+    }
+
+    #[actix_web::test]
+    async fn films_filling_most_of_the_remaining_quota_are_accepted() {
+        let username = format!("quota-test-{}", uuid::Uuid::new_v4().simple());
+        let pool = quota_pool(&username, 1_000_000).await;
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::from(storage))
+                .route("/upload_film", web::post().to(upload_film)),
+        )
+        .await;
+
+        let mut video = b"\0\0\0\x18ftypisom\0\0\0\0isommp41".to_vec();
+        video.resize(600_000, 0);
+        let request = test::TestRequest::post()
+            .uri("/upload_film")
+            .cookie(Cookie::new("username", username.clone()))
+            .insert_header((header::CONTENT_TYPE, "multipart/form-data; boundary=X"))
+            .set_payload(multipart_film(&video))
+            .to_request();
+        let status = test::call_service(&app, request).await.status();
+
+        let _ = fs::remove_dir_all(format!("./user_pages/{}", username));
+        assert_eq!(status, StatusCode::OK);
+    }
 }
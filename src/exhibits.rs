@@ -0,0 +1,485 @@
+// Extra named pages besides my_page.html, such as "Travel 2025" or "Band". Each has its own
+// title, layout, linked content items and visibility, and is served at
+// /user_pages/{username}/{slug} through the same access checks as the rest of the folder.
+use crate::customize::{self, ContentItem};
+use crate::friends;
+use crate::layout::PageLayout;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+const MAX_PAGES: i64 = 20;
+const MAX_TITLE_LENGTH: usize = 60;
+const MAX_SLUG_LENGTH: usize = 40;
+const MAX_PAGE_ITEMS: usize = 200;
+
+// Folders in a user's directory, which a page slug must not shadow
+const RESERVED_SLUGS: [&str; 6] = ["blobs", "gallery", "films", "audios", "albums", "hls"];
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    // Only the owner
+    Private,
+    // The owner and their friends, like my_page.html
+    Friends,
+    // Anyone, including people who are not logged in
+    Public,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Private => "private",
+            Visibility::Friends => "friends",
+            Visibility::Public => "public",
+        }
+    }
+
+    pub fn parse(value: &str) -> Visibility {
+        match value {
+            "public" => Visibility::Public,
+            "friends" => Visibility::Friends,
+            _ => Visibility::Private,
+        }
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        match self {
+            Visibility::Private => access == Access::Owner,
+            Visibility::Friends => access != Access::Visitor,
+            Visibility::Public => true,
+        }
+    }
+}
+
+// How the person viewing a page relates to its owner
+#[derive(Clone, Copy, PartialEq)]
+pub enum Access {
+    Owner,
+    Friend,
+    Visitor,
+}
+
+pub async fn access(pool: &SqlitePool, owner: &str, viewer: Option<&str>) -> Access {
+    match viewer {
+        Some(viewer) if viewer == owner => Access::Owner,
+        Some(viewer) if friends::are_friends(pool, viewer, owner).await => Access::Friend,
+        _ => Access::Visitor,
+    }
+}
+
+#[derive(Serialize)]
+pub struct ExhibitPage {
+    pub slug: String,
+    pub title: String,
+    pub visibility: Visibility,
+    // Linked content items as "{Type}/{timestamp}", in the order they are shown
+    pub items: Vec<String>,
+    #[serde(skip)]
+    pub layout: PageLayout,
+}
+
+#[derive(sqlx::FromRow)]
+struct PageRow {
+    slug: String,
+    title: String,
+    visibility: String,
+    items: String,
+    layout: String,
+}
+
+impl From<PageRow> for ExhibitPage {
+    fn from(row: PageRow) -> ExhibitPage {
+        ExhibitPage {
+            slug: row.slug,
+            title: row.title,
+            visibility: Visibility::parse(&row.visibility),
+            items: serde_json::from_str(&row.items).unwrap_or_default(),
+            layout: serde_json::from_str(&row.layout)
+                .unwrap_or_else(|_| PageLayout::default_layout()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreatePageData {
+    pub title: String,
+    pub visibility: Visibility,
+}
+
+#[derive(Deserialize)]
+pub struct UpdatePageData {
+    pub slug: String,
+    pub title: Option<String>,
+    pub visibility: Option<Visibility>,
+    pub items: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct DeletePageData {
+    pub slug: String,
+}
+
+// Slugs are lowercase letters, digits and dashes, so they never contain a "." and cannot
+// be mistaken for a file in the user's folder
+pub fn is_slug(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_SLUG_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && !RESERVED_SLUGS.contains(&name)
+}
+
+// "Travel 2025" becomes "travel-2025"
+fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    for c in title.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(MAX_SLUG_LENGTH - 4);
+    let slug = slug.trim_end_matches('-').to_string();
+    if is_slug(&slug) {
+        slug
+    } else {
+        format!("page-{}", slug).trim_end_matches('-').to_string()
+    }
+}
+
+fn is_valid_title(title: &str) -> bool {
+    let title = title.trim();
+    !title.is_empty() && title.chars().count() <= MAX_TITLE_LENGTH
+}
+
+pub async fn find(
+    pool: &SqlitePool,
+    username: &str,
+    slug: &str,
+) -> Result<Option<ExhibitPage>, sqlx::Error> {
+    sqlx::query_as::<_, PageRow>(
+        "SELECT slug, title, visibility, items, layout FROM exhibit_pages
+         WHERE username = ? AND slug = ?",
+    )
+    .bind(username)
+    .bind(slug)
+    .fetch_optional(pool)
+    .await
+    .map(|row| row.map(ExhibitPage::from))
+}
+
+// A user's pages in navigation order
+pub async fn list(pool: &SqlitePool, username: &str) -> Result<Vec<ExhibitPage>, sqlx::Error> {
+    sqlx::query_as::<_, PageRow>(
+        "SELECT slug, title, visibility, items, layout FROM exhibit_pages
+         WHERE username = ? ORDER BY position",
+    )
+    .bind(username)
+    .fetch_all(pool)
+    .await
+    .map(|rows| rows.into_iter().map(ExhibitPage::from).collect())
+}
+
+pub async fn visibility(pool: &SqlitePool, username: &str, slug: &str) -> Option<Visibility> {
+    sqlx::query_scalar::<_, String>(
+        "SELECT visibility FROM exhibit_pages WHERE username = ? AND slug = ?",
+    )
+    .bind(username)
+    .bind(slug)
+    .fetch_optional(pool)
+    .await
+    .unwrap_or_else(|e| {
+        eprintln!("Failed to look up page {} of {}: {}", slug, username, e);
+        None
+    })
+    .map(|visibility| Visibility::parse(&visibility))
+}
+
+// Returns false if the page does not exist
+pub async fn save_layout(
+    pool: &SqlitePool,
+    username: &str,
+    slug: &str,
+    layout: &PageLayout,
+) -> Result<bool, sqlx::Error> {
+    let layout = serde_json::to_string(layout).unwrap_or_default();
+    sqlx::query("UPDATE exhibit_pages SET layout = ? WHERE username = ? AND slug = ?")
+        .bind(layout)
+        .bind(username)
+        .bind(slug)
+        .execute(pool)
+        .await
+        .map(|result| result.rows_affected() > 0)
+}
+
+// The page's linked items that `access` may see, in the page's order. Visitors only see the
+// items that are public, whatever the page's own visibility.
+pub fn linked_content(username: &str, page: &ExhibitPage, access: Access) -> Vec<ContentItem> {
+    let mut content = customize::load_all_content(username);
+    let mut linked = Vec::new();
+    for id in &page.items {
+        if let Some(index) = content.iter().position(|item| item.id() == *id) {
+            let item = content.swap_remove(index);
+            if access != Access::Visitor || item.is_public() {
+                linked.push(item);
+            }
+        }
+    }
+    linked
+}
+
+pub async fn list_pages(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    match list(pool.get_ref(), &username).await {
+        Ok(pages) => HttpResponse::Ok().json(pages),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+pub async fn create_page(
+    data: web::Json<CreatePageData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    if !is_valid_title(&data.title) {
+        return HttpResponse::BadRequest().body(format!(
+            "Page titles must be 1 to {} characters.",
+            MAX_TITLE_LENGTH
+        ));
+    }
+
+    let existing = match list(pool.get_ref(), &username).await {
+        Ok(pages) => pages,
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Database error: {}", e))
+        }
+    };
+    if existing.len() as i64 >= MAX_PAGES {
+        return HttpResponse::BadRequest().body(format!("You can have up to {} pages.", MAX_PAGES));
+    }
+
+    // Pages with the same title get numbered slugs
+    let base = slugify(&data.title);
+    let mut slug = base.clone();
+    let mut number = 2;
+    while existing.iter().any(|page| page.slug == slug) {
+        slug = format!("{}-{}", base, number);
+        number += 1;
+    }
+
+    let page = ExhibitPage {
+        slug,
+        title: data.title.trim().to_string(),
+        visibility: data.visibility,
+        items: Vec::new(),
+        layout: PageLayout::default_layout(),
+    };
+    let result = sqlx::query(
+        "INSERT INTO exhibit_pages (username, slug, title, visibility, items, layout, position)
+         VALUES (?, ?, ?, ?, '[]', ?,
+            (SELECT COALESCE(MAX(position), 0) + 1 FROM exhibit_pages WHERE username = ?))",
+    )
+    .bind(&username)
+    .bind(&page.slug)
+    .bind(&page.title)
+    .bind(page.visibility.as_str())
+    .bind(serde_json::to_string(&page.layout).unwrap_or_default())
+    .bind(&username)
+    .execute(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(_) => HttpResponse::Ok().json(page),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+// Change a page's title, visibility or linked items. The slug, and so the page's URL, stays
+// the same.
+pub async fn update_page(
+    data: web::Json<UpdatePageData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    let mut page = match find(pool.get_ref(), &username, &data.slug).await {
+        Ok(Some(page)) => page,
+        Ok(None) => return HttpResponse::NotFound().body("Page not found"),
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Database error: {}", e))
+        }
+    };
+
+    if let Some(title) = &data.title {
+        if !is_valid_title(title) {
+            return HttpResponse::BadRequest().body(format!(
+                "Page titles must be 1 to {} characters.",
+                MAX_TITLE_LENGTH
+            ));
+        }
+        page.title = title.trim().to_string();
+    }
+    if let Some(visibility) = data.visibility {
+        page.visibility = visibility;
+    }
+    if let Some(items) = &data.items {
+        if items.len() > MAX_PAGE_ITEMS {
+            return HttpResponse::BadRequest()
+                .body(format!("A page can link up to {} items.", MAX_PAGE_ITEMS));
+        }
+        let content: Vec<String> = customize::load_all_content(&username)
+            .iter()
+            .map(ContentItem::id)
+            .collect();
+        let mut linked: Vec<String> = Vec::new();
+        for id in items {
+            if !content.contains(id) {
+                return HttpResponse::BadRequest().body(format!("{} is not on your page.", id));
+            }
+            if !linked.contains(id) {
+                linked.push(id.clone());
+            }
+        }
+        page.items = linked;
+    }
+
+    let result = sqlx::query(
+        "UPDATE exhibit_pages SET title = ?, visibility = ?, items = ?
+         WHERE username = ? AND slug = ?",
+    )
+    .bind(&page.title)
+    .bind(page.visibility.as_str())
+    .bind(serde_json::to_string(&page.items).unwrap_or_default())
+    .bind(&username)
+    .bind(&page.slug)
+    .execute(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(_) => HttpResponse::Ok().json(page),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+// Deleting a page leaves the content it linked to in place
+pub async fn delete_page(
+    data: web::Json<DeletePageData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    let result = sqlx::query("DELETE FROM exhibit_pages WHERE username = ? AND slug = ?")
+        .bind(&username)
+        .bind(&data.slug)
+        .execute(pool.get_ref())
+        .await;
+
+    match result {
+        Ok(result) if result.rows_affected() > 0 => {
+            HttpResponse::Ok().body("Page deleted successfully")
+        }
+        Ok(_) => HttpResponse::NotFound().body("Page not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+// The content shown on one of a user's pages, for whoever may see the page
+pub async fn page_content(
+    path: web::Path<(String, String)>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let (username, slug) = path.into_inner();
+    let page = match find(pool.get_ref(), &username, &slug).await {
+        Ok(Some(page)) => page,
+        Ok(None) => return HttpResponse::NotFound().body("Page not found"),
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Database error: {}", e))
+        }
+    };
+
+    let viewer = req
+        .cookie("username")
+        .map(|cookie| cookie.value().to_string());
+    let access = access(pool.get_ref(), &username, viewer.as_deref()).await;
+    if !page.visibility.allows(access) {
+        return match viewer {
+            Some(_) => HttpResponse::Forbidden().finish(),
+            None => HttpResponse::Unauthorized().finish(),
+        };
+    }
+
+    HttpResponse::Ok().json(linked_content(&username, &page, access))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn titles_become_slugs() {
+        assert_eq!(slugify("Travel 2025"), "travel-2025");
+        assert_eq!(slugify("  Hello,  World!  "), "hello-world");
+        assert_eq!(slugify("Café au lait"), "caf-au-lait");
+    }
+
+    #[test]
+    fn reserved_and_empty_slugs_get_a_prefix() {
+        assert_eq!(slugify("Gallery"), "page-gallery");
+        assert_eq!(slugify("HLS"), "page-hls");
+        assert_eq!(slugify("日本"), "page");
+        assert_eq!(slugify("!!!"), "page");
+    }
+
+    #[test]
+    fn long_titles_are_cut_to_a_valid_slug() {
+        for title in ["a".repeat(100), "a ".repeat(100), "Gallery ".repeat(20)] {
+            let slug = slugify(&title);
+            assert!(is_slug(&slug), "{} is not a slug", slug);
+        }
+    }
+
+    #[test]
+    fn only_slugs_that_cannot_name_a_file_are_accepted() {
+        for slug in ["travel", "travel-2025", "a", &"a".repeat(MAX_SLUG_LENGTH)] {
+            assert!(is_slug(slug), "{} was refused", slug);
+        }
+        for name in [
+            "",
+            "my_page.html",
+            "my_styles.css",
+            "Travel",
+            "-travel",
+            "travel-",
+            "../x",
+            "blobs",
+            "gallery",
+            &"a".repeat(MAX_SLUG_LENGTH + 1),
+        ] {
+            assert!(!is_slug(name), "{} was accepted", name);
+        }
+    }
+}
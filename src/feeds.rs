@@ -0,0 +1,536 @@
+// Feeds for following a user's exhibit outside the site. Podcast apps and feed readers cannot
+// send our session cookie, so every subscriber gets their own secret token that goes in the
+// feed URL and in the URLs of the media the feed links to. Public items are also published
+// in an open Atom feed that needs no token.
+use crate::customize::ContentItem;
+use crate::page::escape_html;
+use crate::{blobs, customize, friends, user};
+use actix_web::{web, HttpRequest, HttpResponse};
+use atom_syndication::{Content, Entry, Feed, Link, Person};
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use rss::extension::itunes::{ITunesChannelExtension, ITunesItemExtension, ITunesOwner};
+use rss::{Channel, Enclosure, Guid, Item};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::SqlitePool;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+// Feeds a subscriber can ask for a token to
+const FEEDS: &[&str] = &["podcast", "atom"];
+
+#[derive(Deserialize)]
+pub struct FeedTokenData {
+    // Whose feed to subscribe to
+    pub username: String,
+    pub feed: String,
+}
+
+#[derive(Deserialize)]
+pub struct FeedQuery {
+    pub token: Option<String>,
+}
+
+// Give the logged-in user a token for a friend's (or their own) feed. Asking again returns
+// the same token.
+pub async fn create_feed_token(
+    data: web::Json<FeedTokenData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    // Extract the username from the cookie
+    let subscriber = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    if !user::is_valid_username(&data.username) {
+        return HttpResponse::BadRequest().body("Invalid username.");
+    }
+    if !FEEDS.contains(&data.feed.as_str()) {
+        return HttpResponse::BadRequest().body("Unknown feed.");
+    }
+    if subscriber != data.username
+        && !friends::are_friends(pool.get_ref(), &subscriber, &data.username).await
+    {
+        return HttpResponse::Forbidden().body("Only friends can subscribe to this feed.");
+    }
+
+    let existing = sqlx::query_scalar::<_, String>(
+        "SELECT token FROM feed_tokens WHERE owner = ? AND subscriber = ? AND feed = ?",
+    )
+    .bind(&data.username)
+    .bind(&subscriber)
+    .bind(&data.feed)
+    .fetch_optional(pool.get_ref())
+    .await;
+
+    let token = match existing {
+        Ok(Some(token)) => token,
+        Ok(None) => {
+            let token = Uuid::new_v4().to_string();
+            if let Err(e) = sqlx::query(
+                "INSERT INTO feed_tokens (token, owner, subscriber, feed) VALUES (?, ?, ?, ?)",
+            )
+            .bind(&token)
+            .bind(&data.username)
+            .bind(&subscriber)
+            .bind(&data.feed)
+            .execute(pool.get_ref())
+            .await
+            {
+                return HttpResponse::InternalServerError().body(format!("Database error: {}", e));
+            }
+            token
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Database error: {}", e))
+        }
+    };
+
+    HttpResponse::Ok().json(json!({
+        "url": format!("/{}/{}?token={}", data.feed, data.username, token)
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct RevokeFeedTokenData {
+    pub subscriber: String,
+    pub feed: String,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+struct FeedSubscription {
+    subscriber: String,
+    feed: String,
+    created_at: String,
+}
+
+// List who holds a token to the logged-in user's feeds
+pub async fn list_feed_tokens(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
+    // Extract the username from the cookie
+    let owner = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    match sqlx::query_as::<_, FeedSubscription>(
+        "SELECT subscriber, feed, CAST(created_at AS TEXT) AS created_at FROM feed_tokens
+         WHERE owner = ? ORDER BY created_at DESC",
+    )
+    .bind(&owner)
+    .fetch_all(pool.get_ref())
+    .await
+    {
+        Ok(subscriptions) => HttpResponse::Ok().json(subscriptions),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+// Revoke one subscriber's token to one of the logged-in user's feeds. Their feed URL stops
+// working straight away; other subscribers are not affected.
+pub async fn revoke_feed_token(
+    data: web::Json<RevokeFeedTokenData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    // Extract the username from the cookie
+    let owner = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    match sqlx::query("DELETE FROM feed_tokens WHERE owner = ? AND subscriber = ? AND feed = ?")
+        .bind(&owner)
+        .bind(&data.subscriber)
+        .bind(&data.feed)
+        .execute(pool.get_ref())
+        .await
+    {
+        Ok(result) if result.rows_affected() == 0 => {
+            HttpResponse::NotFound().body("Feed token not found.")
+        }
+        Ok(_) => HttpResponse::Ok().body("Feed token revoked."),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+// Whether `token` lets its holder read `owner`'s `feed`. Tokens stop working as soon as the
+// subscriber is no longer a friend.
+pub async fn token_grants(pool: &SqlitePool, owner: &str, feed: &str, token: &str) -> bool {
+    let subscriber = sqlx::query_scalar::<_, String>(
+        "SELECT subscriber FROM feed_tokens WHERE token = ? AND owner = ? AND feed = ?",
+    )
+    .bind(token)
+    .bind(owner)
+    .bind(feed)
+    .fetch_optional(pool)
+    .await
+    .unwrap_or(None);
+
+    match subscriber {
+        Some(subscriber) => {
+            subscriber == owner || friends::are_friends(pool, &subscriber, owner).await
+        }
+        None => false,
+    }
+}
+
+// Whether `token` lets its holder fetch `owner`'s media at `url_path`. A token only reaches
+// the files listed in the feed it was given for: a podcast token the episodes and their cover
+// art, an Atom token the media of the items in the feed.
+pub async fn token_allows_media(
+    pool: &SqlitePool,
+    owner: &str,
+    token: &str,
+    url_path: &str,
+) -> bool {
+    let feed = sqlx::query_scalar::<_, String>(
+        "SELECT feed FROM feed_tokens WHERE token = ? AND owner = ?",
+    )
+    .bind(token)
+    .bind(owner)
+    .fetch_optional(pool)
+    .await
+    .unwrap_or(None);
+    let feed = match feed {
+        Some(feed) => feed,
+        None => return false,
+    };
+    if !token_grants(pool, owner, &feed, token).await {
+        return false;
+    }
+
+    match feed.as_str() {
+        "podcast" => customize::load_audios(owner).iter().any(|audio| {
+            audio.audio_path == url_path || audio.cover_art.as_deref() == Some(url_path)
+        }),
+        "atom" => customize::load_all_content(owner)
+            .iter()
+            .any(|item| listed_media(item).iter().any(|path| path == url_path)),
+        _ => false,
+    }
+}
+
+// The media paths an item's Atom entry links to
+fn listed_media(item: &ContentItem) -> Vec<String> {
+    let listed = RefCell::new(Vec::new());
+    entry_body(item, &|path| {
+        listed.borrow_mut().push(path.to_string());
+        String::new()
+    });
+    listed.into_inner()
+}
+
+// Content timestamps are stored as UTC "%Y%m%d%H%M%S"
+fn parse_timestamp(timestamp: &str) -> Option<chrono::DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(timestamp, "%Y%m%d%H%M%S")
+        .ok()
+        .map(|naive| Utc.from_utc_datetime(&naive))
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
+// Scheme and host the client used to reach us, for building absolute links
+fn base_url(req: &HttpRequest) -> String {
+    let connection = req.connection_info();
+    format!("{}://{}", connection.scheme(), connection.host())
+}
+
+// RSS 2.0 feed, with iTunes podcast extensions, of a user's audio posts
+pub async fn podcast_feed(
+    path: web::Path<String>,
+    query: web::Query<FeedQuery>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let owner = path.into_inner();
+    if !user::is_valid_username(&owner) {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let token = match &query.token {
+        Some(token) => token,
+        None => return HttpResponse::Unauthorized().body("A feed token is required."),
+    };
+    if !token_grants(pool.get_ref(), &owner, "podcast", token).await {
+        return HttpResponse::Forbidden().body("Invalid feed token.");
+    }
+
+    // Podcast apps need absolute URLs, and the token so they can fetch the media
+    let base_url = base_url(&req);
+    let media_url = |path: &str| format!("{}{}?token={}", base_url, path, token);
+
+    let mut audios = customize::load_audios(&owner);
+    audios.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+    let mut items = Vec::new();
+    for audio in &audios {
+        let extension = audio
+            .audio_path
+            .rsplit_once('.')
+            .map(|(_, extension)| extension)
+            .unwrap_or_default();
+        let length = blobs::size(pool.get_ref(), &owner, &audio.audio_path)
+            .await
+            .unwrap_or(0);
+
+        let mut enclosure = Enclosure::default();
+        enclosure.set_url(media_url(&audio.audio_path));
+        enclosure.set_length(length.to_string());
+        enclosure.set_mime_type(actix_files::file_extension_to_mime(extension).to_string());
+
+        let mut itunes = ITunesItemExtension::default();
+        itunes.set_author(audio.artist.clone().unwrap_or_else(|| owner.clone()));
+        itunes.set_duration(audio.duration.map(format_duration));
+        itunes.set_image(audio.cover_art.as_deref().map(media_url));
+        if let Some(track_number) = audio.track_number {
+            itunes.set_episode(track_number.to_string());
+        }
+
+        let mut guid = Guid::default();
+        guid.set_value(audio.audio_path.clone());
+        guid.set_permalink(false);
+
+        let mut item = Item::default();
+        item.set_title(audio.title.clone());
+        item.set_enclosure(enclosure);
+        item.set_guid(guid);
+        item.set_pub_date(parse_timestamp(&audio.timestamp).map(|date| date.to_rfc2822()));
+        item.set_itunes_ext(itunes);
+        items.push(item);
+    }
+
+    let mut itunes = ITunesChannelExtension::default();
+    itunes.set_author(owner.clone());
+    itunes.set_explicit("false".to_string());
+    itunes.set_type("episodic".to_string());
+    let mut itunes_owner = ITunesOwner::default();
+    itunes_owner.set_name(owner.clone());
+    itunes.set_owner(itunes_owner);
+    // Use the newest episode's cover art for the show
+    itunes.set_image(
+        audios
+            .iter()
+            .find_map(|audio| audio.cover_art.as_deref())
+            .map(media_url),
+    );
+
+    let mut namespaces = BTreeMap::new();
+    namespaces.insert(
+        "itunes".to_string(),
+        rss::extension::itunes::NAMESPACE.to_string(),
+    );
+
+    let mut channel = Channel::default();
+    channel.set_title(format!("{}'s podcast", owner));
+    channel.set_link(format!("{}/user_pages/{}/my_page.html", base_url, owner));
+    channel.set_description(format!("Audio posts from {}'s exhibit", owner));
+    channel.set_language("en".to_string());
+    channel.set_last_build_date(Utc::now().to_rfc2822());
+    channel.set_namespaces(namespaces);
+    channel.set_itunes_ext(itunes);
+    channel.set_items(items);
+
+    HttpResponse::Ok()
+        .content_type("application/rss+xml; charset=utf-8")
+        .body(channel.to_string())
+}
+
+// HTML shown by feed readers for an item, plus enclosure links for its audio and video
+fn entry_body(item: &ContentItem, media_url: &dyn Fn(&str) -> String) -> (String, Vec<Link>) {
+    let mut html = String::new();
+    let mut enclosures = Vec::new();
+    let mut enclose = |path: &str| {
+        let extension = path.rsplit_once('.').map(|(_, e)| e).unwrap_or_default();
+        let mut link = Link::default();
+        link.set_href(media_url(path));
+        link.set_rel("enclosure".to_string());
+        link.set_mime_type(actix_files::file_extension_to_mime(extension).to_string());
+        enclosures.push(link);
+    };
+
+    match item {
+        ContentItem::TextPost(post) => {
+            for paragraph in post.content.split("\n\n") {
+                html += &format!("<p>{}</p>", escape_html(paragraph).replace('\n', "<br>"));
+            }
+        }
+        ContentItem::Gallery(gallery) => {
+            for image in &gallery.images {
+                html += &format!(
+                    "<p><img src=\"{}\" alt=\"{}\"></p>",
+                    escape_html(&media_url(image)),
+                    escape_html(&gallery.title)
+                );
+            }
+        }
+        ContentItem::Film(film) => {
+            let url = escape_html(&media_url(&film.video_path));
+            let poster = film
+                .poster
+                .as_deref()
+                .map(|poster| format!(" poster=\"{}\"", escape_html(&media_url(poster))))
+                .unwrap_or_default();
+            html += &format!(
+                "<video src=\"{0}\"{1} controls></video><p><a href=\"{0}\">Watch the film</a></p>",
+                url, poster
+            );
+            enclose(&film.video_path);
+        }
+        ContentItem::Audio(audio) => {
+            if let Some(cover_art) = &audio.cover_art {
+                html += &format!(
+                    "<p><img src=\"{}\" alt=\"\"></p>",
+                    escape_html(&media_url(cover_art))
+                );
+            }
+            if let Some(artist) = &audio.artist {
+                html += &format!("<p>{}</p>", escape_html(artist));
+            }
+            html += &format!(
+                "<audio src=\"{}\" controls></audio>",
+                escape_html(&media_url(&audio.audio_path))
+            );
+            enclose(&audio.audio_path);
+        }
+        ContentItem::Album(album) => {
+            if let Some(cover_art) = &album.cover_art {
+                html += &format!(
+                    "<p><img src=\"{}\" alt=\"\"></p>",
+                    escape_html(&media_url(cover_art))
+                );
+            }
+            if !album.description.is_empty() {
+                html += &format!("<p>{}</p>", escape_html(&album.description));
+            }
+            html += "<ol>";
+            for track in &album.tracks {
+                html += &format!(
+                    "<li>{} <audio src=\"{}\" controls></audio></li>",
+                    escape_html(&track.title),
+                    escape_html(&media_url(&track.audio_path))
+                );
+                enclose(&track.audio_path);
+            }
+            html += "</ol>";
+        }
+    }
+
+    (html, enclosures)
+}
+
+fn item_title(item: &ContentItem) -> &str {
+    match item {
+        ContentItem::TextPost(post) => &post.title,
+        ContentItem::Gallery(gallery) => &gallery.title,
+        ContentItem::Film(film) => &film.title,
+        ContentItem::Audio(audio) => &audio.title,
+        ContentItem::Album(album) => &album.title,
+    }
+}
+
+// Atom feed of everything on a user's page. Without a token only public items are listed;
+// with a subscriber's token the friends-only items are included as well.
+pub async fn atom_feed(
+    path: web::Path<String>,
+    query: web::Query<FeedQuery>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let owner = path.into_inner();
+    if !user::is_valid_username(&owner) {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let token = query.token.as_deref();
+    if let Some(token) = token {
+        if !token_grants(pool.get_ref(), &owner, "atom", token).await {
+            return HttpResponse::Forbidden().body("Invalid feed token.");
+        }
+    }
+
+    // Feed readers need absolute URLs. Media of friends-only items needs the token too.
+    let base_url = base_url(&req);
+    let media_url = |path: &str| match token {
+        Some(token) => format!("{}{}?token={}", base_url, path, token),
+        None => format!("{}{}", base_url, path),
+    };
+    let page_url = format!("{}/user_pages/{}/my_page.html", base_url, owner);
+    let feed_url = match token {
+        Some(token) => format!("{}/atom/{}?token={}", base_url, owner, token),
+        None => format!("{}/atom/{}", base_url, owner),
+    };
+
+    let mut author = Person::default();
+    author.set_name(owner.clone());
+
+    let items: Vec<ContentItem> = customize::load_all_content(&owner)
+        .into_iter()
+        .filter(|item| token.is_some() || item.is_public())
+        .collect();
+
+    let mut entries = Vec::new();
+    for item in &items {
+        let (html, mut links) = entry_body(item, &media_url);
+
+        let mut alternate = Link::default();
+        alternate.set_href(page_url.clone());
+        links.insert(0, alternate);
+
+        let mut content = Content::default();
+        content.set_content_type("html".to_string());
+        content.set_value(html);
+
+        let updated = parse_timestamp(item.timestamp()).unwrap_or_else(Utc::now);
+
+        let mut entry = Entry::default();
+        entry.set_id(format!(
+            "{}/atom/{}#{}-{}",
+            base_url,
+            owner,
+            item.type_name(),
+            item.timestamp()
+        ));
+        entry.set_title(item_title(item).to_string());
+        entry.set_updated(updated);
+        entry.set_published(Some(updated.into()));
+        entry.set_authors(vec![author.clone()]);
+        entry.set_links(links);
+        entry.set_content(content);
+        entries.push(entry);
+    }
+
+    let mut self_link = Link::default();
+    self_link.set_href(feed_url);
+    self_link.set_rel("self".to_string());
+    let mut page_link = Link::default();
+    page_link.set_href(page_url);
+
+    let mut feed = Feed::default();
+    feed.set_id(format!("{}/atom/{}", base_url, owner));
+    feed.set_title(format!("{}'s Exhibit", owner));
+    // Items are loaded newest first
+    feed.set_updated(
+        items
+            .first()
+            .and_then(|item| parse_timestamp(item.timestamp()))
+            .unwrap_or_else(Utc::now),
+    );
+    feed.set_authors(vec![author]);
+    feed.set_links(vec![self_link, page_link]);
+    feed.set_entries(entries);
+
+    HttpResponse::Ok()
+        .content_type("application/atom+xml; charset=utf-8")
+        .body(feed.to_string())
+}
@@ -0,0 +1,274 @@
+// How many leading bytes uploads are checked on. Most signatures are a few bytes, but MP4
+// files can be recognised by a compatible brand near the end of their ftyp box.
+pub const SNIFF_LEN: usize = 256;
+
+// The kind of media an upload endpoint expects
+#[derive(Clone, Copy, PartialEq)]
+pub enum MediaKind {
+    Image,
+    Video,
+    Audio,
+}
+
+impl MediaKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MediaKind::Image => "an image",
+            MediaKind::Video => "a video",
+            MediaKind::Audio => "an audio file",
+        }
+    }
+}
+
+// A file type we accept, recognised by its magic bytes rather than the client's filename
+struct Signature {
+    kinds: &'static [MediaKind],
+    extensions: &'static [&'static str],
+    matches: fn(&[u8]) -> bool,
+}
+
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        kinds: &[MediaKind::Image],
+        extensions: &["jpg", "jpeg"],
+        matches: |d| d.starts_with(&[0xFF, 0xD8, 0xFF]),
+    },
+    Signature {
+        kinds: &[MediaKind::Image],
+        extensions: &["png"],
+        matches: |d| d.starts_with(b"\x89PNG\r\n\x1a\n"),
+    },
+    Signature {
+        kinds: &[MediaKind::Image],
+        extensions: &["gif"],
+        matches: |d| d.starts_with(b"GIF87a") || d.starts_with(b"GIF89a"),
+    },
+    Signature {
+        kinds: &[MediaKind::Image],
+        extensions: &["webp"],
+        matches: |d| d.len() >= 12 && &d[0..4] == b"RIFF" && &d[8..12] == b"WEBP",
+    },
+    Signature {
+        kinds: &[MediaKind::Image],
+        extensions: &["bmp"],
+        matches: |d| d.starts_with(b"BM"),
+    },
+    Signature {
+        kinds: &[MediaKind::Video],
+        extensions: &["mp4"],
+        matches: is_mp4,
+    },
+    Signature {
+        kinds: &[MediaKind::Video],
+        extensions: &["webm"],
+        matches: |d| d.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]),
+    },
+    Signature {
+        kinds: &[MediaKind::Video, MediaKind::Audio],
+        extensions: &["ogg"],
+        matches: |d| d.starts_with(b"OggS"),
+    },
+    Signature {
+        kinds: &[MediaKind::Audio],
+        extensions: &["mp3"],
+        // Either an ID3 tag or a bare MPEG audio frame
+        matches: |d| d.starts_with(b"ID3") || is_mp3_frame(d),
+    },
+    Signature {
+        kinds: &[MediaKind::Audio],
+        extensions: &["wav"],
+        matches: |d| d.len() >= 12 && &d[0..4] == b"RIFF" && &d[8..12] == b"WAVE",
+    },
+    Signature {
+        kinds: &[MediaKind::Audio],
+        extensions: &["flac"],
+        matches: |d| d.starts_with(b"fLaC"),
+    },
+];
+
+// ISO base media brands (from the ftyp box) of files that are not video, though they share
+// the container: HEIF and AVIF images and M4A audio
+const NON_VIDEO_BRANDS: &[&[u8; 4]] = &[
+    b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1", b"avif", b"avis",
+    b"M4A ", b"M4B ", b"M4P ",
+];
+
+// Brands of MP4 video
+const VIDEO_BRANDS: &[&[u8; 4]] = &[
+    b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"avc1", b"dash", b"M4V ",
+];
+
+// An ftyp box whose major brand is MP4 video, or whose compatible brands include one without
+// the major brand saying it is something else
+fn is_mp4(d: &[u8]) -> bool {
+    if d.len() < 16 || &d[4..8] != b"ftyp" {
+        return false;
+    }
+    let size = u32::from_be_bytes([d[0], d[1], d[2], d[3]]) as usize;
+    let major = &d[8..12];
+    let compatible = d[16..size.clamp(16, d.len())].chunks_exact(4);
+
+    let is = |brands: &[&[u8; 4]], brand: &[u8]| brands.iter().any(|b| b.as_slice() == brand);
+    !is(NON_VIDEO_BRANDS, major)
+        && std::iter::once(major)
+            .chain(compatible)
+            .any(|brand| is(VIDEO_BRANDS, brand))
+}
+
+// An MPEG audio Layer III frame header, with none of its fields holding a reserved value
+fn is_mp3_frame(d: &[u8]) -> bool {
+    if d.len() < 4 || d[0] != 0xFF || d[1] & 0xE0 != 0xE0 {
+        return false;
+    }
+    let version = (d[1] >> 3) & 0b11;
+    let layer = (d[1] >> 1) & 0b11;
+    let bitrate = d[2] >> 4;
+    let sample_rate = (d[2] >> 2) & 0b11;
+    let emphasis = d[3] & 0b11;
+    version != 0b01 && layer == 0b01 && bitrate != 0b1111 && sample_rate != 0b11 && emphasis != 0b10
+}
+
+// Check the first bytes of an upload against what the endpoint expects and the filename's
+// extension. Only the first SNIFF_LEN bytes are inspected.
+pub fn validate(kind: MediaKind, filename: &str, data: &[u8]) -> Result<(), String> {
+    let signature = SIGNATURES
+        .iter()
+        .find(|signature| signature.kinds.contains(&kind) && (signature.matches)(data))
+        .ok_or_else(|| format!("File is not {} we support.", kind.as_str()))?;
+
+    let extension = filename
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase())
+        .unwrap_or_default();
+    if !signature.extensions.contains(&extension.as_str()) {
+        return Err(format!(
+            "File contents do not match its extension (expected .{}).",
+            signature.extensions[0]
+        ));
+    }
+
+    Ok(())
+}
+
+// Images are additionally decoded in full so that only real images end up on disk
+pub fn validate_image(filename: &str, data: &[u8]) -> Result<(), String> {
+    validate(MediaKind::Image, filename, data)?;
+
+    let format = image::guess_format(data).map_err(|_| "File is not a valid image.".to_string())?;
+    image::load_from_memory_with_format(data, format)
+        .map(|_| ())
+        .map_err(|_| "File is not a valid image.".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ftyp(major: &[u8; 4], compatible: &[&[u8; 4]]) -> Vec<u8> {
+        let size = 16 + compatible.len() * 4;
+        let mut data = (size as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(b"ftyp");
+        data.extend_from_slice(major);
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        for brand in compatible {
+            data.extend_from_slice(*brand);
+        }
+        // The start of the next box
+        data.extend_from_slice(b"\0\0\0\x08free");
+        data
+    }
+
+    #[test]
+    fn images_are_recognised() {
+        assert!(validate(MediaKind::Image, "a.jpg", &[0xFF, 0xD8, 0xFF, 0xE0]).is_ok());
+        assert!(validate(MediaKind::Image, "a.PNG", b"\x89PNG\r\n\x1a\n").is_ok());
+        assert!(validate(MediaKind::Image, "a.webp", b"RIFF\0\0\0\0WEBPVP8 ").is_ok());
+    }
+
+    #[test]
+    fn extension_must_match_contents() {
+        assert!(validate(MediaKind::Image, "a.png", &[0xFF, 0xD8, 0xFF, 0xE0]).is_err());
+        assert!(validate(MediaKind::Image, "a", &[0xFF, 0xD8, 0xFF, 0xE0]).is_err());
+    }
+
+    #[test]
+    fn kind_must_match_endpoint() {
+        assert!(validate(MediaKind::Image, "a.flac", b"fLaC").is_err());
+        assert!(validate(MediaKind::Audio, "a.ogg", b"OggS").is_ok());
+        assert!(validate(MediaKind::Video, "a.ogg", b"OggS").is_ok());
+    }
+
+    #[test]
+    fn html_is_refused() {
+        for kind in [MediaKind::Image, MediaKind::Video, MediaKind::Audio] {
+            assert!(validate(kind, "a.mp3", b"<html><script>").is_err());
+        }
+    }
+
+    #[test]
+    fn mp3_frames_are_recognised() {
+        // MPEG-1 Layer III, 128 kbit/s, 44.1 kHz
+        assert!(validate(MediaKind::Audio, "a.mp3", &[0xFF, 0xFB, 0x90, 0x64]).is_ok());
+        assert!(validate(MediaKind::Audio, "a.mp3", b"ID3\x04\0").is_ok());
+    }
+
+    #[test]
+    fn utf16_text_is_not_mp3() {
+        // A byte order mark followed by "<h"
+        let html = [0xFF, 0xFE, b'<', 0, b'h', 0];
+        assert!(validate(MediaKind::Audio, "a.mp3", &html).is_err());
+    }
+
+    #[test]
+    fn mp3_frames_with_reserved_values_are_refused() {
+        // Layer I, reserved version, bad bitrate, reserved sample rate, reserved emphasis
+        for header in [
+            [0xFF, 0xFF, 0x90, 0x64],
+            [0xFF, 0xEB, 0x90, 0x64],
+            [0xFF, 0xFB, 0xF0, 0x64],
+            [0xFF, 0xFB, 0x9C, 0x64],
+            [0xFF, 0xFB, 0x90, 0x66],
+        ] {
+            assert!(validate(MediaKind::Audio, "a.mp3", &header).is_err());
+        }
+    }
+
+    #[test]
+    fn mp4_video_is_recognised() {
+        let mp4 = ftyp(b"isom", &[b"isom", b"iso2", b"avc1", b"mp41"]);
+        assert!(validate(MediaKind::Video, "a.mp4", &mp4).is_ok());
+        let mp4 = ftyp(b"XAVC", &[b"XAVC", b"mp42", b"iso2"]);
+        assert!(validate(MediaKind::Video, "a.mp4", &mp4).is_ok());
+    }
+
+    #[test]
+    fn video_brands_are_found_within_the_sniffed_bytes() {
+        // Sony XAVC lists its MP4 brands after its own
+        let mut mp4 = ftyp(
+            b"XAVC",
+            &[
+                b"XAVC", b"mp42", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41",
+            ],
+        );
+        mp4.resize(4096, 0);
+        mp4.truncate(SNIFF_LEN);
+        assert!(validate(MediaKind::Video, "a.mp4", &mp4).is_ok());
+
+        let mut mov = ftyp(b"3gp4", &[b"3gp4", b"3gp5", b"3g2a", b"isom"]);
+        mov.truncate(SNIFF_LEN);
+        assert!(validate(MediaKind::Video, "a.mp4", &mov).is_ok());
+    }
+
+    #[test]
+    fn other_iso_media_is_not_video() {
+        for data in [
+            ftyp(b"heic", &[b"mif1", b"heic"]),
+            ftyp(b"avif", &[b"avif", b"mif1", b"miaf"]),
+            ftyp(b"mif1", &[b"mif1", b"heic", b"iso8"]),
+            ftyp(b"M4A ", &[b"M4A ", b"mp42", b"isom"]),
+            ftyp(b"crx ", &[b"crx "]),
+        ] {
+            assert!(validate(MediaKind::Video, "a.mp4", &data).is_err());
+        }
+    }
+}
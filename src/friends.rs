@@ -1,14 +1,20 @@
 use actix_web::{web, HttpRequest, HttpResponse};
-use serde::Deserialize;
-use sqlx::sqlite::SqlitePoolOptions;
+use serde::{Deserialize, Serialize};
 use sqlx::{Row, SqlitePool};
-use url::Url;
 
 #[derive(Deserialize)]
 pub struct AddFriendData {
     pub invite_code: String,
 }
 
+// A friend as listed in the friends sidebar
+#[derive(Serialize, sqlx::FromRow)]
+pub struct FriendSummary {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub avatar: Option<String>,
+}
+
 pub async fn add_friend(
     data: web::Json<AddFriendData>,
     req: HttpRequest,
@@ -105,11 +111,14 @@ pub async fn get_friends(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpR
         }
     };
 
-    // Retrieve friends from the database
-    match sqlx::query(
-        "SELECT user2 as friend FROM friends WHERE user1 = ? AND user2 != ?
-         UNION
-         SELECT user1 as friend FROM friends WHERE user2 = ? AND user1 != ?",
+    // Retrieve friends from the database, with what their profiles show everyone
+    match sqlx::query_as::<_, FriendSummary>(
+        "SELECT f.friend AS username, p.display_name, p.avatar FROM (
+            SELECT user2 as friend FROM friends WHERE user1 = ? AND user2 != ?
+            UNION
+            SELECT user1 as friend FROM friends WHERE user2 = ? AND user1 != ?
+         ) f LEFT JOIN profiles p ON p.username = f.friend
+         ORDER BY f.friend",
     )
     .bind(&username)
     .bind(&username)
@@ -118,10 +127,18 @@ pub async fn get_friends(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpR
     .fetch_all(pool.get_ref())
     .await
     {
-        Ok(rows) => {
-            let friends: Vec<String> = rows.into_iter().map(|row| row.get("friend")).collect();
-            HttpResponse::Ok().json(friends)
-        }
+        Ok(friends) => HttpResponse::Ok().json(friends),
         Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
     }
 }
+
+// Friendships are stored in both directions, so one lookup is enough
+pub async fn are_friends(pool: &SqlitePool, user: &str, other: &str) -> bool {
+    sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM friends WHERE user1 = ? AND user2 = ?")
+        .bind(user)
+        .bind(other)
+        .fetch_one(pool)
+        .await
+        .unwrap_or(0)
+        > 0
+}
@@ -0,0 +1,279 @@
+// HLS streaming of films. When HLS_TRANSCODE is set, uploaded films are transcoded in the
+// background with ffmpeg into a ladder of renditions under ./user_pages/{user}/hls/{film},
+// and /hls/... serves the playlists and segments to whoever may watch the film. Until the
+// ladder is ready, pages play the original file. Transcodes run one at a time, or
+// HLS_TRANSCODE_JOBS at a time when that is set, and further films wait their turn.
+//
+// Browsers without native HLS play the ladder with hls.js, served from
+// ./static/vendor/hls.min.js (see the README there). Without it they play the original file.
+use crate::customize;
+use crate::friends;
+use crate::user;
+use crate::video::{self, ToolError};
+use actix_files::NamedFile;
+use actix_web::{web, HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use sqlx::SqlitePool;
+use std::env;
+use std::fs;
+use std::path::Path;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+struct Rendition {
+    height: u32,
+    // Bitrates in kbit/s
+    video_bitrate: u32,
+    audio_bitrate: u32,
+}
+
+// Renditions larger than the source video are skipped
+const LADDER: [Rendition; 3] = [
+    Rendition {
+        height: 360,
+        video_bitrate: 800,
+        audio_bitrate: 96,
+    },
+    Rendition {
+        height: 720,
+        video_bitrate: 2800,
+        audio_bitrate: 128,
+    },
+    Rendition {
+        height: 1080,
+        video_bitrate: 5000,
+        audio_bitrate: 160,
+    },
+];
+
+// Length of each segment in seconds
+const SEGMENT_SECONDS: u32 = 6;
+
+lazy_static! {
+    static ref TRANSCODE_SLOTS: Semaphore = Semaphore::new(
+        env::var("HLS_TRANSCODE_JOBS")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .filter(|jobs| *jobs > 0)
+            .unwrap_or(1)
+    );
+}
+
+pub fn is_enabled() -> bool {
+    env::var("HLS_TRANSCODE")
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+// Wait until fewer than HLS_TRANSCODE_JOBS transcodes are running. The slot is held until the
+// permit is dropped.
+pub async fn transcode_slot() -> SemaphorePermit<'static> {
+    TRANSCODE_SLOTS
+        .acquire()
+        .await
+        .expect("the transcode semaphore is never closed")
+}
+
+fn folder(username: &str, timestamp: &str) -> String {
+    format!("./user_pages/{}/hls/{}", username, timestamp)
+}
+
+pub fn remove(username: &str, timestamp: &str) {
+    let _ = fs::remove_dir_all(folder(username, timestamp));
+}
+
+// The renditions made for a source of the given width and height
+fn renditions(source_size: Option<(u32, u32)>) -> Vec<&'static Rendition> {
+    match source_size {
+        Some((_, source_height)) => {
+            let fitting: Vec<&Rendition> = LADDER
+                .iter()
+                .filter(|rendition| rendition.height <= source_height)
+                .collect();
+            if fitting.is_empty() {
+                vec![&LADDER[0]]
+            } else {
+                fitting
+            }
+        }
+        None => LADDER[..2].iter().collect(),
+    }
+}
+
+// Roughly how many bytes the ladder of a film will take, at the peak bitrate ffmpeg is
+// allowed, so it can be checked against the owner's quota before transcoding
+pub fn estimated_size(source_size: Option<(u32, u32)>, duration: f64) -> u64 {
+    let kbits_per_second: u64 = renditions(source_size)
+        .iter()
+        .map(|rendition| (rendition.video_bitrate * 107 / 100 + rendition.audio_bitrate) as u64)
+        .sum();
+    (kbits_per_second as f64 * 1000.0 / 8.0 * duration.max(0.0)) as u64
+}
+
+// Transcode `video_file` into the film's HLS folder and return the master playlist's URL
+// path. Everything is written to a temporary folder first, so a half-finished ladder is
+// never served.
+pub fn transcode(
+    video_file: &Path,
+    username: &str,
+    timestamp: &str,
+    source_size: Option<(u32, u32)>,
+) -> Result<String, ToolError> {
+    let renditions = renditions(source_size);
+
+    let output = folder(username, timestamp);
+    let partial = format!("{}.partial", output);
+    let _ = fs::remove_dir_all(&partial);
+
+    let mut master = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+    for rendition in renditions {
+        // Never scale small videos up
+        let height = source_size
+            .map(|(_, source_height)| rendition.height.min(source_height))
+            .unwrap_or(rendition.height);
+        let rendition_folder = format!("{}/{}p", partial, height);
+        fs::create_dir_all(&rendition_folder)
+            .map_err(|e| ToolError::Failed(format!("Failed to create {}: {}", partial, e)))?;
+
+        let scale = format!("scale=-2:{}", height);
+        let video_bitrate = format!("{}k", rendition.video_bitrate);
+        let max_rate = format!("{}k", rendition.video_bitrate * 107 / 100);
+        let buffer_size = format!("{}k", rendition.video_bitrate * 3 / 2);
+        let audio_bitrate = format!("{}k", rendition.audio_bitrate);
+        let segment_seconds = SEGMENT_SECONDS.to_string();
+        let segments = format!("{}/segment_%03d.ts", rendition_folder);
+        let playlist = format!("{}/index.m3u8", rendition_folder);
+        let result = video::ffmpeg(&[
+            "-v".as_ref(),
+            "error".as_ref(),
+            "-y".as_ref(),
+            "-i".as_ref(),
+            video_file.as_os_str(),
+            "-map".as_ref(),
+            "0:v:0".as_ref(),
+            "-map".as_ref(),
+            "0:a:0?".as_ref(),
+            "-vf".as_ref(),
+            scale.as_ref(),
+            "-c:v".as_ref(),
+            "libx264".as_ref(),
+            "-preset".as_ref(),
+            "veryfast".as_ref(),
+            "-b:v".as_ref(),
+            video_bitrate.as_ref(),
+            "-maxrate".as_ref(),
+            max_rate.as_ref(),
+            "-bufsize".as_ref(),
+            buffer_size.as_ref(),
+            "-c:a".as_ref(),
+            "aac".as_ref(),
+            "-b:a".as_ref(),
+            audio_bitrate.as_ref(),
+            "-ac".as_ref(),
+            "2".as_ref(),
+            "-f".as_ref(),
+            "hls".as_ref(),
+            "-hls_time".as_ref(),
+            segment_seconds.as_ref(),
+            "-hls_playlist_type".as_ref(),
+            "vod".as_ref(),
+            "-hls_segment_filename".as_ref(),
+            segments.as_ref(),
+            playlist.as_ref(),
+        ]);
+        if let Err(e) = result {
+            let _ = fs::remove_dir_all(&partial);
+            return Err(e);
+        }
+
+        let bandwidth = (rendition.video_bitrate + rendition.audio_bitrate) * 1100;
+        master += &format!("#EXT-X-STREAM-INF:BANDWIDTH={}", bandwidth);
+        if let Some((source_width, source_height)) = source_size {
+            // Same rounding to an even width as scale=-2
+            let width = (source_width * height / source_height.max(1)).div_ceil(2) * 2;
+            master += &format!(",RESOLUTION={}x{}", width, height);
+        }
+        master += &format!("\n{}p/index.m3u8\n", height);
+    }
+
+    let moved = fs::write(format!("{}/master.m3u8", partial), master).and_then(|_| {
+        let _ = fs::remove_dir_all(&output);
+        fs::rename(&partial, &output)
+    });
+    if let Err(e) = moved {
+        let _ = fs::remove_dir_all(&partial);
+        return Err(ToolError::Failed(format!(
+            "Failed to save {}: {}",
+            output, e
+        )));
+    }
+
+    Ok(format!("/hls/{}/{}/master.m3u8", username, timestamp))
+}
+
+// Only names ffmpeg writes: master.m3u8, {height}p/index.m3u8 and {height}p/segment_NNN.ts
+fn is_hls_file(file: &str) -> bool {
+    if file == "master.m3u8" {
+        return true;
+    }
+    match file.split_once('/') {
+        Some((rendition, name)) => {
+            rendition
+                .strip_suffix('p')
+                .map(|height| !height.is_empty() && height.chars().all(|c| c.is_ascii_digit()))
+                .unwrap_or(false)
+                && (name == "index.m3u8"
+                    || name
+                        .strip_prefix("segment_")
+                        .and_then(|name| name.strip_suffix(".ts"))
+                        .map(|number| {
+                            !number.is_empty() && number.chars().all(|c| c.is_ascii_digit())
+                        })
+                        .unwrap_or(false))
+        }
+        None => false,
+    }
+}
+
+// Serve a film's playlists and segments to its owner, their friends, or anyone if the film
+// is public
+pub async fn serve_hls(
+    path: web::Path<(String, String, String)>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> actix_web::Result<HttpResponse> {
+    let (username, timestamp, file) = path.into_inner();
+    if !user::is_valid_username(&username)
+        || !timestamp.chars().all(|c| c.is_ascii_digit())
+        || !is_hls_file(&file)
+    {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let film = match customize::find_film(&username, &timestamp) {
+        Some(film) if film.hls.is_some() => film,
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    if !film.public {
+        let viewer = match req.cookie("username") {
+            Some(cookie) => cookie.value().to_string(),
+            None => return Ok(HttpResponse::Unauthorized().finish()),
+        };
+        if viewer != username && !friends::are_friends(pool.get_ref(), &viewer, &username).await {
+            return Ok(HttpResponse::Forbidden().finish());
+        }
+    }
+
+    let file_path = format!("{}/{}", folder(&username, &timestamp), file);
+    if !Path::new(&file_path).exists() {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    let content_type = if file.ends_with(".m3u8") {
+        "application/vnd.apple.mpegurl"
+    } else {
+        "video/mp2t"
+    };
+    Ok(NamedFile::open(file_path)?
+        .set_content_type(content_type.parse().unwrap())
+        .into_response(&req))
+}
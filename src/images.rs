@@ -0,0 +1,462 @@
+use crate::cache::CacheFolder;
+use actix_files::NamedFile;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use img_parts::jpeg::Jpeg;
+use img_parts::png::Png;
+use img_parts::webp::WebP;
+use img_parts::{Bytes, ImageEXIF, ImageICC};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+// Largest width or height the resize endpoint will produce
+const MAX_DIMENSION: u32 = 2048;
+
+// Requested sizes are rounded up to one of these, so each image has a bounded number of
+// resized versions however many sizes are asked for
+const SIZE_BUCKETS: [u32; 16] = [
+    32,
+    48,
+    64,
+    96,
+    128,
+    160,
+    200,
+    256,
+    320,
+    480,
+    640,
+    800,
+    1024,
+    1280,
+    1600,
+    MAX_DIMENSION,
+];
+
+// Resized images are cached here, outside of ./user_pages so they are never served directly.
+// RESIZE_CACHE_MB limits its size.
+static RESIZE_CACHE: CacheFolder = CacheFolder::new("./cache/resized", "RESIZE_CACHE_MB", 1024);
+
+#[derive(Deserialize)]
+pub struct ResizeQuery {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    pub fit: Option<String>,
+}
+
+impl ResizeQuery {
+    pub fn is_requested(&self) -> bool {
+        self.w.is_some() || self.h.is_some() || self.fit.is_some()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Fit {
+    // Scale down to fit inside the box, keeping the aspect ratio
+    Contain,
+    // Scale and crop so the box is completely covered
+    Cover,
+    // Stretch to exactly the requested size
+    Fill,
+}
+
+impl Fit {
+    fn parse(value: Option<&str>) -> Option<Fit> {
+        match value.unwrap_or("contain") {
+            "contain" => Some(Fit::Contain),
+            "cover" => Some(Fit::Cover),
+            "fill" => Some(Fit::Fill),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+}
+
+// Serve an image from a user's folder, resized according to the ?w=, ?h= and ?fit= query.
+// Callers must have already performed the access checks for `file_path`.
+pub async fn serve_resized(
+    req: &HttpRequest,
+    file_path: String,
+    query: ResizeQuery,
+) -> actix_web::Result<HttpResponse> {
+    let fit = match Fit::parse(query.fit.as_deref()) {
+        Some(fit) => fit,
+        None => {
+            return Ok(HttpResponse::BadRequest().body("fit must be one of contain, cover or fill"))
+        }
+    };
+
+    for dimension in [query.w, query.h].into_iter().flatten() {
+        if dimension == 0 || dimension > MAX_DIMENSION {
+            return Ok(HttpResponse::BadRequest().body(format!(
+                "Width and height must be between 1 and {}.",
+                MAX_DIMENSION
+            )));
+        }
+    }
+    let (width, height) = (query.w.map(snap_to_bucket), query.h.map(snap_to_bucket));
+
+    // Formats we cannot decode (e.g. svg) are served untouched
+    let source_format = match ImageFormat::from_path(&file_path) {
+        Ok(format) if format.reading_enabled() && format.writing_enabled() => format,
+        _ => return Ok(NamedFile::open(file_path)?.into_response(req)),
+    };
+
+    // Prefer WebP when the client advertises support for it
+    let accepts_webp = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.contains("image/webp"))
+        .unwrap_or(false);
+    let output_format = if accepts_webp {
+        ImageFormat::WebP
+    } else {
+        source_format
+    };
+
+    // Include the modification time so that replacing the original invalidates the cache
+    let modified = fs::metadata(&file_path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let mut hasher = Sha256::new();
+    hasher.update(format!(
+        "{}|{}|{:?}|{:?}|{}",
+        file_path,
+        modified,
+        width,
+        height,
+        fit.as_str()
+    ));
+    let cache_path = format!(
+        "{}/{:x}.{}",
+        RESIZE_CACHE.path,
+        hasher.finalize(),
+        output_format.extensions_str()[0]
+    );
+
+    if Path::new(&cache_path).exists() {
+        RESIZE_CACHE.touch(Path::new(&cache_path));
+    } else {
+        let cache_path_inner = cache_path.clone();
+        let result = web::block(move || -> Result<(), String> {
+            let source = image::open(&file_path).map_err(|e| e.to_string())?;
+            let resized = resize(&source, width, height, fit);
+
+            // JPEG cannot carry an alpha channel
+            let resized = if output_format == ImageFormat::Jpeg {
+                DynamicImage::ImageRgb8(resized.to_rgb8())
+            } else {
+                resized
+            };
+
+            let mut encoded = Cursor::new(Vec::new());
+            resized
+                .write_to(&mut encoded, output_format)
+                .map_err(|e| e.to_string())?;
+
+            // Write to a temporary file first so concurrent requests never see a partial image
+            fs::create_dir_all(RESIZE_CACHE.path).map_err(|e| e.to_string())?;
+            let tmp_path = format!("{}.{}.tmp", cache_path_inner, uuid::Uuid::new_v4());
+            fs::write(&tmp_path, encoded.into_inner()).map_err(|e| e.to_string())?;
+            fs::rename(&tmp_path, &cache_path_inner).map_err(|e| e.to_string())?;
+            RESIZE_CACHE.added();
+            Ok(())
+        })
+        .await;
+
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                eprintln!("Failed to resize image: {}", e);
+                return Ok(HttpResponse::UnprocessableEntity().body("Unable to resize image."));
+            }
+            Err(e) => {
+                eprintln!("Failed to resize image: {}", e);
+                return Ok(HttpResponse::InternalServerError().body("Error resizing image."));
+            }
+        }
+    }
+
+    let mut response = NamedFile::open(cache_path)?.into_response(req);
+    response
+        .headers_mut()
+        .insert(header::VARY, header::HeaderValue::from_static("Accept"));
+    Ok(response)
+}
+
+// The smallest bucket at least as large as `size`, which is at most MAX_DIMENSION
+fn snap_to_bucket(size: u32) -> u32 {
+    SIZE_BUCKETS
+        .into_iter()
+        .find(|bucket| *bucket >= size)
+        .unwrap_or(MAX_DIMENSION)
+}
+
+fn resize(
+    source: &DynamicImage,
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+) -> DynamicImage {
+    match (width, height) {
+        (Some(w), Some(h)) => match fit {
+            Fit::Contain => source.resize(w, h, FilterType::Lanczos3),
+            Fit::Cover => source.resize_to_fill(w, h, FilterType::Lanczos3),
+            Fit::Fill => source.resize_exact(w, h, FilterType::Lanczos3),
+        },
+        // With a single dimension the other one follows the aspect ratio
+        (Some(w), None) => source.resize(w, u32::MAX, FilterType::Lanczos3),
+        (None, Some(h)) => source.resize(u32::MAX, h, FilterType::Lanczos3),
+        (None, None) => source.clone(),
+    }
+}
+
+// Scale and crop an image to a centred square of at most `size` pixels, as used for
+// avatars. Images with transparency stay PNG, the rest become JPEG. Returns the encoded
+// square and its file extension.
+pub fn crop_square(data: &[u8], size: u32) -> Result<(Vec<u8>, &'static str), String> {
+    let source = image::load_from_memory(data).map_err(|e| e.to_string())?;
+    let side = size.min(source.width()).min(source.height());
+    let square = source.resize_to_fill(side, side, FilterType::Lanczos3);
+
+    let mut encoded = Cursor::new(Vec::new());
+    if square.color().has_alpha() {
+        square
+            .write_to(&mut encoded, ImageFormat::Png)
+            .map_err(|e| e.to_string())?;
+        Ok((encoded.into_inner(), "png"))
+    } else {
+        JpegEncoder::new_with_quality(&mut encoded, 90)
+            .encode_image(&DynamicImage::ImageRgb8(square.to_rgb8()))
+            .map_err(|e| e.to_string())?;
+        Ok((encoded.into_inner(), "jpg"))
+    }
+}
+
+// Camera details read from a photo before its metadata is removed
+#[derive(Default)]
+pub struct CameraDetails {
+    pub capture_time: Option<String>,
+    pub camera_model: Option<String>,
+}
+
+// Remove EXIF, XMP and other embedded metadata (GPS position, device serials, ...) from an
+// uploaded photo. Returns the cleaned image along with the camera details that were found.
+pub fn strip_metadata(data: Bytes) -> Result<(Vec<u8>, CameraDetails), String> {
+    let format = image::guess_format(&data).map_err(|_| "Unsupported image format.".to_string())?;
+
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(&data[..]))
+        .ok();
+    let details = exif.as_ref().map(camera_details).unwrap_or_default();
+    let orientation = exif
+        .as_ref()
+        .and_then(|exif| exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY))
+        .and_then(|field| field.value.get_uint(0))
+        .unwrap_or(1);
+
+    let cleaned = match format {
+        // Segments between progressive scans, secondary images in an MPF (APP2) index and
+        // anything appended after the end of the image (motion photo videos, ...) can all
+        // carry EXIF and GPS, so JPEGs are always decoded and encoded again. Only the colour
+        // profile is carried over.
+        ImageFormat::Jpeg => {
+            let icc_profile = Jpeg::from_bytes(data.clone())
+                .ok()
+                .and_then(|jpeg| jpeg.icc_profile());
+            let encoded = reencode(&data, format, orientation)?;
+            let mut jpeg = Jpeg::from_bytes(encoded.into()).map_err(|e| e.to_string())?;
+            jpeg.set_icc_profile(icc_profile);
+            jpeg.encoder().bytes().to_vec()
+        }
+        // Rotated photos rely on the EXIF orientation tag, so apply it to the pixels instead
+        _ if orientation != 1 => reencode(&data, format, orientation)?,
+        ImageFormat::Png => {
+            let mut png = Png::from_bytes(data).map_err(|e| e.to_string())?;
+            png.set_exif(None);
+            for kind in [*b"tEXt", *b"zTXt", *b"iTXt", *b"tIME"] {
+                png.remove_chunks_by_type(kind);
+            }
+            png.encoder().bytes().to_vec()
+        }
+        ImageFormat::WebP => {
+            let mut webp = WebP::from_bytes(data).map_err(|e| e.to_string())?;
+            webp.remove_chunks_by_id(*b"XMP ");
+            webp.set_exif(None);
+            webp.encoder().bytes().to_vec()
+        }
+        // Any other format is decoded and written out again, which drops all metadata
+        _ => reencode(&data, format, 1)?,
+    };
+
+    Ok((cleaned, details))
+}
+
+fn camera_details(exif: &exif::Exif) -> CameraDetails {
+    let ascii = |tag| {
+        exif.get_field(tag, exif::In::PRIMARY)
+            .and_then(|field| match field.value {
+                exif::Value::Ascii(ref values) => values.first().cloned(),
+                _ => None,
+            })
+            .map(|value| String::from_utf8_lossy(&value).trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    let camera_model = match (ascii(exif::Tag::Make), ascii(exif::Tag::Model)) {
+        // Most cameras already repeat the make in the model name
+        (Some(make), Some(model)) if !model.starts_with(&make) => {
+            Some(format!("{} {}", make, model))
+        }
+        (_, Some(model)) => Some(model),
+        (make, None) => make,
+    };
+
+    CameraDetails {
+        capture_time: ascii(exif::Tag::DateTimeOriginal),
+        camera_model,
+    }
+}
+
+fn reencode(data: &[u8], format: ImageFormat, orientation: u32) -> Result<Vec<u8>, String> {
+    let source = image::load_from_memory_with_format(data, format).map_err(|e| e.to_string())?;
+
+    let oriented = match orientation {
+        2 => source.fliph(),
+        3 => source.rotate180(),
+        4 => source.flipv(),
+        5 => source.rotate90().fliph(),
+        6 => source.rotate90(),
+        7 => source.rotate270().fliph(),
+        8 => source.rotate270(),
+        _ => source,
+    };
+
+    let mut encoded = Cursor::new(Vec::new());
+    if format == ImageFormat::Jpeg {
+        JpegEncoder::new_with_quality(&mut encoded, 90)
+            .encode_image(&DynamicImage::ImageRgb8(oriented.to_rgb8()))
+            .map_err(|e| e.to_string())?;
+    } else if format.writing_enabled() {
+        oriented
+            .write_to(&mut encoded, format)
+            .map_err(|e| e.to_string())?;
+    } else {
+        return Err("Unsupported image format.".to_string());
+    }
+
+    Ok(encoded.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_snap_up_to_a_bucket() {
+        assert_eq!(snap_to_bucket(1), 32);
+        assert_eq!(snap_to_bucket(48), 48);
+        assert_eq!(snap_to_bucket(49), 64);
+        assert_eq!(snap_to_bucket(1999), 2048);
+        assert_eq!(snap_to_bucket(MAX_DIMENSION), MAX_DIMENSION);
+    }
+
+    const SECRET: &[u8] = b"GPS 51.5007N 0.1246W";
+
+    fn segment(marker: u8, contents: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(contents.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(contents);
+        segment
+    }
+
+    fn exif_segment() -> Vec<u8> {
+        segment(0xE1, &[b"Exif\0\0".as_slice(), SECRET].concat())
+    }
+
+    // An 8x8 grey progressive JPEG with a DC scan and an AC scan. An APP1 segment sits
+    // between the scans and another EXIF payload follows the end of the image.
+    fn progressive_jpeg_with_trailing_exif() -> Vec<u8> {
+        // Huffman tables holding a single one-bit code for symbol 0: a DC difference of 0,
+        // or an end of band
+        let huffman_table = |class: u8| {
+            let mut table = vec![class, 1];
+            table.extend_from_slice(&[0; 15]);
+            table.push(0);
+            table
+        };
+
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend(segment(0xDB, &[[0].as_slice(), &[1; 64]].concat()));
+        jpeg.extend(segment(0xC2, &[8, 0, 8, 0, 8, 1, 1, 0x11, 0]));
+        jpeg.extend(segment(0xC4, &huffman_table(0x00)));
+        jpeg.extend(segment(0xC4, &huffman_table(0x10)));
+        // DC scan, a single 0 bit padded with 1s
+        jpeg.extend(segment(0xDA, &[1, 1, 0x00, 0, 0, 0]));
+        jpeg.push(0x7F);
+        jpeg.extend(exif_segment());
+        // AC scan, a single end of band
+        jpeg.extend(segment(0xDA, &[1, 1, 0x00, 1, 63, 0]));
+        jpeg.push(0x7F);
+        jpeg.extend_from_slice(&[0xFF, 0xD9]);
+        jpeg.extend(exif_segment());
+        jpeg
+    }
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn metadata_after_the_first_scan_is_removed() {
+        let jpeg = progressive_jpeg_with_trailing_exif();
+        assert!(image::load_from_memory(&jpeg).is_ok());
+
+        let (cleaned, _) = strip_metadata(jpeg.into()).unwrap();
+        assert!(!contains(&cleaned, SECRET));
+        assert!(!contains(&cleaned, b"Exif"));
+        let cleaned = image::load_from_memory(&cleaned).unwrap();
+        assert_eq!((cleaned.width(), cleaned.height()), (8, 8));
+    }
+
+    #[test]
+    fn png_text_and_exif_chunks_are_removed() {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(4, 4)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        let mut png = Png::from_bytes(png.into_inner().into()).unwrap();
+        png.set_exif(Some(Bytes::from([b"MM\0*".as_slice(), SECRET].concat())));
+        let text = img_parts::png::PngChunk::new(*b"tEXt", Bytes::from_static(SECRET));
+        png.chunks_mut().insert(1, text);
+        let png = png.encoder().bytes().to_vec();
+        assert!(contains(&png, SECRET));
+
+        let (cleaned, _) = strip_metadata(png.into()).unwrap();
+        assert!(!contains(&cleaned, SECRET));
+        assert!(image::load_from_memory(&cleaned).is_ok());
+    }
+
+    #[test]
+    fn unsupported_data_is_refused() {
+        assert!(strip_metadata(Bytes::from_static(b"<svg></svg>")).is_err());
+    }
+}
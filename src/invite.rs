@@ -1,21 +1,13 @@
+use crate::page;
 use actix_files::NamedFile;
 use actix_web::web;
 use actix_web::HttpRequest;
 use actix_web::HttpResponse;
-use actix_web::Responder;
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
-use serde::Serialize;
 use serde_json::json;
 use sqlx::Row;
 use sqlx::SqlitePool;
 use uuid::Uuid;
 
-#[derive(Serialize)]
-pub struct InviteLinkResponse {
-    pub invite_link: String,
-}
-
 pub async fn generate_invite(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
     let username = match req.cookie("username") {
         Some(cookie) => cookie.value().to_string(),
@@ -40,8 +32,9 @@ pub async fn generate_invite(req: HttpRequest, pool: web::Data<SqlitePool>) -> H
 }
 pub async fn handle_invite(
     token: web::Path<String>,
+    req: HttpRequest,
     db_pool: web::Data<sqlx::SqlitePool>,
-) -> actix_web::Result<NamedFile> {
+) -> actix_web::Result<HttpResponse> {
     let token_str = token.into_inner();
 
     // Fetch the username associated with the token
@@ -61,16 +54,15 @@ pub async fn handle_invite(
                 .await;
 
             // Serve the user's page
-            let user_page_path = format!("./user_pages/{}/my_page.html", username);
-            Ok(NamedFile::open(user_page_path)?)
+            page::serve(&req, db_pool.get_ref(), &username, "my_page.html").await
         }
         Err(sqlx::Error::RowNotFound) => {
             // Token not found or already used
-            Ok(NamedFile::open("./static/404.html")?)
+            Ok(NamedFile::open("./static/404.html")?.into_response(&req))
         }
         Err(err) => {
             eprintln!("Database query error: {}", err);
-            Ok(NamedFile::open("./static/500.html")?)
+            Ok(NamedFile::open("./static/500.html")?.into_response(&req))
         }
     }
 }
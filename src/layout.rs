@@ -0,0 +1,550 @@
+// The structured layout of a user's page: an ordered list of sections, each of which can be
+// hidden. Layouts are edited as JSON through /page_layout, validated block by block, and
+// rendered into the page template by page.rs. Extra pages from exhibits.rs keep their own
+// layout, chosen with ?page={slug}.
+use crate::customize::{self, ContentItem};
+use crate::exhibits;
+use crate::page::escape_html;
+use crate::security;
+use crate::snapshots;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Sqlite, SqlitePool};
+use std::collections::HashSet;
+
+const MAX_BIO_LENGTH: usize = 1000;
+const MAX_LINKS: usize = 10;
+const MAX_LINK_LABEL_LENGTH: usize = 50;
+const MAX_LINK_URL_LENGTH: usize = 500;
+const MAX_PINNED_ITEMS: usize = 6;
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct PageLayout {
+    pub sections: Vec<Section>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct Section {
+    #[serde(default = "visible_by_default")]
+    pub visible: bool,
+    #[serde(flatten)]
+    pub block: Block,
+}
+
+fn visible_by_default() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Block {
+    // The main title and greeting
+    Intro,
+    Bio { text: String },
+    // One of the user's gallery images
+    Avatar { image: String },
+    Links { links: Vec<PageLink> },
+    // Items shown above the feed, as "{Type}/{timestamp}"
+    Pinned { items: Vec<String> },
+    // The feed of all the user's content
+    Content,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct PageLink {
+    pub label: String,
+    pub url: String,
+}
+
+impl Block {
+    fn name(&self) -> &'static str {
+        match self {
+            Block::Intro => "intro",
+            Block::Bio { .. } => "bio",
+            Block::Avatar { .. } => "avatar",
+            Block::Links { .. } => "links",
+            Block::Pinned { .. } => "pinned",
+            Block::Content => "content",
+        }
+    }
+
+    fn validate(&self, content: &[ContentItem], linked: Option<&[String]>) -> Result<(), String> {
+        match self {
+            Block::Intro | Block::Content => Ok(()),
+            Block::Bio { text } => {
+                if text.trim().is_empty() {
+                    return Err("The bio is empty.".to_string());
+                }
+                if text.chars().count() > MAX_BIO_LENGTH {
+                    return Err(format!(
+                        "The bio must be {} characters or fewer.",
+                        MAX_BIO_LENGTH
+                    ));
+                }
+                Ok(())
+            }
+            Block::Avatar { image } => {
+                let is_own_image = content.iter().any(|item| match item {
+                    ContentItem::Gallery(gallery) => gallery.images.contains(image),
+                    _ => false,
+                });
+                if !is_own_image {
+                    return Err("The avatar must be one of your gallery images.".to_string());
+                }
+                Ok(())
+            }
+            Block::Links { links } => {
+                if links.is_empty() || links.len() > MAX_LINKS {
+                    return Err(format!("Add between 1 and {} links.", MAX_LINKS));
+                }
+                for link in links {
+                    let label = link.label.trim();
+                    if label.is_empty() || label.chars().count() > MAX_LINK_LABEL_LENGTH {
+                        return Err(format!(
+                            "Link labels must be 1 to {} characters.",
+                            MAX_LINK_LABEL_LENGTH
+                        ));
+                    }
+                    if !is_valid_link(&link.url) {
+                        return Err(format!(
+                            "\"{}\" is not an http, https or mailto link.",
+                            link.url
+                        ));
+                    }
+                }
+                Ok(())
+            }
+            Block::Pinned { items } => {
+                if items.is_empty() || items.len() > MAX_PINNED_ITEMS {
+                    return Err(format!("Pin between 1 and {} items.", MAX_PINNED_ITEMS));
+                }
+                let mut seen = HashSet::new();
+                for id in items {
+                    if !seen.insert(id) {
+                        return Err(format!("{} is pinned twice.", id));
+                    }
+                    if !content.iter().any(|item| item.id() == *id) {
+                        return Err(format!("{} is not on your page.", id));
+                    }
+                    if linked.is_some_and(|linked| !linked.contains(id)) {
+                        return Err(format!("{} is not linked to this page.", id));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn is_valid_link(link: &str) -> bool {
+    link.len() <= MAX_LINK_URL_LENGTH
+        && url::Url::parse(link)
+            .map(|url| matches!(url.scheme(), "http" | "https" | "mailto"))
+            .unwrap_or(false)
+}
+
+impl PageLayout {
+    // What pages looked like before layouts could be edited
+    pub fn default_layout() -> PageLayout {
+        PageLayout {
+            sections: vec![
+                Section {
+                    visible: true,
+                    block: Block::Intro,
+                },
+                Section {
+                    visible: true,
+                    block: Block::Content,
+                },
+            ],
+        }
+    }
+
+    // `content` is everything on the user's page. Extra pages can only pin the items they
+    // link to, given in `linked`.
+    fn validate(&self, content: &[ContentItem], linked: Option<&[String]>) -> Result<(), String> {
+        let mut seen = HashSet::new();
+        for section in &self.sections {
+            if !seen.insert(section.block.name()) {
+                return Err(format!(
+                    "There can only be one {} section.",
+                    section.block.name()
+                ));
+            }
+        }
+        // Page scripts fill the feed in, so it has to be there even when it is hidden
+        if !seen.contains("content") {
+            return Err("The layout needs a content section.".to_string());
+        }
+
+        for section in &self.sections {
+            section
+                .block
+                .validate(content, linked)
+                .map_err(|e| format!("{} section: {}", section.block.name(), e))?;
+        }
+        Ok(())
+    }
+
+    // A layout saved earlier, such as in a snapshot, made to fit the user's page as it is now:
+    // pins of deleted items are dropped, and so are the avatar if its image was deleted and
+    // pinned sections left empty. What remains must still be a valid layout.
+    pub fn fit_to_content(mut self, content: &[ContentItem]) -> Result<PageLayout, String> {
+        let ids: HashSet<String> = content.iter().map(ContentItem::id).collect();
+        self.sections
+            .retain_mut(|section| match &mut section.block {
+                Block::Pinned { items } => {
+                    items.retain(|id| ids.contains(id));
+                    !items.is_empty()
+                }
+                Block::Avatar { .. } => section.block.validate(content, None).is_ok(),
+                _ => true,
+            });
+        self.validate(content, None)?;
+        Ok(self)
+    }
+
+    // HTML for the page's <main>, in section order. Hidden sections are left out, except the
+    // content feed, which page scripts still look for. `media_grant` is the viewer's grant
+    // for the media origin, if they have one.
+    pub fn render(&self, username: &str, main_title: &str, media_grant: Option<&str>) -> String {
+        let mut html = String::new();
+        for section in &self.sections {
+            if !section.visible && !matches!(section.block, Block::Content) {
+                continue;
+            }
+            match &section.block {
+                Block::Intro => {
+                    html += &format!(
+                        "<section class=\"page-intro\">\n  <h2 id=\"main-title\">{}</h2>\n  \
+                         <p>Feel free to explore and see what I've been up to.</p>\n</section>\n",
+                        escape_html(main_title)
+                    );
+                }
+                Block::Bio { text } => {
+                    html += &format!(
+                        "<section class=\"page-bio\">\n  <p>{}</p>\n</section>\n",
+                        escape_html(text.trim())
+                    );
+                }
+                Block::Avatar { image } => {
+                    html += &format!(
+                        "<section class=\"page-avatar\">\n  <img src=\"{}\" alt=\"{}'s avatar\">\n</section>\n",
+                        escape_html(&security::media_url(image, media_grant)),
+                        escape_html(username)
+                    );
+                }
+                Block::Links { links } => {
+                    html += "<section class=\"page-links\">\n  <ul>\n";
+                    for link in links {
+                        html += &format!(
+                            "    <li><a href=\"{}\" target=\"_blank\" rel=\"noopener noreferrer nofollow\">{}</a></li>\n",
+                            escape_html(&link.url),
+                            escape_html(link.label.trim())
+                        );
+                    }
+                    html += "  </ul>\n</section>\n";
+                }
+                Block::Pinned { items } => {
+                    html += &format!(
+                        "<section id=\"pinned-content\" class=\"page-pinned\" data-items=\"{}\">\n  \
+                         <h2>Pinned</h2>\n</section>\n",
+                        escape_html(&items.join(" "))
+                    );
+                }
+                Block::Content => {
+                    let hidden = if section.visible { "" } else { " hidden" };
+                    html += &format!("<div id=\"content-feed\"{}></div>\n", hidden);
+                }
+            }
+        }
+        html
+    }
+}
+
+pub async fn load(pool: &SqlitePool, username: &str) -> PageLayout {
+    let stored =
+        sqlx::query_scalar::<_, String>("SELECT layout FROM page_layouts WHERE username = ?")
+            .bind(username)
+            .fetch_optional(pool)
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to load page layout for {}: {}", username, e);
+                None
+            });
+
+    stored
+        .and_then(|layout| serde_json::from_str(&layout).ok())
+        .unwrap_or_else(PageLayout::default_layout)
+}
+
+// Save the layout of the user's main page
+pub async fn save<'e, E: Executor<'e, Database = Sqlite>>(
+    executor: E,
+    username: &str,
+    layout: &PageLayout,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO page_layouts (username, layout) VALUES (?, ?)
+         ON CONFLICT(username) DO UPDATE SET layout = excluded.layout",
+    )
+    .bind(username)
+    .bind(serde_json::to_string(layout).unwrap_or_default())
+    .execute(executor)
+    .await
+    .map(|_| ())
+}
+
+#[derive(Deserialize)]
+pub struct LayoutQuery {
+    // One of the user's extra pages; my_page.html if absent
+    pub page: Option<String>,
+}
+
+pub async fn get_page_layout(
+    query: web::Query<LayoutQuery>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    match &query.page {
+        Some(slug) => match exhibits::find(pool.get_ref(), &username, slug).await {
+            Ok(Some(page)) => HttpResponse::Ok().json(page.layout),
+            Ok(None) => HttpResponse::NotFound().body("Page not found"),
+            Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+        },
+        None => HttpResponse::Ok().json(load(pool.get_ref(), &username).await),
+    }
+}
+
+// Replace the layout of the user's main page or one of their extra pages. Nothing is saved
+// unless every section is valid.
+pub async fn save_page_layout(
+    data: web::Json<PageLayout>,
+    query: web::Query<LayoutQuery>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    let content = customize::load_all_content(&username);
+
+    if let Some(slug) = &query.page {
+        let page = match exhibits::find(pool.get_ref(), &username, slug).await {
+            Ok(Some(page)) => page,
+            Ok(None) => return HttpResponse::NotFound().body("Page not found"),
+            Err(e) => {
+                return HttpResponse::InternalServerError().body(format!("Database error: {}", e))
+            }
+        };
+        if let Err(e) = data.validate(&content, Some(&page.items)) {
+            return HttpResponse::BadRequest().body(e);
+        }
+        return match exhibits::save_layout(pool.get_ref(), &username, slug, &data).await {
+            Ok(_) => HttpResponse::Ok().json(data.0),
+            Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+        };
+    }
+
+    if let Err(e) = data.validate(&content, None) {
+        return HttpResponse::BadRequest().body(e);
+    }
+
+    snapshots::record(pool.get_ref(), &username, "layout").await;
+    match save(pool.get_ref(), &username, &data).await {
+        Ok(()) => HttpResponse::Ok().json(data.0),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::customize::{Gallery, TextPost};
+
+    fn gallery(timestamp: &str, images: &[&str]) -> ContentItem {
+        ContentItem::Gallery(Gallery {
+            title: "Holiday".to_string(),
+            images: images.iter().map(|image| image.to_string()).collect(),
+            timestamp: timestamp.to_string(),
+            photo_details: Vec::new(),
+            public: false,
+        })
+    }
+
+    fn post(timestamp: &str) -> ContentItem {
+        ContentItem::TextPost(TextPost {
+            title: "Hello".to_string(),
+            content: "World".to_string(),
+            timestamp: timestamp.to_string(),
+            public: false,
+        })
+    }
+
+    fn layout(blocks: Vec<Block>) -> PageLayout {
+        PageLayout {
+            sections: blocks
+                .into_iter()
+                .map(|block| Section {
+                    visible: true,
+                    block,
+                })
+                .collect(),
+        }
+    }
+
+    fn pinned(items: &[&str]) -> Block {
+        Block::Pinned {
+            items: items.iter().map(|item| item.to_string()).collect(),
+        }
+    }
+
+    fn link(url: &str) -> Block {
+        Block::Links {
+            links: vec![PageLink {
+                label: "Me".to_string(),
+                url: url.to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn layouts_with_every_block_are_valid() {
+        let content = [
+            gallery("20240101000000", &["/a.png"]),
+            post("20240102000000"),
+        ];
+        let layout = layout(vec![
+            Block::Intro,
+            Block::Bio {
+                text: "Hi".to_string(),
+            },
+            Block::Avatar {
+                image: "/a.png".to_string(),
+            },
+            link("https://example.com"),
+            pinned(&["Gallery/20240101000000", "TextPost/20240102000000"]),
+            Block::Content,
+        ]);
+        assert!(layout.validate(&content, None).is_ok());
+        assert!(PageLayout::default_layout().validate(&[], None).is_ok());
+    }
+
+    #[test]
+    fn sections_are_unique_and_content_is_required() {
+        assert!(layout(vec![Block::Intro, Block::Intro, Block::Content])
+            .validate(&[], None)
+            .is_err());
+        assert!(layout(vec![Block::Intro]).validate(&[], None).is_err());
+    }
+
+    #[test]
+    fn avatars_must_be_own_gallery_images() {
+        let content = [gallery("20240101000000", &["/a.png"])];
+        let avatar = |image: &str| {
+            layout(vec![
+                Block::Avatar {
+                    image: image.to_string(),
+                },
+                Block::Content,
+            ])
+        };
+        assert!(avatar("/a.png").validate(&content, None).is_ok());
+        assert!(avatar("/user_pages/bob/b.png")
+            .validate(&content, None)
+            .is_err());
+    }
+
+    #[test]
+    fn pins_must_be_on_the_page() {
+        let content = [
+            gallery("20240101000000", &["/a.png"]),
+            post("20240102000000"),
+        ];
+        let pins = |items: &[&str]| layout(vec![pinned(items), Block::Content]);
+        assert!(pins(&["Film/20240101000000"])
+            .validate(&content, None)
+            .is_err());
+        assert!(
+            pins(&["TextPost/20240102000000", "TextPost/20240102000000"])
+                .validate(&content, None)
+                .is_err()
+        );
+        assert!(pins(&[]).validate(&content, None).is_err());
+        assert!(pins(&["Gallery/20240101000000"; MAX_PINNED_ITEMS + 1])
+            .validate(&content, None)
+            .is_err());
+
+        // Extra pages can only pin what they link to
+        let linked = ["TextPost/20240102000000".to_string()];
+        assert!(pins(&["TextPost/20240102000000"])
+            .validate(&content, Some(&linked))
+            .is_ok());
+        assert!(pins(&["Gallery/20240101000000"])
+            .validate(&content, Some(&linked))
+            .is_err());
+    }
+
+    #[test]
+    fn links_and_bios_are_checked() {
+        for url in ["mailto:me@example.com", "http://example.com/a?b"] {
+            assert!(layout(vec![link(url), Block::Content])
+                .validate(&[], None)
+                .is_ok());
+        }
+        for url in ["javascript:alert(1)", "data:text/html,x", "/relative", ""] {
+            assert!(layout(vec![link(url), Block::Content])
+                .validate(&[], None)
+                .is_err());
+        }
+
+        let bio = |text: String| layout(vec![Block::Bio { text }, Block::Content]);
+        assert!(bio("x".repeat(MAX_BIO_LENGTH)).validate(&[], None).is_ok());
+        assert!(bio("x".repeat(MAX_BIO_LENGTH + 1))
+            .validate(&[], None)
+            .is_err());
+    }
+
+    #[test]
+    fn restored_layouts_drop_deleted_content() {
+        let content = [
+            gallery("20240101000000", &["/a.png"]),
+            post("20240102000000"),
+        ];
+        let restored = layout(vec![
+            Block::Avatar {
+                image: "/deleted.png".to_string(),
+            },
+            pinned(&["TextPost/20240102000000", "Film/20230101000000"]),
+            Block::Content,
+        ])
+        .fit_to_content(&content)
+        .unwrap();
+        assert!(restored == layout(vec![pinned(&["TextPost/20240102000000"]), Block::Content]));
+
+        let restored = layout(vec![pinned(&["Film/20230101000000"]), Block::Content])
+            .fit_to_content(&content)
+            .unwrap();
+        assert!(restored == layout(vec![Block::Content]));
+    }
+
+    #[test]
+    fn restored_layouts_must_still_be_valid() {
+        let content = [gallery("20240101000000", &["/a.png"])];
+        assert!(layout(vec![Block::Intro, Block::Intro, Block::Content])
+            .fit_to_content(&content)
+            .is_err());
+        assert!(layout(vec![Block::Bio {
+            text: " ".to_string()
+        }])
+        .fit_to_content(&content)
+        .is_err());
+    }
+}
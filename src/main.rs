@@ -1,14 +1,38 @@
 use actix_files::NamedFile;
+use actix_web::dev::Service;
+use actix_web::http::Method;
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Result};
 use sqlx::sqlite::SqlitePoolOptions;
 use sqlx::SqlitePool;
 use std::path::Path;
+use storage::Storage;
+mod audio;
+mod blobs;
+mod cache;
+mod captions;
 mod customize;
+mod exhibits;
+mod feeds;
+mod filetype;
 mod friends;
+mod hls;
+mod images;
 mod invite;
+mod layout;
 mod login;
+mod page;
+mod profile;
+mod quota;
 mod register;
+mod security;
+mod settings;
+mod snapshots;
+mod storage;
+mod styles;
+mod tus;
+mod upload;
 mod user;
+mod video;
 
 // Serve the index.html file
 async fn index() -> actix_web::Result<NamedFile> {
@@ -20,6 +44,7 @@ async fn user_page(
     path: web::Path<(String, String)>,
     req: HttpRequest,
     pool: web::Data<SqlitePool>,
+    storage: web::Data<dyn Storage>,
 ) -> Result<HttpResponse> {
     let (username, filename) = path.into_inner();
 
@@ -35,14 +60,38 @@ async fn user_page(
 
     let allowed_extensions = [
         "html", "css", "js", "jpg", "jpeg", "png", "gif", "svg", "webp", "bmp", "mp4", "webm",
-        "ogg", "mp3", "wav", "ogg", "flac",
+        "ogg", "mp3", "wav", "ogg", "flac", "vtt",
     ];
 
-    let file_extension = filename.split('.').last().unwrap_or("");
-    if !allowed_extensions.contains(&file_extension) {
+    // Extra pages are addressed by their slug, which has no extension
+    let is_extra_page = exhibits::is_slug(&filename);
+
+    let file_extension = filename.rsplit('.').next().unwrap_or("");
+    if !allowed_extensions.contains(&file_extension) && !is_extra_page {
         return Ok(HttpResponse::Forbidden().finish());
     }
 
+    // The media origin serves nothing but media, to holders of a grant or to anyone for
+    // public media. No cookies reach it.
+    if security::is_media_host(&req) {
+        if page::is_page_file(&filename) {
+            return Ok(HttpResponse::NotFound().finish());
+        }
+        let grant = web::Query::<security::MediaGrantQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().grant);
+        if grant.is_some_and(|grant| security::grant_allows(&username, &grant)) {
+            return serve_user_file(
+                &req,
+                pool.get_ref(),
+                storage.get_ref(),
+                &username,
+                &filename,
+            )
+            .await;
+        }
+    }
+
     let logged_in_username = req
         .cookie("username")
         .map(|cookie| cookie.value().to_string());
@@ -51,16 +100,70 @@ async fn user_page(
 
     if Some(username.clone()) == logged_in_username || is_css_or_js {
         // Allow access to own files or CSS/JS files
-        let user_file_path = format!("./user_pages/{}/{}", username, filename);
+        serve_user_file(
+            &req,
+            pool.get_ref(),
+            storage.get_ref(),
+            &username,
+            &filename,
+        )
+        .await
+    } else {
+        // Feed subscribers fetch media with their feed token, as their apps have no cookie
+        let feed_token = web::Query::<feeds::FeedQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().token);
+        if let Some(token) = feed_token {
+            let url_path = format!("/user_pages/{}/{}", username, filename);
+            if feeds::token_allows_media(pool.get_ref(), &username, &token, &url_path).await {
+                return serve_user_file(
+                    &req,
+                    pool.get_ref(),
+                    storage.get_ref(),
+                    &username,
+                    &filename,
+                )
+                .await;
+            }
+        }
 
-        if Path::new(&user_file_path).exists() {
-            // Serve the file with correct content type
-            Ok(NamedFile::open(user_file_path)?.into_response(&req))
-        } else {
-            // File not found
-            Ok(HttpResponse::NotFound().finish())
+        // Extra pages have their own visibility. Friends-only pages go through the friend
+        // check below like my_page.html.
+        if is_extra_page {
+            match exhibits::visibility(pool.get_ref(), &username, &filename).await {
+                Some(exhibits::Visibility::Public) => {
+                    return serve_user_file(
+                        &req,
+                        pool.get_ref(),
+                        storage.get_ref(),
+                        &username,
+                        &filename,
+                    )
+                    .await;
+                }
+                Some(exhibits::Visibility::Private) if logged_in_username.is_some() => {
+                    return Ok(HttpResponse::Forbidden().finish());
+                }
+                Some(exhibits::Visibility::Private) => {
+                    return Ok(HttpResponse::Unauthorized().finish());
+                }
+                _ => {}
+            }
         }
-    } else {
+
+        // Media of public items can be seen by anyone
+        let url_path = format!("/user_pages/{}/{}", username, filename);
+        if customize::is_public_media(pool.get_ref(), &username, &url_path).await {
+            return serve_user_file(
+                &req,
+                pool.get_ref(),
+                storage.get_ref(),
+                &username,
+                &filename,
+            )
+            .await;
+        }
+
         // Check if the logged-in user is a friend of the requested user
         if let Some(logged_in_user) = logged_in_username {
             let is_friend = sqlx::query_scalar::<_, i64>(
@@ -76,13 +179,14 @@ async fn user_page(
 
             if is_friend {
                 // Allow access to friend's pages
-                let user_file_path = format!("./user_pages/{}/{}", username, filename);
-
-                if Path::new(&user_file_path).exists() {
-                    Ok(NamedFile::open(user_file_path)?.into_response(&req))
-                } else {
-                    Ok(HttpResponse::NotFound().finish())
-                }
+                serve_user_file(
+                    &req,
+                    pool.get_ref(),
+                    storage.get_ref(),
+                    &username,
+                    &filename,
+                )
+                .await
             } else {
                 Ok(HttpResponse::Forbidden().finish())
             }
@@ -91,6 +195,65 @@ async fn user_page(
         }
     }
 }
+
+// Serve a file that has already passed the access checks in user_page
+async fn serve_user_file(
+    req: &HttpRequest,
+    pool: &SqlitePool,
+    storage: &dyn Storage,
+    username: &str,
+    filename: &str,
+) -> Result<HttpResponse> {
+    // The page itself and its stylesheet and script come from the templates
+    if page::is_page_file(filename) {
+        return page::serve(req, pool, username, filename).await;
+    }
+
+    let resize = match web::Query::<images::ResizeQuery>::from_query(req.query_string()) {
+        Ok(query) => Some(query).filter(|query| query.is_requested()),
+        Err(_) => return Ok(HttpResponse::BadRequest().body("Invalid resize parameters.")),
+    };
+
+    // Media blobs live in the configured storage backend
+    if filename.starts_with("blobs/") {
+        let key = format!("{}/{}", username, filename);
+        return match resize {
+            Some(query) => match storage.local_path(&key).await {
+                Ok(path) => {
+                    images::serve_resized(
+                        req,
+                        path.to_string_lossy().into_owned(),
+                        query.into_inner(),
+                    )
+                    .await
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    Ok(HttpResponse::NotFound().finish())
+                }
+                Err(e) => {
+                    eprintln!("Failed to fetch {} from storage: {}", key, e);
+                    Ok(HttpResponse::InternalServerError().finish())
+                }
+            },
+            None => storage.serve(req, &key).await,
+        };
+    }
+
+    let user_file_path = format!("./user_pages/{}/{}", username, filename);
+    if !Path::new(&user_file_path).exists() {
+        // File not found
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    // Images can be resized on the fly with ?w=, ?h= and ?fit=
+    if let Some(query) = resize {
+        return images::serve_resized(req, user_file_path, query.into_inner()).await;
+    }
+
+    // Serve the file with correct content type
+    Ok(NamedFile::open(user_file_path)?.into_response(req))
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Create a connection pool
@@ -137,10 +300,249 @@ async fn main() -> std::io::Result<()> {
     .await
     .expect("Failed to create friends table");
 
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS metadata_settings (
+        username TEXT PRIMARY KEY,
+        keep_capture_time BOOLEAN NOT NULL DEFAULT 0,
+        keep_camera_model BOOLEAN NOT NULL DEFAULT 0,
+        FOREIGN KEY(username) REFERENCES users(username)
+    );",
+    )
+    .execute(&db_pool)
+    .await
+    .expect("Failed to create metadata_settings table");
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS tus_uploads (
+        id TEXT PRIMARY KEY,
+        username TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        title TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        upload_length INTEGER NOT NULL,
+        upload_offset INTEGER NOT NULL DEFAULT 0,
+        updated_at TEXT NOT NULL,
+        FOREIGN KEY(username) REFERENCES users(username)
+    );",
+    )
+    .execute(&db_pool)
+    .await
+    .expect("Failed to create tus_uploads table");
+
+    // Admins can override storage quotas; add usernames with the sqlite3 CLI
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS admins (
+        username TEXT PRIMARY KEY,
+        FOREIGN KEY(username) REFERENCES users(username)
+    );",
+    )
+    .execute(&db_pool)
+    .await
+    .expect("Failed to create admins table");
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS storage_quotas (
+        username TEXT PRIMARY KEY,
+        quota_bytes INTEGER NOT NULL,
+        FOREIGN KEY(username) REFERENCES users(username)
+    );",
+    )
+    .execute(&db_pool)
+    .await
+    .expect("Failed to create storage_quotas table");
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS media_blobs (
+        username TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        file_name TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        ref_count INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY(username, hash),
+        FOREIGN KEY(username) REFERENCES users(username)
+    );",
+    )
+    .execute(&db_pool)
+    .await
+    .expect("Failed to create media_blobs table");
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS feed_tokens (
+        token TEXT PRIMARY KEY,
+        owner TEXT NOT NULL,
+        subscriber TEXT NOT NULL,
+        feed TEXT NOT NULL,
+        created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+        FOREIGN KEY(owner) REFERENCES users(username),
+        FOREIGN KEY(subscriber) REFERENCES users(username)
+    );",
+    )
+    .execute(&db_pool)
+    .await
+    .expect("Failed to create feed_tokens table");
+
+    // Media files of public items, one row for each item that shows them
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS public_media (
+        username TEXT NOT NULL,
+        path TEXT NOT NULL,
+        item TEXT NOT NULL,
+        PRIMARY KEY(username, path, item),
+        FOREIGN KEY(username) REFERENCES users(username)
+    );",
+    )
+    .execute(&db_pool)
+    .await
+    .expect("Failed to create public_media table");
+
+    // Titles and other settings pages are rendered with
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS page_settings (
+        username TEXT PRIMARY KEY,
+        exhibit_title TEXT NOT NULL,
+        main_title TEXT NOT NULL,
+        theme TEXT NOT NULL DEFAULT 'cyberpunk',
+        FOREIGN KEY(username) REFERENCES users(username)
+    );",
+    )
+    .execute(&db_pool)
+    .await
+    .expect("Failed to create page_settings table");
+
+    // Databases created before themes have no theme column
+    let has_theme: bool = sqlx::query_scalar(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('page_settings') WHERE name = 'theme'",
+    )
+    .fetch_one(&db_pool)
+    .await
+    .expect("Failed to inspect page_settings table");
+    if !has_theme {
+        sqlx::query("ALTER TABLE page_settings ADD COLUMN theme TEXT NOT NULL DEFAULT 'cyberpunk'")
+            .execute(&db_pool)
+            .await
+            .expect("Failed to add theme column to page_settings");
+    }
+
+    // Versions of each user's custom CSS, newest last
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS custom_styles (
+        username TEXT NOT NULL,
+        version INTEGER NOT NULL,
+        css TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        PRIMARY KEY(username, version),
+        FOREIGN KEY(username) REFERENCES users(username)
+    );",
+    )
+    .execute(&db_pool)
+    .await
+    .expect("Failed to create custom_styles table");
+
+    // Each user's page layout as JSON
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS page_layouts (
+        username TEXT PRIMARY KEY,
+        layout TEXT NOT NULL,
+        FOREIGN KEY(username) REFERENCES users(username)
+    );",
+    )
+    .execute(&db_pool)
+    .await
+    .expect("Failed to create page_layouts table");
+
+    // Extra pages besides my_page.html, in navigation order
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS exhibit_pages (
+        username TEXT NOT NULL,
+        slug TEXT NOT NULL,
+        title TEXT NOT NULL,
+        visibility TEXT NOT NULL,
+        items TEXT NOT NULL,
+        layout TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        PRIMARY KEY(username, slug),
+        FOREIGN KEY(username) REFERENCES users(username)
+    );",
+    )
+    .execute(&db_pool)
+    .await
+    .expect("Failed to create exhibit_pages table");
+
+    // Earlier versions of each user's my_page.html
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS page_snapshots (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        username TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        change TEXT NOT NULL,
+        state TEXT NOT NULL,
+        FOREIGN KEY(username) REFERENCES users(username)
+    );",
+    )
+    .execute(&db_pool)
+    .await
+    .expect("Failed to create page_snapshots table");
+
+    // Display names, avatars and other profile fields
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS profiles (
+        username TEXT PRIMARY KEY,
+        display_name TEXT,
+        bio TEXT,
+        pronouns TEXT,
+        location TEXT,
+        website TEXT,
+        avatar TEXT,
+        visibility TEXT NOT NULL DEFAULT 'friends',
+        FOREIGN KEY(username) REFERENCES users(username)
+    );",
+    )
+    .execute(&db_pool)
+    .await
+    .expect("Failed to create profiles table");
+
+    // Pages were once copied into each user's folder; import what those copies held
+    page::migrate_legacy_pages(&db_pool).await;
+
+    // Media storage backend, chosen with STORAGE_BACKEND
+    let media_storage = web::Data::from(storage::from_env());
+
+    // CSP, nosniff and framing headers for every response; see security.rs
+    let security_headers =
+        web::Data::new(security::SecurityHeaders::new(media_storage.media_source()));
+
     HttpServer::new(move || {
         let db_pool_clone = db_pool.clone();
+        let security_headers = security_headers.clone();
         App::new()
+            // Refuse state-changing requests from other sites; see security.rs
+            .wrap_fn(|req, srv| {
+                let response = if security::is_cross_site(&req) {
+                    Err(req.into_response(
+                        HttpResponse::Forbidden().body("Cross-site request refused"),
+                    ))
+                } else {
+                    Ok(srv.call(req))
+                };
+                async move {
+                    match response {
+                        Ok(response) => Ok(response.await?.map_into_boxed_body()),
+                        Err(refused) => Ok(refused),
+                    }
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let security_headers = security_headers.clone();
+                let response = srv.call(req);
+                async move {
+                    let mut response = response.await?;
+                    security_headers.apply(&mut response);
+                    Ok(response)
+                }
+            })
             .app_data(web::Data::new(db_pool.clone()))
+            .app_data(media_storage.clone())
             .service(actix_files::Files::new("/static", "./static").show_files_listing())
             // Remove or secure the following line to prevent direct access to user_pages
             // .service(actix_files::Files::new("/user_pages", "./user_pages").show_files_listing())
@@ -151,18 +553,73 @@ async fn main() -> std::io::Result<()> {
                 "/user_pages/{username}/{filename:.*}",
                 web::get().to({
                     let db_pool_inner = db_pool_clone.clone(); // Clone inside the closure
+                    let storage_inner = media_storage.clone();
                     move |path, req| {
-                        user_page(path, req, web::Data::new(db_pool_inner.clone()))
+                        user_page(
+                            path,
+                            req,
+                            web::Data::new(db_pool_inner.clone()),
+                            storage_inner.clone(),
+                        )
                         // Clone again here
                     }
                 }),
             )
             .route("/save_changes", web::post().to(customize::save_changes))
-            .route("/generate_invite", web::get().to(invite::generate_invite))
+            .route("/themes", web::get().to(customize::get_themes))
+            .route("/set_theme", web::post().to(customize::set_theme))
+            .route("/page_layout", web::get().to(layout::get_page_layout))
+            .route("/page_layout", web::post().to(layout::save_page_layout))
+            .route("/page_snapshots", web::get().to(snapshots::list_snapshots))
+            .route(
+                "/page_snapshots/{id}/preview",
+                web::get().to(snapshots::preview_snapshot),
+            )
+            .route(
+                "/page_snapshots/{id}/custom_styles.css",
+                web::get().to(snapshots::snapshot_custom_css),
+            )
+            .route(
+                "/restore_page_snapshot",
+                web::post().to(snapshots::restore_snapshot),
+            )
+            .route("/exhibit_pages", web::get().to(exhibits::list_pages))
+            .route("/exhibit_pages", web::post().to(exhibits::create_page))
+            .route(
+                "/update_exhibit_page",
+                web::post().to(exhibits::update_page),
+            )
+            .route(
+                "/delete_exhibit_page",
+                web::post().to(exhibits::delete_page),
+            )
+            .route(
+                "/page_content/{username}/{slug}",
+                web::get().to(exhibits::page_content),
+            )
+            .route("/custom_css", web::get().to(customize::get_custom_css))
+            .route("/custom_css", web::post().to(customize::save_custom_css))
+            .route(
+                "/rollback_custom_css",
+                web::post().to(customize::rollback_custom_css),
+            )
+            .route("/generate_invite", web::post().to(invite::generate_invite))
             .route("/invite/{token}", web::get().to(invite::handle_invite))
             .route("/upload_gallery", web::post().to(customize::upload_gallery))
             .route("/get_galleries", web::get().to(customize::get_galleries))
+            .route(
+                "/get_metadata_settings",
+                web::get().to(settings::get_metadata_settings),
+            )
+            .route(
+                "/save_metadata_settings",
+                web::post().to(settings::save_metadata_settings),
+            )
             .route("/get_friends", web::get().to(friends::get_friends))
+            .route("/profile/{username}", web::get().to(profile::get_profile))
+            .route("/profile", web::post().to(profile::save_profile))
+            .route("/upload_avatar", web::post().to(profile::upload_avatar))
+            .route("/remove_avatar", web::post().to(profile::remove_avatar))
             .route("/add_friend", web::post().to(friends::add_friend))
             .route(
                 "/upload_text_post",
@@ -171,13 +628,41 @@ async fn main() -> std::io::Result<()> {
             .route("/get_text_posts", web::get().to(customize::get_text_posts))
             .route("/upload_film", web::post().to(customize::upload_film))
             .route("/get_films", web::get().to(customize::get_films))
+            .route(
+                "/upload_captions",
+                web::post().to(customize::upload_captions),
+            )
+            .route(
+                "/hls/{username}/{timestamp}/{file:.*}",
+                web::get().to(hls::serve_hls),
+            )
+            // Resumable uploads (tus protocol)
+            .route("/tus", web::method(Method::OPTIONS).to(tus::options))
+            .route("/tus", web::post().to(tus::create_upload))
+            .route("/tus/{id}", web::head().to(tus::upload_offset))
+            .route("/tus/{id}", web::patch().to(tus::append_chunk))
+            .route("/tus/{id}", web::delete().to(tus::terminate_upload))
             .route("/upload_audio", web::post().to(customize::upload_audio))
             .route("/get_audios", web::get().to(customize::get_audios))
+            .route("/upload_album", web::post().to(customize::upload_album))
+            .route("/feed_token", web::post().to(feeds::create_feed_token))
+            .route("/podcast/{username}", web::get().to(feeds::podcast_feed))
+            .route("/atom/{username}", web::get().to(feeds::atom_feed))
+            .route("/feed_tokens", web::get().to(feeds::list_feed_tokens))
+            .route(
+                "/revoke_feed_token",
+                web::post().to(feeds::revoke_feed_token),
+            )
+            .route("/reorder_album", web::post().to(customize::reorder_album))
+            .route("/storage", web::get().to(quota::get_storage))
+            .route("/set_quota", web::post().to(quota::set_quota))
             // Inside your HttpServer configuration
             .route(
                 "/get_all_content",
                 web::get().to(customize::get_all_content),
             )
+            .route("/delete_content", web::post().to(customize::delete_content))
+            .route("/set_visibility", web::post().to(customize::set_visibility))
     })
     .bind("127.0.0.1:8080")?
     .run()
@@ -0,0 +1,557 @@
+// User pages are rendered on every request from the templates in ./user_pages plus the
+// settings each user has saved, so template changes reach every existing page. Nothing in
+// a user's folder is written while rendering.
+//
+// Styles are layered: the shared stylesheet, then the user's theme from ./static/themes,
+// then the user's own overrides from styles.rs.
+//
+// Pages used to be copied into each user's folder on first login and edited there. On
+// startup, migrate_legacy_pages imports what those copies held; see there.
+use crate::exhibits::{self, Access, ExhibitPage};
+use crate::layout::{self, PageLayout};
+use crate::security::{self, ScriptNonce};
+use crate::styles;
+use actix_files::NamedFile;
+use actix_web::{HttpRequest, HttpResponse};
+use scraper::{Html, Selector};
+use sqlx::{Executor, Sqlite, SqlitePool};
+use std::fs;
+use std::path::Path;
+
+const PAGE_TEMPLATE: &str = "./user_pages/default_page.html";
+const STYLES_TEMPLATE: &str = "./user_pages/default_styles.css";
+const SCRIPTS_TEMPLATE: &str = "./user_pages/default_scripts.js";
+
+const DEFAULT_MAIN_TITLE: &str = "Welcome to my cyberpunk world!";
+
+// Themes shipped in ./static/themes, as (name, label). The shared stylesheet already looks
+// like the default theme, so its file is empty.
+pub const THEMES: [(&str, &str); 4] = [
+    ("cyberpunk", "Cyberpunk"),
+    ("synthwave", "Synthwave"),
+    ("terminal", "Terminal"),
+    ("daylight", "Daylight"),
+];
+pub const DEFAULT_THEME: &str = "cyberpunk";
+
+#[derive(sqlx::FromRow)]
+pub struct PageSettings {
+    pub exhibit_title: String,
+    pub main_title: String,
+    pub theme: String,
+}
+
+impl PageSettings {
+    fn default_for(username: &str) -> PageSettings {
+        PageSettings {
+            exhibit_title: format!("{}'s Exhibit", username),
+            main_title: DEFAULT_MAIN_TITLE.to_string(),
+            theme: DEFAULT_THEME.to_string(),
+        }
+    }
+}
+
+pub fn is_theme(name: &str) -> bool {
+    THEMES.iter().any(|(theme, _)| *theme == name)
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub async fn load_page_settings(pool: &SqlitePool, username: &str) -> PageSettings {
+    sqlx::query_as::<_, PageSettings>(
+        "SELECT exhibit_title, main_title, theme FROM page_settings WHERE username = ?",
+    )
+    .bind(username)
+    .fetch_optional(pool)
+    .await
+    .unwrap_or_else(|e| {
+        eprintln!("Failed to load page settings for {}: {}", username, e);
+        None
+    })
+    .map(|mut settings| {
+        // A theme that is no longer shipped falls back to the default
+        if !is_theme(&settings.theme) {
+            settings.theme = DEFAULT_THEME.to_string();
+        }
+        settings
+    })
+    .unwrap_or_else(|| PageSettings::default_for(username))
+}
+
+pub async fn save_titles<'e, E: Executor<'e, Database = Sqlite>>(
+    executor: E,
+    username: &str,
+    exhibit_title: &str,
+    main_title: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO page_settings (username, exhibit_title, main_title) VALUES (?, ?, ?)
+         ON CONFLICT(username) DO UPDATE SET
+            exhibit_title = excluded.exhibit_title,
+            main_title = excluded.main_title",
+    )
+    .bind(username)
+    .bind(exhibit_title)
+    .bind(main_title)
+    .execute(executor)
+    .await
+    .map(|_| ())
+}
+
+pub async fn save_theme<'e, E: Executor<'e, Database = Sqlite>>(
+    executor: E,
+    username: &str,
+    theme: &str,
+) -> Result<(), sqlx::Error> {
+    let defaults = PageSettings::default_for(username);
+    sqlx::query(
+        "INSERT INTO page_settings (username, exhibit_title, main_title, theme) VALUES (?, ?, ?, ?)
+         ON CONFLICT(username) DO UPDATE SET theme = excluded.theme",
+    )
+    .bind(username)
+    .bind(&defaults.exhibit_title)
+    .bind(&defaults.main_title)
+    .bind(theme)
+    .execute(executor)
+    .await
+    .map(|_| ())
+}
+
+// Replace each {{name}} in the template in one pass, so nothing users wrote is ever read
+// as a placeholder
+fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let value = after.find("}}").and_then(|end| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &after[..end])
+                .map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                output.push_str(value);
+                rest = &after[end + 2..];
+            }
+            None => {
+                output.push_str("{{");
+                rest = after;
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+// Links to the main page and the extra pages `access` may see. Users without extra pages get
+// no navigation at all.
+fn render_navigation(
+    username: &str,
+    pages: &[ExhibitPage],
+    current: Option<&str>,
+    access: Access,
+) -> String {
+    let visible: Vec<&ExhibitPage> = pages
+        .iter()
+        .filter(|page| page.visibility.allows(access))
+        .collect();
+    if visible.is_empty() {
+        return String::new();
+    }
+
+    let link = |href: String, title: &str, is_current: bool| {
+        format!(
+            "    <li><a href=\"{}\"{}>{}</a></li>\n",
+            href,
+            if is_current { " class=\"current\"" } else { "" },
+            escape_html(title)
+        )
+    };
+    let mut html = String::from("<nav class=\"page-nav\">\n  <ul>\n");
+    html += &link(
+        format!("/user_pages/{}/my_page.html", username),
+        "Home",
+        current.is_none(),
+    );
+    for page in visible {
+        html += &link(
+            format!("/user_pages/{}/{}", username, page.slug),
+            &page.title,
+            current == Some(page.slug.as_str()),
+        );
+    }
+    html += "  </ul>\n</nav>\n";
+    html
+}
+
+// A filled-in page and the nonce its script tags carry
+pub struct RenderedPage {
+    pub html: String,
+    pub nonce: String,
+}
+
+// Fill the page template in for a user's main page, or one of their extra pages
+pub async fn render(
+    pool: &SqlitePool,
+    username: &str,
+    page: Option<&ExhibitPage>,
+    access: Access,
+) -> std::io::Result<RenderedPage> {
+    let settings = load_page_settings(pool, username).await;
+    match page {
+        Some(page) => {
+            render_with(
+                pool,
+                username,
+                &settings,
+                &page.layout,
+                Some(page),
+                access,
+                None,
+            )
+            .await
+        }
+        None => {
+            let layout = layout::load(pool, username).await;
+            render_with(pool, username, &settings, &layout, None, access, None).await
+        }
+    }
+}
+
+// Render from the given settings and layout rather than the saved ones, which is how
+// snapshots are previewed. `custom_styles_url` replaces the link to the user's custom CSS.
+pub async fn render_with(
+    pool: &SqlitePool,
+    username: &str,
+    settings: &PageSettings,
+    layout: &PageLayout,
+    page: Option<&ExhibitPage>,
+    access: Access,
+    custom_styles_url: Option<&str>,
+) -> std::io::Result<RenderedPage> {
+    let template = fs::read_to_string(PAGE_TEMPLATE)?;
+
+    // Visitors only see public media, which the media origin serves without a grant
+    let media_grant = match access {
+        Access::Owner | Access::Friend if security::media_origin().is_some() => {
+            Some(security::media_grant(username))
+        }
+        _ => None,
+    };
+    let (sections, page_title) = match page {
+        Some(page) => (
+            layout.render(username, &page.title, media_grant.as_deref()),
+            format!("{} - {}", page.title, settings.exhibit_title),
+        ),
+        None => (
+            layout.render(username, &settings.main_title, media_grant.as_deref()),
+            settings.exhibit_title.clone(),
+        ),
+    };
+    let pages = exhibits::list(pool, username).await.unwrap_or_else(|e| {
+        eprintln!("Failed to list pages of {}: {}", username, e);
+        Vec::new()
+    });
+    let navigation = render_navigation(
+        username,
+        &pages,
+        page.map(|page| page.slug.as_str()),
+        access,
+    );
+    let custom_styles_url = custom_styles_url
+        .map(|url| url.to_string())
+        .unwrap_or_else(|| format!("/user_pages/{}/custom_styles.css", username));
+    let nonce = security::new_nonce();
+
+    let html = fill_template(
+        &template,
+        &[
+            ("sections", &sections),
+            ("page_nav", &navigation),
+            ("page_title", &escape_html(&page_title)),
+            (
+                "page",
+                page.map(|page| page.slug.as_str()).unwrap_or_default(),
+            ),
+            ("exhibit_title", &escape_html(&settings.exhibit_title)),
+            ("main_title", &escape_html(&settings.main_title)),
+            ("theme", &settings.theme),
+            ("custom_styles_url", &escape_html(&custom_styles_url)),
+            ("username", &escape_html(username)),
+            ("nonce", &nonce),
+            (
+                "media_origin",
+                &escape_html(&security::media_origin().unwrap_or_default()),
+            ),
+            ("media_grant", media_grant.as_deref().unwrap_or_default()),
+        ],
+    );
+    Ok(RenderedPage { html, nonce })
+}
+
+// The page's Content-Security-Policy only lets through scripts carrying its nonce
+pub fn html_response(username: &str, rendered: std::io::Result<RenderedPage>) -> HttpResponse {
+    match rendered {
+        Ok(rendered) => {
+            let mut response = HttpResponse::Ok()
+                .content_type("text/html; charset=utf-8")
+                .body(rendered.html);
+            response
+                .extensions_mut()
+                .insert(ScriptNonce(rendered.nonce));
+            response
+        }
+        Err(e) => {
+            eprintln!("Failed to render page for {}: {}", username, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Serve a rendered page, or the stylesheets and script it links to
+pub async fn serve(
+    req: &HttpRequest,
+    pool: &SqlitePool,
+    username: &str,
+    filename: &str,
+) -> actix_web::Result<HttpResponse> {
+    let viewer = req
+        .cookie("username")
+        .map(|cookie| cookie.value().to_string());
+
+    match filename {
+        "my_page.html" => {
+            let access = exhibits::access(pool, username, viewer.as_deref()).await;
+            Ok(html_response(
+                username,
+                render(pool, username, None, access).await,
+            ))
+        }
+        "my_styles.css" => Ok(NamedFile::open(STYLES_TEMPLATE)?.into_response(req)),
+        "my_scripts.js" => Ok(NamedFile::open(SCRIPTS_TEMPLATE)?.into_response(req)),
+        // Users without overrides get an empty stylesheet
+        "custom_styles.css" => match styles::current(pool, username).await {
+            Ok(css) => Ok(HttpResponse::Ok()
+                .content_type("text/css; charset=utf-8")
+                .body(css.unwrap_or_default())),
+            Err(e) => {
+                eprintln!("Failed to load custom styles for {}: {}", username, e);
+                Ok(HttpResponse::InternalServerError().finish())
+            }
+        },
+        // An extra page; user_page has already checked its visibility
+        slug if exhibits::is_slug(slug) => match exhibits::find(pool, username, slug).await {
+            Ok(Some(page)) => {
+                let access = exhibits::access(pool, username, viewer.as_deref()).await;
+                Ok(html_response(
+                    username,
+                    render(pool, username, Some(&page), access).await,
+                ))
+            }
+            Ok(None) => Ok(HttpResponse::NotFound().finish()),
+            Err(e) => {
+                eprintln!("Failed to load page {} of {}: {}", slug, username, e);
+                Ok(HttpResponse::InternalServerError().finish())
+            }
+        },
+        _ => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+// Files that are rendered from templates rather than read from the user's folder
+pub fn is_page_file(filename: &str) -> bool {
+    matches!(
+        filename,
+        "my_page.html" | "my_styles.css" | "my_scripts.js" | "custom_styles.css"
+    ) || exhibits::is_slug(filename)
+}
+
+// Import the per-user copies pages were once served from, then rename each file to
+// *.migrated so it is imported only once:
+// - the titles in my_page.html become the page settings, unless settings were saved since;
+// - edits to my_styles.css become the user's custom CSS, if it passes the sanitizer and the
+//   user has none yet;
+// - edits to my_scripts.js are dropped, since pages only run the shared script.
+// The renamed files are kept, so nothing that could not be imported is lost.
+pub async fn migrate_legacy_pages(pool: &SqlitePool) {
+    let usernames: Vec<String> = match sqlx::query_scalar("SELECT username FROM users")
+        .fetch_all(pool)
+        .await
+    {
+        Ok(usernames) => usernames,
+        Err(e) => {
+            eprintln!("Failed to list users for the page migration: {}", e);
+            return;
+        }
+    };
+
+    for username in usernames {
+        let folder = format!("./user_pages/{}", username);
+        if let Err(e) = migrate_legacy_page(pool, &username, &folder).await {
+            eprintln!("Failed to migrate the page of {}: {}", username, e);
+        }
+    }
+}
+
+async fn migrate_legacy_page(
+    pool: &SqlitePool,
+    username: &str,
+    folder: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let page_path = format!("{}/my_page.html", folder);
+    if let Ok(html) = fs::read_to_string(&page_path) {
+        let (exhibit_title, main_title) = legacy_titles(&html, username);
+        sqlx::query(
+            "INSERT INTO page_settings (username, exhibit_title, main_title) VALUES (?, ?, ?)
+             ON CONFLICT(username) DO NOTHING",
+        )
+        .bind(username)
+        .bind(&exhibit_title)
+        .bind(&main_title)
+        .execute(pool)
+        .await?;
+        mark_migrated(&page_path)?;
+    }
+
+    let styles_path = format!("{}/my_styles.css", folder);
+    if let Some(css) = edited_copy(&styles_path, STYLES_TEMPLATE) {
+        match styles::sanitize(&css) {
+            Ok(css) if styles::current(pool, username).await?.is_none() => {
+                styles::save_version(pool, username, &css).await?;
+            }
+            Ok(_) => eprintln!(
+                "Not importing {}: {} already has custom CSS",
+                styles_path, username
+            ),
+            Err(e) => eprintln!("Not importing {}: {}", styles_path, e),
+        }
+    }
+    if Path::new(&styles_path).exists() {
+        mark_migrated(&styles_path)?;
+    }
+
+    let scripts_path = format!("{}/my_scripts.js", folder);
+    if edited_copy(&scripts_path, SCRIPTS_TEMPLATE).is_some() {
+        eprintln!(
+            "Edits to {} are no longer used; pages run the shared script",
+            scripts_path
+        );
+    }
+    if Path::new(&scripts_path).exists() {
+        mark_migrated(&scripts_path)?;
+    }
+    Ok(())
+}
+
+// The titles a legacy page showed: the exhibit title in <title>, and the main title in the
+// first heading of <main>. Missing titles fall back to the defaults.
+fn legacy_titles(html: &str, username: &str) -> (String, String) {
+    let document = Html::parse_document(html);
+    let text = |selector: &str| {
+        let selector = Selector::parse(selector).unwrap();
+        document
+            .select(&selector)
+            .next()
+            .map(|element| element.text().collect::<String>().trim().to_string())
+            .filter(|text| !text.is_empty())
+            // Pages of users who never logged in still hold the placeholder
+            .map(|text| text.replace("{{username}}", username))
+    };
+
+    let defaults = PageSettings::default_for(username);
+    (
+        text("title").unwrap_or(defaults.exhibit_title),
+        text("#main-title, main h2").unwrap_or(defaults.main_title),
+    )
+}
+
+// The contents of a user's copy of a template, if they differ from the template. Lines
+// importing something the template imports too are left out: the sanitizer refuses
+// @import, and the shared stylesheet loads them anyway.
+fn edited_copy(path: &str, template: &str) -> Option<String> {
+    let copy = fs::read_to_string(path).ok()?;
+    let template = fs::read_to_string(template).unwrap_or_default();
+    if template.trim() == copy.trim() {
+        return None;
+    }
+    Some(without_shared_imports(&copy, &template))
+}
+
+fn without_shared_imports(copy: &str, template: &str) -> String {
+    let is_shared_import = |line: &str| {
+        let line = line.trim();
+        line.starts_with("@import") && template.lines().any(|shared| shared.trim() == line)
+    };
+    copy.lines()
+        .filter(|line| !is_shared_import(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn mark_migrated(path: &str) -> std::io::Result<()> {
+    fs::rename(path, format!("{}.migrated", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_filled() {
+        let html = fill_template(
+            "<h1>{{title}}</h1><p>{{user}} {{title}}</p>",
+            &[("title", "Hi"), ("user", "alice")],
+        );
+        assert_eq!(html, "<h1>Hi</h1><p>alice Hi</p>");
+    }
+
+    #[test]
+    fn filled_values_are_not_read_as_placeholders() {
+        let html = fill_template("{{a}}{{b}}", &[("a", "{{b}}"), ("b", "x")]);
+        assert_eq!(html, "{{b}}x");
+    }
+
+    #[test]
+    fn unknown_and_unclosed_placeholders_are_kept() {
+        let html = fill_template("{{nope}} {{a}} {{a", &[("a", "1")]);
+        assert_eq!(html, "{{nope}} 1 {{a");
+    }
+
+    #[test]
+    fn imports_the_template_shares_are_left_out() {
+        let template = "@import url('fonts.css');\nbody { color: red; }";
+        let copy = "@import url('fonts.css');\n@import url('other.css');\nh1 { color: blue; }";
+        assert_eq!(
+            without_shared_imports(copy, template),
+            "@import url('other.css');\nh1 { color: blue; }"
+        );
+    }
+
+    #[test]
+    fn legacy_titles_are_read_from_the_page() {
+        let html = "<html><head><title>Alice &amp; Co</title></head><body>\
+                    <header><h1>Alice &amp; Co</h1></header>\
+                    <main><section><h2> Hello there </h2></section></main></body></html>";
+        assert_eq!(
+            legacy_titles(html, "alice"),
+            ("Alice & Co".to_string(), "Hello there".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_legacy_titles_fall_back_to_the_defaults() {
+        let html = "<html><head><title>{{username}}'s Exhibit</title></head><body></body></html>";
+        assert_eq!(
+            legacy_titles(html, "bob"),
+            ("bob's Exhibit".to_string(), DEFAULT_MAIN_TITLE.to_string())
+        );
+    }
+}
@@ -0,0 +1,386 @@
+// User profiles: a display name, bio, pronouns, location, website and avatar. The display
+// name and avatar stand for the user wherever they appear, in friend lists for example, so
+// anyone may see them. The other fields follow the profile's visibility, judged the same way
+// as extra pages.
+use crate::blobs;
+use crate::customize::MAX_IMAGE_SIZE;
+use crate::exhibits::{self, Access, Visibility};
+use crate::filetype::{self, MediaKind};
+use crate::images;
+use crate::quota;
+use crate::storage::Storage;
+use crate::upload;
+use crate::user::{self, Profile};
+use actix_multipart::Multipart;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::fs;
+
+const MAX_DISPLAY_NAME_LENGTH: usize = 50;
+const MAX_BIO_LENGTH: usize = 500;
+const MAX_PRONOUNS_LENGTH: usize = 30;
+const MAX_LOCATION_LENGTH: usize = 100;
+const MAX_WEBSITE_LENGTH: usize = 500;
+
+// Width and height avatars are cropped to; smaller images keep their shorter side
+const AVATAR_SIZE: u32 = 512;
+
+// How the avatar is listed in public_media
+const AVATAR_ITEM: &str = "Profile/avatar";
+
+#[derive(Deserialize)]
+pub struct ProfileData {
+    pub display_name: String,
+    pub bio: String,
+    pub pronouns: String,
+    pub location: String,
+    pub website: String,
+    pub visibility: Visibility,
+}
+
+// A profile as seen by one viewer. Fields the viewer may not see are left out.
+#[derive(Serialize)]
+pub struct ProfileView {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub avatar: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pronouns: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub website: Option<String>,
+    // Only shown to the owner
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<Visibility>,
+}
+
+// A trimmed field, or None when it is empty. Errors name the field.
+fn clean(value: &str, name: &str, max_length: usize) -> Result<Option<String>, String> {
+    let value = value.trim();
+    if value.chars().count() > max_length {
+        return Err(format!(
+            "{} must be {} characters or fewer.",
+            name, max_length
+        ));
+    }
+    if value.chars().any(|c| c.is_control() && c != '\n') {
+        return Err(format!("{} contains invalid characters.", name));
+    }
+    Ok(Some(value.to_string()).filter(|value| !value.is_empty()))
+}
+
+impl ProfileData {
+    // The profile to store, with every field trimmed and checked
+    fn validate(&self) -> Result<Profile, String> {
+        let website = clean(&self.website, "Website", MAX_WEBSITE_LENGTH)?;
+        if website
+            .as_deref()
+            .is_some_and(|website| !is_valid_website(website))
+        {
+            return Err("The website must be an http or https link.".to_string());
+        }
+        Ok(Profile {
+            display_name: clean(&self.display_name, "Display name", MAX_DISPLAY_NAME_LENGTH)?,
+            bio: clean(&self.bio, "Bio", MAX_BIO_LENGTH)?,
+            pronouns: clean(&self.pronouns, "Pronouns", MAX_PRONOUNS_LENGTH)?,
+            location: clean(&self.location, "Location", MAX_LOCATION_LENGTH)?,
+            website,
+            avatar: None,
+            visibility: Some(self.visibility.as_str().to_string()),
+        })
+    }
+}
+
+fn is_valid_website(website: &str) -> bool {
+    url::Url::parse(website)
+        .map(|url| matches!(url.scheme(), "http" | "https"))
+        .unwrap_or(false)
+}
+
+fn visibility_of(profile: &Profile) -> Visibility {
+    profile
+        .visibility
+        .as_deref()
+        .map(Visibility::parse)
+        .unwrap_or(Visibility::Friends)
+}
+
+pub async fn load(pool: &SqlitePool, username: &str) -> Result<Profile, sqlx::Error> {
+    sqlx::query_as::<_, Profile>(
+        "SELECT display_name, bio, pronouns, location, website, avatar, visibility
+         FROM profiles WHERE username = ?",
+    )
+    .bind(username)
+    .fetch_optional(pool)
+    .await
+    .map(|profile| profile.unwrap_or_default())
+}
+
+pub async fn get_profile(
+    path: web::Path<String>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let username = path.into_inner();
+    if !user::is_valid_username(&username) {
+        return HttpResponse::NotFound().body("User not found");
+    }
+
+    let exists = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM users WHERE username = ?")
+        .bind(&username)
+        .fetch_one(pool.get_ref())
+        .await;
+    match exists {
+        Ok(0) => return HttpResponse::NotFound().body("User not found"),
+        Ok(_) => {}
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Database error: {}", e))
+        }
+    }
+
+    let profile = match load(pool.get_ref(), &username).await {
+        Ok(profile) => profile,
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Database error: {}", e))
+        }
+    };
+
+    let viewer = req
+        .cookie("username")
+        .map(|cookie| cookie.value().to_string());
+    let access = exhibits::access(pool.get_ref(), &username, viewer.as_deref()).await;
+    let visibility = visibility_of(&profile);
+    let show_details = visibility.allows(access);
+    let detail = |field: Option<String>| field.filter(|_| show_details);
+
+    HttpResponse::Ok().json(ProfileView {
+        username,
+        display_name: profile.display_name,
+        avatar: profile.avatar,
+        bio: detail(profile.bio),
+        pronouns: detail(profile.pronouns),
+        location: detail(profile.location),
+        website: detail(profile.website),
+        visibility: Some(visibility).filter(|_| access == Access::Owner),
+    })
+}
+
+// Save the text fields of the user's own profile. The avatar has its own endpoints.
+pub async fn save_profile(
+    data: web::Json<ProfileData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    let profile = match data.validate() {
+        Ok(profile) => profile,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+
+    let result = sqlx::query(
+        "INSERT INTO profiles
+            (username, display_name, bio, pronouns, location, website, visibility)
+         VALUES (?, ?, ?, ?, ?, ?, ?)
+         ON CONFLICT(username) DO UPDATE SET
+            display_name = excluded.display_name,
+            bio = excluded.bio,
+            pronouns = excluded.pronouns,
+            location = excluded.location,
+            website = excluded.website,
+            visibility = excluded.visibility",
+    )
+    .bind(&username)
+    .bind(profile.display_name)
+    .bind(profile.bio)
+    .bind(profile.pronouns)
+    .bind(profile.location)
+    .bind(profile.website)
+    .bind(profile.visibility)
+    .execute(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(_) => HttpResponse::Ok().body("Profile saved successfully"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+// Replace the user's avatar with `avatar`, or remove it. The new avatar is public media,
+// and the old one's blob is released.
+async fn set_avatar(
+    pool: &SqlitePool,
+    storage: &dyn Storage,
+    username: &str,
+    avatar: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    let old_avatar = load(pool, username).await?.avatar;
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "INSERT INTO profiles (username, avatar) VALUES (?, ?)
+         ON CONFLICT(username) DO UPDATE SET avatar = excluded.avatar",
+    )
+    .bind(username)
+    .bind(avatar)
+    .execute(&mut tx)
+    .await?;
+    sqlx::query("DELETE FROM public_media WHERE username = ? AND item = ?")
+        .bind(username)
+        .bind(AVATAR_ITEM)
+        .execute(&mut tx)
+        .await?;
+    if let Some(avatar) = avatar {
+        sqlx::query("INSERT OR IGNORE INTO public_media (username, path, item) VALUES (?, ?, ?)")
+            .bind(username)
+            .bind(avatar)
+            .bind(AVATAR_ITEM)
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    // Re-uploading the same image gives the same path; store() has already counted the
+    // new reference, so dropping the old one leaves the blob in place
+    if let Some(old_avatar) = old_avatar {
+        blobs::release(pool, storage, username, &old_avatar).await;
+    }
+    Ok(())
+}
+
+// Upload a new avatar. It goes through the same checks and metadata stripping as gallery
+// images, then is cropped to a square.
+pub async fn upload_avatar(
+    mut payload: Multipart,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    storage: web::Data<dyn Storage>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    let user_folder = format!("./user_pages/{}", username);
+    if let Err(e) = fs::create_dir_all(&user_folder) {
+        eprintln!("Failed to create folder for {}: {}", username, e);
+        return HttpResponse::InternalServerError().body("Error saving file.");
+    }
+    let quota_remaining = quota::remaining_quota(pool.get_ref(), &username).await;
+
+    let mut avatar = None;
+    while let Some(item) = payload.next().await {
+        let mut field = match item {
+            Ok(field) => field,
+            Err(_) => continue,
+        };
+        let content_disposition = field.content_disposition().cloned();
+        if content_disposition.as_ref().and_then(|cd| cd.get_name()) != Some("avatar") {
+            continue;
+        }
+        if avatar.is_some() {
+            return HttpResponse::BadRequest().body("Only one avatar image is allowed.");
+        }
+
+        let filename = content_disposition
+            .as_ref()
+            .and_then(|cd| cd.get_filename())
+            .map(sanitize_filename::sanitize)
+            .unwrap_or_else(|| "avatar.png".to_string());
+
+        let upload = match upload::receive_file(
+            &mut field,
+            &user_folder,
+            &filename,
+            MediaKind::Image,
+            MAX_IMAGE_SIZE,
+            quota_remaining,
+        )
+        .await
+        {
+            Ok(upload) => upload,
+            Err(response) => return response,
+        };
+        avatar = Some((upload, filename));
+    }
+
+    let (upload, filename) = match avatar {
+        Some(avatar) => avatar,
+        None => return HttpResponse::BadRequest().body("Please choose an image."),
+    };
+
+    let image_filename = filename.clone();
+    let prepared = web::block(move || {
+        let data = fs::read(upload.path()).map_err(|e| e.to_string())?;
+        filetype::validate_image(&image_filename, &data)?;
+        let (data, _) = images::strip_metadata(data.into())?;
+        let (square, extension) = images::crop_square(&data, AVATAR_SIZE)?;
+        fs::write(upload.path(), square).map_err(|e| e.to_string())?;
+        Ok::<_, String>((upload, extension))
+    })
+    .await;
+    let (upload, extension) = match prepared {
+        Ok(Ok(prepared)) => prepared,
+        Ok(Err(e)) => {
+            return HttpResponse::BadRequest()
+                .body(format!("Invalid image \"{}\": {}", filename, e))
+        }
+        Err(e) => {
+            eprintln!("Failed to process avatar for {}: {}", username, e);
+            return HttpResponse::InternalServerError().body("Error processing image.");
+        }
+    };
+
+    let avatar_path = match blobs::store(
+        pool.get_ref(),
+        storage.get_ref(),
+        &username,
+        upload,
+        &format!("avatar.{}", extension),
+        MediaKind::Image,
+    )
+    .await
+    {
+        Ok(path) => path,
+        Err(response) => return response,
+    };
+
+    match set_avatar(
+        pool.get_ref(),
+        storage.get_ref(),
+        &username,
+        Some(&avatar_path),
+    )
+    .await
+    {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "avatar": avatar_path })),
+        Err(e) => {
+            blobs::release(pool.get_ref(), storage.get_ref(), &username, &avatar_path).await;
+            HttpResponse::InternalServerError().body(format!("Database error: {}", e))
+        }
+    }
+}
+
+pub async fn remove_avatar(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    storage: web::Data<dyn Storage>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    match set_avatar(pool.get_ref(), storage.get_ref(), &username, None).await {
+        Ok(()) => HttpResponse::Ok().body("Avatar removed successfully"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
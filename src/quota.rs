@@ -0,0 +1,205 @@
+use crate::blobs;
+use crate::filetype::MediaKind;
+use crate::user;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::fs;
+use std::path::Path;
+
+// Storage quota for users without an override, in megabytes. Can be changed with the
+// STORAGE_QUOTA_MB environment variable.
+const DEFAULT_QUOTA_MB: u64 = 1024;
+
+// Bytes used by a user, broken down by content type
+#[derive(Serialize, Default)]
+pub struct StorageUsage {
+    pub galleries: u64,
+    pub films: u64,
+    pub audios: u64,
+    pub text_posts: u64,
+    // Anything else in the user's folder
+    pub page: u64,
+    // Space reserved by resumable uploads that are still in progress
+    pub pending_uploads: u64,
+    pub total: u64,
+    pub quota: u64,
+}
+
+#[derive(Deserialize)]
+pub struct StorageQuery {
+    pub username: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SetQuotaData {
+    pub username: String,
+    // None removes the override so the default quota applies again
+    pub quota_mb: Option<u64>,
+}
+
+fn default_quota() -> u64 {
+    std::env::var("STORAGE_QUOTA_MB")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_QUOTA_MB)
+        .saturating_mul(1024 * 1024)
+}
+
+fn folder_size(path: &Path) -> u64 {
+    let mut size = 0;
+    if let Ok(entries) = fs::read_dir(path) {
+        for entry in entries.flatten() {
+            match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => size += folder_size(&entry.path()),
+                Ok(metadata) => size += metadata.len(),
+                Err(_) => {}
+            }
+        }
+    }
+    size
+}
+
+pub async fn is_admin(pool: &SqlitePool, username: &str) -> bool {
+    sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM admins WHERE username = ?")
+        .bind(username)
+        .fetch_one(pool)
+        .await
+        .unwrap_or(0)
+        > 0
+}
+
+pub async fn user_quota(pool: &SqlitePool, username: &str) -> u64 {
+    sqlx::query_scalar::<_, i64>("SELECT quota_bytes FROM storage_quotas WHERE username = ?")
+        .bind(username)
+        .fetch_optional(pool)
+        .await
+        .unwrap_or(None)
+        .map(|quota| quota.max(0) as u64)
+        .unwrap_or_else(default_quota)
+}
+
+pub async fn storage_usage(pool: &SqlitePool, username: &str) -> StorageUsage {
+    let user_folder = format!("./user_pages/{}", username);
+    let mut usage = web::block(move || {
+        let user_folder = Path::new(&user_folder);
+        let mut usage = StorageUsage {
+            galleries: folder_size(&user_folder.join("gallery")),
+            films: folder_size(&user_folder.join("films")) + folder_size(&user_folder.join("hls")),
+            audios: folder_size(&user_folder.join("audios"))
+                + folder_size(&user_folder.join("albums")),
+            text_posts: folder_size(&user_folder.join("text_posts")),
+            ..Default::default()
+        };
+        let content = usage.galleries
+            + usage.films
+            + usage.audios
+            + usage.text_posts
+            + folder_size(&user_folder.join("blobs"));
+        usage.page = folder_size(user_folder).saturating_sub(content);
+        usage
+    })
+    .await
+    .unwrap_or_default();
+
+    // Shared media blobs count once, under the kind of media they hold
+    usage.galleries += blobs::usage(pool, username, MediaKind::Image).await;
+    usage.films += blobs::usage(pool, username, MediaKind::Video).await;
+    usage.audios += blobs::usage(pool, username, MediaKind::Audio).await;
+
+    usage.pending_uploads = sqlx::query_scalar::<_, i64>(
+        "SELECT COALESCE(SUM(upload_length), 0) FROM tus_uploads WHERE username = ?",
+    )
+    .bind(username)
+    .fetch_one(pool)
+    .await
+    .unwrap_or(0)
+    .max(0) as u64;
+
+    usage.total = usage.galleries
+        + usage.films
+        + usage.audios
+        + usage.text_posts
+        + usage.page
+        + usage.pending_uploads;
+    usage.quota = user_quota(pool, username).await;
+    usage
+}
+
+// How many more bytes the user may store
+pub async fn remaining_quota(pool: &SqlitePool, username: &str) -> u64 {
+    let usage = storage_usage(pool, username).await;
+    usage.quota.saturating_sub(usage.total)
+}
+
+pub async fn get_storage(
+    req: HttpRequest,
+    query: web::Query<StorageQuery>,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    // Admins may look at anyone's usage
+    let target = match &query.username {
+        Some(target) if *target != username => {
+            if !user::is_valid_username(target) {
+                return HttpResponse::BadRequest().body("Invalid username.");
+            }
+            if !is_admin(pool.get_ref(), &username).await {
+                return HttpResponse::Forbidden()
+                    .body("Only admins can view other users' storage.");
+            }
+            target.clone()
+        }
+        _ => username,
+    };
+
+    HttpResponse::Ok().json(storage_usage(pool.get_ref(), &target).await)
+}
+
+pub async fn set_quota(
+    data: web::Json<SetQuotaData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    if !is_admin(pool.get_ref(), &username).await {
+        return HttpResponse::Forbidden().body("Only admins can change storage quotas.");
+    }
+
+    if !user::is_valid_username(&data.username) {
+        return HttpResponse::BadRequest().body("Invalid username.");
+    }
+
+    let result = match data.quota_mb {
+        Some(quota_mb) => {
+            sqlx::query(
+                "INSERT OR REPLACE INTO storage_quotas (username, quota_bytes) VALUES (?, ?)",
+            )
+            .bind(&data.username)
+            .bind(quota_mb.saturating_mul(1024 * 1024).min(i64::MAX as u64) as i64)
+            .execute(pool.get_ref())
+            .await
+        }
+        None => {
+            sqlx::query("DELETE FROM storage_quotas WHERE username = ?")
+                .bind(&data.username)
+                .execute(pool.get_ref())
+                .await
+        }
+    };
+
+    match result {
+        Ok(_) => HttpResponse::Ok().body("Quota updated successfully."),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
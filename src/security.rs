@@ -0,0 +1,335 @@
+// Security headers added to every response, the cross-site request check, and the optional
+// separate origin for user media.
+//
+// Every response gets nosniff, a Referrer-Policy, X-Frame-Options and a
+// Content-Security-Policy. Rendered user pages get a policy whose scripts must carry the
+// nonce the page was rendered with; media files from a user's folder get a sandboxed policy,
+// so an uploaded SVG opened on its own cannot run script against the site.
+//
+// Requests that change anything (every method but GET, HEAD and OPTIONS) must come from a
+// page on this site, as shown by their Origin header, or Referer when there is no Origin.
+// Sessions are a cookie alone, so otherwise any site could post to /save_changes and the
+// like on a logged-in user's behalf.
+//
+// Set MEDIA_ORIGIN (e.g. https://media.example.net, a second hostname pointing at this server)
+// to load user media from there instead. Session cookies are host-only, so they never reach
+// that origin: pages of owners and friends hand out a signed, expiring grant in its place,
+// and public media needs none. MEDIA_URL_SECRET signs the grants; without it a random secret
+// is used, and grants stop working when the server restarts. HLS playlists stay on the main
+// origin.
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::Method;
+use actix_web::HttpRequest;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use sha2::Sha256;
+
+// How long a media grant stays valid, in seconds. Expiry is rounded up to the hour so media
+// URLs stay the same, and cacheable, for a while.
+const GRANT_LIFETIME: i64 = 12 * 60 * 60;
+
+// Stylesheets and fonts pages load from Google Fonts
+const FONT_STYLES: &str = "https://fonts.googleapis.com";
+const FONT_FILES: &str = "https://fonts.gstatic.com";
+
+// Files in a user's folder, other than the rendered pages, never need to run anything
+const MEDIA_FILE_POLICY: &str =
+    "default-src 'none'; img-src 'self' data:; media-src 'self'; style-src 'unsafe-inline'; \
+     frame-ancestors 'none'; sandbox";
+
+lazy_static! {
+    static ref MEDIA_ORIGIN: Option<url::Url> = std::env::var("MEDIA_ORIGIN").ok().map(|origin| {
+        let url = url::Url::parse(&origin).expect("MEDIA_ORIGIN must be a URL");
+        if url.host_str().is_none() {
+            panic!("MEDIA_ORIGIN must include a host");
+        }
+        url
+    });
+    static ref MEDIA_URL_SECRET: Vec<u8> = std::env::var("MEDIA_URL_SECRET")
+        .map(|secret| secret.into_bytes())
+        .unwrap_or_else(|_| rand::random::<[u8; 32]>().to_vec());
+}
+
+#[derive(Deserialize)]
+pub struct MediaGrantQuery {
+    pub grant: Option<String>,
+}
+
+// Set on a rendered page's response so the policy allows the page's own scripts
+pub struct ScriptNonce(pub String);
+
+pub fn new_nonce() -> String {
+    base64::encode(rand::random::<[u8; 16]>())
+}
+
+// The media origin, as "scheme://host[:port]", when one is configured
+pub fn media_origin() -> Option<String> {
+    MEDIA_ORIGIN
+        .as_ref()
+        .map(|url| url.origin().ascii_serialization())
+}
+
+// Whether the request was made to the media origin rather than the site itself
+pub fn is_media_host(req: &HttpRequest) -> bool {
+    let media_host = match MEDIA_ORIGIN.as_ref() {
+        Some(url) => match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        },
+        None => return false,
+    };
+    req.connection_info()
+        .host()
+        .eq_ignore_ascii_case(&media_host)
+}
+
+fn grant_mac(owner: &str, expires: i64) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(&MEDIA_URL_SECRET).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}\n{}", owner, expires).as_bytes());
+    mac
+}
+
+// A grant to fetch any of `owner`'s media from the media origin. Only handed to viewers who
+// may see all of it: the owner and their friends.
+pub fn media_grant(owner: &str) -> String {
+    let now = Utc::now().timestamp();
+    let expires = (now + GRANT_LIFETIME) / 3600 * 3600 + 3600;
+    let signature = grant_mac(owner, expires).finalize().into_bytes();
+    format!("{}-{}", expires, hex::encode(signature))
+}
+
+pub fn grant_allows(owner: &str, grant: &str) -> bool {
+    let (expires, signature) = match grant.split_once('-') {
+        Some((expires, signature)) => (expires, signature),
+        None => return false,
+    };
+    let expires = match expires.parse::<i64>() {
+        Ok(expires) => expires,
+        Err(_) => return false,
+    };
+    if expires < Utc::now().timestamp() {
+        return false;
+    }
+
+    // Compared through the MAC so the check takes the same time however much matches
+    match hex::decode(signature) {
+        Ok(signature) => grant_mac(owner, expires).verify_slice(&signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+// Where pages should load the media file at `path` ("/user_pages/..."), with the viewer's
+// grant if they have one
+pub fn media_url(path: &str, grant: Option<&str>) -> String {
+    let origin = match media_origin() {
+        Some(origin) if path.starts_with("/user_pages/") => origin,
+        _ => return path.to_string(),
+    };
+    match grant {
+        Some(grant) => {
+            let separator = if path.contains('?') { '&' } else { '?' };
+            format!("{}{}{}grant={}", origin, path, separator, grant)
+        }
+        None => format!("{}{}", origin, path),
+    }
+}
+
+// Whether a state-changing request was sent from another site. Requests that say nothing of
+// where they came from are only let through without a session cookie, as from scripts and
+// apps, which cannot act on someone else's session.
+pub fn is_cross_site(req: &ServiceRequest) -> bool {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return false;
+    }
+
+    let source = [header::ORIGIN, header::REFERER]
+        .iter()
+        .find_map(|name| req.headers().get(name))
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| url::Url::parse(value).ok())
+        });
+    let source = match source {
+        Some(source) => source,
+        None => return req.cookie("username").is_some(),
+    };
+
+    // An Origin of "null" (sandboxed frames, some redirects) does not parse, and is refused
+    let source_host = source.as_ref().and_then(|url| {
+        url.host_str().map(|host| match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        })
+    });
+    match source_host {
+        Some(host) => !host.eq_ignore_ascii_case(req.connection_info().host()),
+        None => true,
+    }
+}
+
+pub struct SecurityHeaders {
+    // Sources besides 'self' that images and media may load from
+    media_sources: String,
+}
+
+impl SecurityHeaders {
+    // `storage_source` is where the storage backend sends clients for media, if elsewhere
+    pub fn new(storage_source: Option<String>) -> SecurityHeaders {
+        let media_sources = media_origin()
+            .into_iter()
+            .chain(storage_source)
+            .collect::<Vec<_>>()
+            .join(" ");
+        SecurityHeaders { media_sources }
+    }
+
+    fn policy(&self, script_src: &str) -> String {
+        format!(
+            "default-src 'self'; script-src {}; style-src 'self' {}; font-src 'self' {}; \
+             img-src 'self' data: blob: {media}; media-src 'self' blob: {media}; \
+             connect-src 'self'; worker-src 'self' blob:; object-src 'none'; base-uri 'none'; \
+             form-action 'self'; frame-ancestors 'none'",
+            script_src,
+            FONT_STYLES,
+            FONT_FILES,
+            media = self.media_sources
+        )
+    }
+
+    // Add the headers to a response, keeping any the handler set itself
+    pub fn apply<B>(&self, res: &mut ServiceResponse<B>) {
+        let nonce = res
+            .response()
+            .extensions()
+            .get::<ScriptNonce>()
+            .map(|nonce| nonce.0.clone());
+        let is_user_file = res.request().path().starts_with("/user_pages/");
+        let on_media_host = is_media_host(res.request());
+
+        let policy = match nonce {
+            Some(nonce) => self.policy(&format!("'nonce-{}'", nonce)),
+            None if is_user_file => MEDIA_FILE_POLICY.to_string(),
+            None => self.policy("'self'"),
+        };
+
+        let headers = res.headers_mut();
+        let mut set = |name: header::HeaderName, value: &str| {
+            if !headers.contains_key(&name) {
+                if let Ok(value) = HeaderValue::from_str(value) {
+                    headers.insert(name, value);
+                }
+            }
+        };
+        set(header::CONTENT_SECURITY_POLICY, &policy);
+        set(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+        set(header::REFERRER_POLICY, "same-origin");
+        set(header::X_FRAME_OPTIONS, "DENY");
+        // Captions are fetched with CORS when they come from the media origin. Its URLs carry
+        // their own grant and no cookies, so any page may read them.
+        if on_media_host {
+            set(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::cookie::Cookie;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn grants_are_valid_for_their_owner_only() {
+        let grant = media_grant("alice");
+        assert!(grant_allows("alice", &grant));
+        assert!(!grant_allows("bob", &grant));
+    }
+
+    #[test]
+    fn grants_expire_on_the_hour_within_their_lifetime() {
+        let grant = media_grant("alice");
+        let expires: i64 = grant.split_once('-').unwrap().0.parse().unwrap();
+        let now = Utc::now().timestamp();
+        assert_eq!(expires % 3600, 0);
+        assert!(expires > now + GRANT_LIFETIME && expires <= now + GRANT_LIFETIME + 7200);
+    }
+
+    #[test]
+    fn altered_and_expired_grants_are_refused() {
+        let grant = media_grant("alice");
+        let (expires, signature) = grant.split_once('-').unwrap();
+
+        // A later expiry with the old signature
+        let later = expires.parse::<i64>().unwrap() + 3600;
+        assert!(!grant_allows("alice", &format!("{}-{}", later, signature)));
+
+        // One flipped signature bit
+        let mut bytes = hex::decode(signature).unwrap();
+        bytes[0] ^= 1;
+        assert!(!grant_allows(
+            "alice",
+            &format!("{}-{}", expires, hex::encode(bytes))
+        ));
+
+        // Correctly signed, but expired
+        let past = Utc::now().timestamp() - 60;
+        let signature = hex::encode(grant_mac("alice", past).finalize().into_bytes());
+        assert!(!grant_allows("alice", &format!("{}-{}", past, signature)));
+
+        for grant in [
+            "",
+            "-",
+            "abc",
+            &format!("{}-", expires),
+            &format!("{}-zz", expires),
+        ] {
+            assert!(!grant_allows("alice", grant), "{} was accepted", grant);
+        }
+    }
+
+    fn post() -> TestRequest {
+        TestRequest::post().insert_header((header::HOST, "gallery.example"))
+    }
+
+    #[test]
+    fn same_site_and_safe_requests_are_allowed() {
+        let requests = [
+            post().insert_header((header::ORIGIN, "https://gallery.example")),
+            post().insert_header((header::REFERER, "https://gallery.example/user_pages/a/")),
+            TestRequest::get().insert_header((header::ORIGIN, "https://evil.example")),
+            // Scripts and apps without a session
+            post(),
+        ];
+        for request in requests {
+            assert!(!is_cross_site(&request.to_srv_request()));
+        }
+    }
+
+    #[test]
+    fn cross_site_requests_are_refused() {
+        let requests = [
+            post().insert_header((header::ORIGIN, "https://evil.example")),
+            post().insert_header((header::ORIGIN, "https://gallery.example:8443")),
+            post().insert_header((header::ORIGIN, "null")),
+            post().insert_header((header::REFERER, "https://evil.example/gallery.example")),
+            // The Origin header wins over a same-site Referer
+            post()
+                .insert_header((header::ORIGIN, "https://evil.example"))
+                .insert_header((header::REFERER, "https://gallery.example/")),
+            // A session cookie without any sign of where the request came from
+            post().cookie(Cookie::new("username", "alice")),
+            TestRequest::delete()
+                .insert_header((header::HOST, "gallery.example"))
+                .insert_header((header::ORIGIN, "https://evil.example")),
+        ];
+        for request in requests {
+            assert!(is_cross_site(&request.to_srv_request()));
+        }
+    }
+}
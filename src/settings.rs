@@ -0,0 +1,61 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+
+// Which photo metadata a user wants to keep in their gallery metadata. Everything else is
+// always stripped from uploaded images.
+#[derive(Serialize, Deserialize, FromRow, Default)]
+pub struct MetadataSettings {
+    pub keep_capture_time: bool,
+    pub keep_camera_model: bool,
+}
+
+pub async fn load_metadata_settings(pool: &SqlitePool, username: &str) -> MetadataSettings {
+    sqlx::query_as::<_, MetadataSettings>(
+        "SELECT keep_capture_time, keep_camera_model FROM metadata_settings WHERE username = ?",
+    )
+    .bind(username)
+    .fetch_optional(pool)
+    .await
+    .unwrap_or_else(|err| {
+        eprintln!("Database query error: {}", err);
+        None
+    })
+    .unwrap_or_default()
+}
+
+pub async fn get_metadata_settings(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    HttpResponse::Ok().json(load_metadata_settings(pool.get_ref(), &username).await)
+}
+
+pub async fn save_metadata_settings(
+    data: web::Json<MetadataSettings>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    match sqlx::query(
+        "INSERT OR REPLACE INTO metadata_settings (username, keep_capture_time, keep_camera_model)
+         VALUES (?, ?, ?)",
+    )
+    .bind(&username)
+    .bind(data.keep_capture_time)
+    .bind(data.keep_camera_model)
+    .execute(pool.get_ref())
+    .await
+    {
+        Ok(_) => HttpResponse::Ok().body("Settings saved successfully."),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
@@ -0,0 +1,243 @@
+// Version history for my_page.html. The page is rendered from its titles, theme, layout and
+// custom CSS (its script is the shared template), so before any of them changes the whole
+// set is saved as a snapshot. Snapshots can be listed, previewed and restored.
+use crate::customize;
+use crate::exhibits::Access;
+use crate::layout::{self, PageLayout};
+use crate::page::{self, PageSettings};
+use crate::styles;
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+// Snapshots kept per user
+const SNAPSHOTS_KEPT: i64 = 30;
+
+// Everything my_page.html is rendered from
+#[derive(Serialize, Deserialize, PartialEq)]
+pub struct PageState {
+    pub exhibit_title: String,
+    pub main_title: String,
+    pub theme: String,
+    pub layout: PageLayout,
+    pub custom_css: String,
+}
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct SnapshotSummary {
+    pub id: i64,
+    pub created_at: String,
+    // What was about to change when the snapshot was taken
+    pub change: String,
+}
+
+#[derive(Deserialize)]
+pub struct RestoreSnapshotData {
+    pub id: i64,
+}
+
+pub async fn current_state(pool: &SqlitePool, username: &str) -> Result<PageState, sqlx::Error> {
+    let settings = page::load_page_settings(pool, username).await;
+    Ok(PageState {
+        exhibit_title: settings.exhibit_title,
+        main_title: settings.main_title,
+        theme: settings.theme,
+        layout: layout::load(pool, username).await,
+        custom_css: styles::current(pool, username).await?.unwrap_or_default(),
+    })
+}
+
+async fn find(
+    pool: &SqlitePool,
+    username: &str,
+    id: i64,
+) -> Result<Option<PageState>, sqlx::Error> {
+    let state = sqlx::query_scalar::<_, String>(
+        "SELECT state FROM page_snapshots WHERE username = ? AND id = ?",
+    )
+    .bind(username)
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(state.and_then(|state| serde_json::from_str(&state).ok()))
+}
+
+// Save the page as it is now, before `change` is applied. Nothing is saved if the page has
+// not changed since the last snapshot. Failures are logged rather than stopping the change.
+pub async fn record(pool: &SqlitePool, username: &str, change: &str) {
+    if let Err(e) = try_record(pool, username, change).await {
+        eprintln!("Failed to snapshot the page of {}: {}", username, e);
+    }
+}
+
+async fn try_record(pool: &SqlitePool, username: &str, change: &str) -> Result<(), sqlx::Error> {
+    let state = current_state(pool, username).await?;
+
+    let latest = sqlx::query_scalar::<_, String>(
+        "SELECT state FROM page_snapshots WHERE username = ? ORDER BY id DESC LIMIT 1",
+    )
+    .bind(username)
+    .fetch_optional(pool)
+    .await?
+    .and_then(|latest| serde_json::from_str::<PageState>(&latest).ok());
+    if latest.as_ref() == Some(&state) {
+        return Ok(());
+    }
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        "INSERT INTO page_snapshots (username, created_at, change, state) VALUES (?, ?, ?, ?)",
+    )
+    .bind(username)
+    .bind(chrono::Utc::now().to_rfc3339())
+    .bind(change)
+    .bind(serde_json::to_string(&state).unwrap_or_default())
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query(
+        "DELETE FROM page_snapshots WHERE username = ? AND id NOT IN (
+            SELECT id FROM page_snapshots WHERE username = ? ORDER BY id DESC LIMIT ?
+        )",
+    )
+    .bind(username)
+    .bind(username)
+    .bind(SNAPSHOTS_KEPT)
+    .execute(&mut tx)
+    .await?;
+
+    tx.commit().await
+}
+
+// The user's snapshots, newest first
+pub async fn list_snapshots(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    let snapshots = sqlx::query_as::<_, SnapshotSummary>(
+        "SELECT id, created_at, change FROM page_snapshots WHERE username = ? ORDER BY id DESC",
+    )
+    .bind(&username)
+    .fetch_all(pool.get_ref())
+    .await;
+
+    match snapshots {
+        Ok(snapshots) => HttpResponse::Ok().json(snapshots),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+// my_page.html as it was when the snapshot was taken
+pub async fn preview_snapshot(
+    path: web::Path<i64>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+    let id = path.into_inner();
+
+    let state = match find(pool.get_ref(), &username, id).await {
+        Ok(Some(state)) => state,
+        Ok(None) => return HttpResponse::NotFound().body("Snapshot not found"),
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Database error: {}", e))
+        }
+    };
+
+    let settings = PageSettings {
+        exhibit_title: state.exhibit_title,
+        main_title: state.main_title,
+        theme: state.theme,
+    };
+    let custom_styles_url = format!("/page_snapshots/{}/custom_styles.css", id);
+    let rendered = page::render_with(
+        pool.get_ref(),
+        &username,
+        &settings,
+        &state.layout,
+        None,
+        Access::Owner,
+        Some(&custom_styles_url),
+    )
+    .await;
+    page::html_response(&username, rendered)
+}
+
+// The custom CSS a snapshot's preview links to
+pub async fn snapshot_custom_css(
+    path: web::Path<i64>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    match find(pool.get_ref(), &username, path.into_inner()).await {
+        Ok(Some(state)) => HttpResponse::Ok()
+            .content_type("text/css; charset=utf-8")
+            .body(state.custom_css),
+        Ok(None) => HttpResponse::NotFound().body("Snapshot not found"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+// Put the page back the way it was in a snapshot. The page is snapshotted first, so a
+// restore can be undone too.
+pub async fn restore_snapshot(
+    data: web::Json<RestoreSnapshotData>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    let mut state = match find(pool.get_ref(), &username, data.id).await {
+        Ok(Some(state)) => state,
+        Ok(None) => return HttpResponse::NotFound().body("Snapshot not found"),
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Database error: {}", e))
+        }
+    };
+
+    // Pinned items and the avatar may have been deleted since
+    let content = customize::load_all_content(&username);
+    state.layout = match state.layout.fit_to_content(&content) {
+        Ok(layout) => layout,
+        Err(e) => {
+            return HttpResponse::BadRequest()
+                .body(format!("The snapshot no longer fits your page: {}", e))
+        }
+    };
+
+    record(pool.get_ref(), &username, "restore").await;
+
+    match restore(pool.get_ref(), &username, &state).await {
+        Ok(()) => HttpResponse::Ok().body("Page restored successfully"),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Database error: {}", e)),
+    }
+}
+
+// Write everything in `state` back in one transaction, so a failed restore changes nothing
+async fn restore(pool: &SqlitePool, username: &str, state: &PageState) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    page::save_titles(&mut tx, username, &state.exhibit_title, &state.main_title).await?;
+    // A theme that has since been removed is left as it is
+    if page::is_theme(&state.theme) {
+        page::save_theme(&mut tx, username, &state.theme).await?;
+    }
+    layout::save(&mut tx, username, &state.layout).await?;
+    let css = styles::current(&mut tx, username).await?;
+    if css.as_deref().unwrap_or_default() != state.custom_css {
+        styles::save_version(&mut tx, username, &state.custom_css).await?;
+    }
+    tx.commit().await
+}
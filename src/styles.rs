@@ -0,0 +1,287 @@
+// Custom CSS that users layer on top of their theme. Every save is tokenized with cssparser
+// and written back out from the tokens, refusing anything that could load content from
+// another site or run script: @import, url()s that leave the site, and IE's expression()
+// and behavior. The last few versions are kept so a user can roll back to one.
+use cssparser::{ParseError, ParseErrorKind, Parser, ParserInput, ToCss, Token};
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, Executor, Sqlite, SqlitePool};
+use url::Url;
+
+// Largest custom stylesheet accepted
+pub const MAX_CUSTOM_CSS_SIZE: usize = 64 * 1024;
+
+// Versions kept per user, including the current one
+const VERSIONS_KEPT: i64 = 5;
+
+#[derive(Serialize, sqlx::FromRow)]
+pub struct CustomCssVersion {
+    pub version: i64,
+    pub created_at: String,
+    pub size: i64,
+}
+
+#[derive(Deserialize)]
+pub struct CustomCssData {
+    pub css: String,
+}
+
+#[derive(Deserialize)]
+pub struct RollbackCssData {
+    pub version: i64,
+}
+
+// Properties that attach script or bindings to elements in old browsers
+const FORBIDDEN_PROPERTIES: [&str; 2] = ["behavior", "-moz-binding"];
+
+// Functions whose string argument is fetched as a URL
+const URL_FUNCTIONS: [&str; 4] = ["url", "src", "image-set", "-webkit-image-set"];
+
+// Parse `css` and return it re-serialized from its tokens, or a message saying what was
+// refused and on which line
+pub fn sanitize(css: &str) -> Result<String, String> {
+    if css.len() > MAX_CUSTOM_CSS_SIZE {
+        return Err("Custom CSS must be 64KB or less.".to_string());
+    }
+
+    let mut input = ParserInput::new(css);
+    let mut parser = Parser::new(&mut input);
+    let mut output = String::new();
+    write_tokens(&mut parser, &mut output, false).map_err(|e| {
+        let message = match e.kind {
+            ParseErrorKind::Custom(message) => message,
+            ParseErrorKind::Basic(_) => "Invalid CSS.".to_string(),
+        };
+        format!("Line {}: {}", e.location.line + 1, message)
+    })?;
+    Ok(output.trim().to_string())
+}
+
+fn write_tokens<'i>(
+    parser: &mut Parser<'i, '_>,
+    output: &mut String,
+    in_url_function: bool,
+) -> Result<(), ParseError<'i, String>> {
+    loop {
+        let token = match parser.next_including_whitespace_and_comments() {
+            Ok(token) => token.clone(),
+            Err(_) => return Ok(()),
+        };
+
+        let closing = match &token {
+            // Dropped, but still separating the tokens either side of it
+            Token::Comment(_) => {
+                output.push(' ');
+                continue;
+            }
+            Token::AtKeyword(name) if name.eq_ignore_ascii_case("import") => {
+                return Err(parser.new_custom_error("@import is not allowed.".to_string()));
+            }
+            Token::Ident(name)
+                if FORBIDDEN_PROPERTIES
+                    .iter()
+                    .any(|property| name.eq_ignore_ascii_case(property)) =>
+            {
+                return Err(parser.new_custom_error(format!("{} is not allowed.", name)));
+            }
+            Token::Function(name) if name.eq_ignore_ascii_case("expression") => {
+                return Err(parser.new_custom_error("expression() is not allowed.".to_string()));
+            }
+            Token::UnquotedUrl(url) => {
+                check_url(parser, url)?;
+                None
+            }
+            Token::QuotedString(url) if in_url_function => {
+                check_url(parser, url)?;
+                None
+            }
+            Token::BadUrl(_) | Token::BadString(_) => {
+                return Err(parser.new_custom_error("Unterminated string or url().".to_string()));
+            }
+            Token::CloseParenthesis | Token::CloseSquareBracket | Token::CloseCurlyBracket => {
+                return Err(parser.new_custom_error("Unbalanced brackets.".to_string()));
+            }
+            Token::Function(_) => Some(')'),
+            Token::ParenthesisBlock => Some(')'),
+            Token::SquareBracketBlock => Some(']'),
+            Token::CurlyBracketBlock => Some('}'),
+            _ => None,
+        };
+
+        // Writing to a String cannot fail
+        let _ = token.to_css(output);
+
+        if let Some(closing) = closing {
+            let nested_url_function = match &token {
+                Token::Function(name) => URL_FUNCTIONS
+                    .iter()
+                    .any(|function| name.eq_ignore_ascii_case(function)),
+                _ => false,
+            };
+            parser
+                .parse_nested_block(|nested| write_tokens(nested, output, nested_url_function))?;
+            output.push(closing);
+        }
+    }
+}
+
+// Only URLs on this site are allowed: paths like "/user_pages/..." or "images/bg.png", and
+// "#fragment" references. The URL is resolved the way browsers resolve it, so tabs and
+// newlines inside it, leading control characters and backslashes cannot turn a path into a
+// link to another host.
+fn check_url<'i>(parser: &Parser<'i, '_>, url: &str) -> Result<(), ParseError<'i, String>> {
+    // Where custom stylesheets are served from; only its origin matters
+    let base = Url::parse("https://gallery.invalid/user_pages/user/custom_styles.css").unwrap();
+    let on_site = base
+        .join(url)
+        .map(|resolved| resolved.origin() == base.origin())
+        .unwrap_or(false);
+    if !on_site {
+        return Err(
+            parser.new_custom_error(format!("url({}) must point to this site.", url.trim()))
+        );
+    }
+    Ok(())
+}
+
+pub async fn current<'e, E: Executor<'e, Database = Sqlite>>(
+    executor: E,
+    username: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar::<_, String>(
+        "SELECT css FROM custom_styles WHERE username = ? ORDER BY version DESC LIMIT 1",
+    )
+    .bind(username)
+    .fetch_optional(executor)
+    .await
+}
+
+pub async fn list_versions(
+    pool: &SqlitePool,
+    username: &str,
+) -> Result<Vec<CustomCssVersion>, sqlx::Error> {
+    sqlx::query_as::<_, CustomCssVersion>(
+        "SELECT version, created_at, length(CAST(css AS BLOB)) AS size
+         FROM custom_styles WHERE username = ? ORDER BY version DESC",
+    )
+    .bind(username)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn find_version(
+    pool: &SqlitePool,
+    username: &str,
+    version: i64,
+) -> Result<Option<String>, sqlx::Error> {
+    sqlx::query_scalar::<_, String>(
+        "SELECT css FROM custom_styles WHERE username = ? AND version = ?",
+    )
+    .bind(username)
+    .bind(version)
+    .fetch_optional(pool)
+    .await
+}
+
+// Store `css` as the user's newest version and drop the oldest ones past VERSIONS_KEPT.
+// Returns the new version number. Given a transaction, this becomes part of it.
+pub async fn save_version<'a, A: Acquire<'a, Database = Sqlite>>(
+    conn: A,
+    username: &str,
+    css: &str,
+) -> Result<i64, sqlx::Error> {
+    let mut tx = conn.begin().await?;
+
+    let version: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(MAX(version), 0) + 1 FROM custom_styles WHERE username = ?",
+    )
+    .bind(username)
+    .fetch_one(&mut tx)
+    .await?;
+
+    sqlx::query(
+        "INSERT INTO custom_styles (username, version, css, created_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(username)
+    .bind(version)
+    .bind(css)
+    .bind(chrono::Utc::now().to_rfc3339())
+    .execute(&mut tx)
+    .await?;
+
+    sqlx::query("DELETE FROM custom_styles WHERE username = ? AND version <= ?")
+        .bind(username)
+        .bind(version - VERSIONS_KEPT)
+        .execute(&mut tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_on_this_site_are_kept() {
+        let css = "body { background: url(/user_pages/alice/bg.png) }\n\
+                   h1 { background: url(\"images/a.png\"), url(\"#shape\") }";
+        assert_eq!(sanitize(css).unwrap(), css);
+    }
+
+    #[test]
+    fn urls_to_other_sites_are_refused() {
+        for url in [
+            "https://evil.example/x.png",
+            "//evil.example/x.png",
+            "/\\evil.example/x.png",
+            "\\\\evil.example/x.png",
+            "javascript:alert(1)",
+            "data:image/svg+xml,<svg/>",
+            " //evil.example/x.png",
+        ] {
+            let css = format!("a {{ background: url(\"{}\") }}", url.replace('\\', "\\\\"));
+            assert!(sanitize(&css).is_err(), "{} was allowed", url);
+        }
+    }
+
+    #[test]
+    fn escaped_whitespace_cannot_hide_another_host() {
+        // \9 is a tab, \a a newline and \d a carriage return; browsers drop all three from
+        // URLs, leaving "//evil.example"
+        for css in [
+            r#"a { background: url("/\9/evil.example/x.png") }"#,
+            r#"a { background: url("/\a/evil.example/x.png") }"#,
+            r#"a { background: url("/\d/evil.example/x.png") }"#,
+            r#"a { background: url("\1//evil.example/x.png") }"#,
+            r#"a { background: url(/\9/evil.example/x.png) }"#,
+            r#"a { background: image-set("j\9 avascript:x" 1x) }"#,
+        ] {
+            assert!(sanitize(css).is_err(), "{} was allowed", css);
+        }
+    }
+
+    #[test]
+    fn imports_and_script_are_refused() {
+        for css in [
+            "@import 'a.css';",
+            "@IMPORT url(a.css);",
+            "a { behavior: url(a.htc) }",
+            "a { -moz-binding: url(a.xml) }",
+            "a { width: expression(alert(1)) }",
+        ] {
+            assert!(sanitize(css).is_err(), "{} was allowed", css);
+        }
+    }
+
+    #[test]
+    fn comments_and_broken_css_are_handled() {
+        assert_eq!(
+            sanitize("a{color:red}/* x */b{}").unwrap(),
+            "a{color:red} b{}"
+        );
+        assert!(sanitize("a { color: red } }").is_err());
+        assert!(sanitize("a { background: url('x.png\n) }").is_err());
+        assert!(sanitize(&"a".repeat(MAX_CUSTOM_CSS_SIZE + 1)).is_err());
+    }
+}
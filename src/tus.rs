@@ -0,0 +1,558 @@
+// Resumable uploads following the tus 1.0.0 protocol (https://tus.io/protocols/resumable-upload)
+use crate::customize::{self, MAX_AUDIO_SIZE, MAX_FILM_SIZE, MAX_IMAGE_SIZE};
+use crate::filetype::{self, MediaKind};
+use crate::storage::Storage;
+use crate::upload::TempUpload;
+use crate::{quota, settings};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::{web, HttpRequest, HttpResponse, HttpResponseBuilder};
+use chrono::Utc;
+use futures::StreamExt;
+use lazy_static::lazy_static;
+use sqlx::{FromRow, SqlitePool};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+const TUS_VERSION: &str = "1.0.0";
+
+// Partial uploads are kept here until they are complete
+const TUS_UPLOADS_FOLDER: &str = "./tus_uploads";
+
+// Unfinished uploads older than this are removed
+const UPLOAD_EXPIRY_HOURS: i64 = 24;
+
+lazy_static! {
+    // Uploads a request is currently writing to or removing
+    static ref BUSY_UPLOADS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+// Held while a request works on an upload, so two PATCHes cannot both append at the same
+// offset. Released when dropped.
+struct UploadLock(String);
+
+impl UploadLock {
+    // None when another request already holds the upload
+    fn acquire(id: &str) -> Option<UploadLock> {
+        let mut busy = BUSY_UPLOADS.lock().unwrap();
+        if busy.insert(id.to_string()) {
+            Some(UploadLock(id.to_string()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        BUSY_UPLOADS.lock().unwrap().remove(&self.0);
+    }
+}
+
+fn locked_response() -> HttpResponse {
+    tus_response(HttpResponse::Locked()).body("The upload is being written by another request.")
+}
+
+#[derive(FromRow)]
+struct TusUpload {
+    id: String,
+    username: String,
+    kind: String,
+    title: String,
+    filename: String,
+    upload_length: i64,
+    upload_offset: i64,
+}
+
+// What a finished upload turns into, and the size limit that applies to it
+fn upload_kind(kind: &str) -> Option<(MediaKind, u64)> {
+    match kind {
+        "film" => Some((MediaKind::Video, MAX_FILM_SIZE)),
+        "audio" => Some((MediaKind::Audio, MAX_AUDIO_SIZE)),
+        "gallery" => Some((MediaKind::Image, MAX_IMAGE_SIZE)),
+        _ => None,
+    }
+}
+
+fn upload_file_path(id: &str) -> PathBuf {
+    PathBuf::from(format!("{}/{}", TUS_UPLOADS_FOLDER, id))
+}
+
+// Every tus response carries the protocol version
+fn tus_response(mut builder: HttpResponseBuilder) -> HttpResponseBuilder {
+    builder.insert_header(("Tus-Resumable", TUS_VERSION));
+    builder
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+// Requests other than OPTIONS must state the protocol version they speak
+fn check_version(req: &HttpRequest) -> Result<(), HttpResponse> {
+    if header_str(req.headers(), "Tus-Resumable") == Some(TUS_VERSION) {
+        Ok(())
+    } else {
+        Err(tus_response(HttpResponse::PreconditionFailed())
+            .insert_header(("Tus-Version", TUS_VERSION))
+            .finish())
+    }
+}
+
+// Upload-Metadata is a comma separated list of "key base64(value)" pairs
+fn parse_metadata(value: &str) -> HashMap<String, String> {
+    value
+        .split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, ' ');
+            let key = parts.next()?.to_string();
+            let value = match parts.next() {
+                Some(encoded) => String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?,
+                None => String::new(),
+            };
+            Some((key, value))
+        })
+        .collect()
+}
+
+async fn find_upload(
+    pool: &SqlitePool,
+    id: &str,
+    username: &str,
+) -> Result<TusUpload, HttpResponse> {
+    match sqlx::query_as::<_, TusUpload>(
+        "SELECT id, username, kind, title, filename, upload_length, upload_offset
+         FROM tus_uploads WHERE id = ? AND username = ?",
+    )
+    .bind(id)
+    .bind(username)
+    .fetch_optional(pool)
+    .await
+    {
+        Ok(Some(upload)) => Ok(upload),
+        Ok(None) => Err(tus_response(HttpResponse::NotFound()).finish()),
+        Err(e) => Err(tus_response(HttpResponse::InternalServerError())
+            .body(format!("Database error: {}", e))),
+    }
+}
+
+async fn remove_upload(pool: &SqlitePool, id: &str) {
+    let _ = fs::remove_file(upload_file_path(id));
+    if let Err(e) = sqlx::query("DELETE FROM tus_uploads WHERE id = ?")
+        .bind(id)
+        .execute(pool)
+        .await
+    {
+        eprintln!("Failed to remove tus upload {}: {}", id, e);
+    }
+}
+
+// Drop unfinished uploads that have not been resumed for a while
+async fn remove_expired_uploads(pool: &SqlitePool) {
+    let cutoff = (Utc::now() - chrono::Duration::hours(UPLOAD_EXPIRY_HOURS)).to_rfc3339();
+    let expired =
+        sqlx::query_scalar::<_, String>("SELECT id FROM tus_uploads WHERE updated_at < ?")
+            .bind(&cutoff)
+            .fetch_all(pool)
+            .await
+            .unwrap_or_default();
+
+    for id in expired {
+        // An upload being written to is not idle after all
+        if let Some(_lock) = UploadLock::acquire(&id) {
+            remove_upload(pool, &id).await;
+        }
+    }
+}
+
+// OPTIONS /tus: advertise what this server supports
+pub async fn options() -> HttpResponse {
+    tus_response(HttpResponse::NoContent())
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", "creation,termination"))
+        .insert_header(("Tus-Max-Size", MAX_FILM_SIZE.to_string()))
+        .finish()
+}
+
+// POST /tus: start a new upload. Upload-Metadata must contain "type" (film, audio or
+// gallery), "filename" and optionally "title".
+pub async fn create_upload(req: HttpRequest, pool: web::Data<SqlitePool>) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    if let Err(response) = check_version(&req) {
+        return response;
+    }
+
+    let upload_length = match header_str(req.headers(), "Upload-Length")
+        .and_then(|value| value.parse::<u64>().ok())
+    {
+        Some(length) if length > 0 => length,
+        _ => {
+            return tus_response(HttpResponse::BadRequest())
+                .body("A positive Upload-Length header is required.")
+        }
+    };
+
+    let metadata = header_str(req.headers(), "Upload-Metadata")
+        .map(parse_metadata)
+        .unwrap_or_default();
+
+    let kind = metadata.get("type").cloned().unwrap_or_default();
+    let max_size = match upload_kind(&kind) {
+        Some((_, max_size)) => max_size,
+        None => {
+            return tus_response(HttpResponse::BadRequest())
+                .body("Upload-Metadata type must be film, audio or gallery.")
+        }
+    };
+
+    if upload_length > max_size {
+        return tus_response(HttpResponse::PayloadTooLarge()).body(format!(
+            "File size too big (must be under {}MB).",
+            max_size / (1024 * 1024)
+        ));
+    }
+
+    // The whole upload is reserved against the storage quota up front
+    if upload_length > quota::remaining_quota(pool.get_ref(), &username).await {
+        return tus_response(HttpResponse::PayloadTooLarge()).body("Storage quota exceeded.");
+    }
+
+    let filename = match metadata.get("filename") {
+        Some(filename) if !filename.is_empty() => sanitize_filename::sanitize(filename),
+        _ => {
+            return tus_response(HttpResponse::BadRequest())
+                .body("Upload-Metadata must include a filename.")
+        }
+    };
+
+    // Validate the title to prevent injection attacks
+    let title = metadata.get("title").cloned().unwrap_or_default();
+    if title.contains('<') || title.contains('>') {
+        return tus_response(HttpResponse::BadRequest()).body("Invalid input detected");
+    }
+
+    remove_expired_uploads(pool.get_ref()).await;
+
+    let id = Uuid::new_v4().to_string();
+    if let Err(e) = fs::create_dir_all(TUS_UPLOADS_FOLDER)
+        .and_then(|_| fs::File::create(upload_file_path(&id)).map(|_| ()))
+    {
+        eprintln!("Failed to create tus upload file: {}", e);
+        return tus_response(HttpResponse::InternalServerError()).body("Error creating upload.");
+    }
+
+    if let Err(e) = sqlx::query(
+        "INSERT INTO tus_uploads (id, username, kind, title, filename, upload_length, upload_offset, updated_at)
+         VALUES (?, ?, ?, ?, ?, ?, 0, ?)",
+    )
+    .bind(&id)
+    .bind(&username)
+    .bind(&kind)
+    .bind(&title)
+    .bind(&filename)
+    .bind(upload_length as i64)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool.get_ref())
+    .await
+    {
+        let _ = fs::remove_file(upload_file_path(&id));
+        return tus_response(HttpResponse::InternalServerError())
+            .body(format!("Database error: {}", e));
+    }
+
+    tus_response(HttpResponse::Created())
+        .insert_header(("Location", format!("/tus/{}", id)))
+        .finish()
+}
+
+// HEAD /tus/{id}: report how much of the upload the server already has
+pub async fn upload_offset(
+    path: web::Path<String>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    if let Err(response) = check_version(&req) {
+        return response;
+    }
+
+    match find_upload(pool.get_ref(), &path.into_inner(), &username).await {
+        Ok(upload) => tus_response(HttpResponse::Ok())
+            .insert_header(("Upload-Offset", upload.upload_offset.to_string()))
+            .insert_header(("Upload-Length", upload.upload_length.to_string()))
+            .insert_header(("Cache-Control", "no-store"))
+            .finish(),
+        Err(response) => response,
+    }
+}
+
+// PATCH /tus/{id}: append the request body at Upload-Offset
+pub async fn append_chunk(
+    path: web::Path<String>,
+    req: HttpRequest,
+    mut payload: web::Payload,
+    pool: web::Data<SqlitePool>,
+    storage: web::Data<dyn Storage>,
+) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    if let Err(response) = check_version(&req) {
+        return response;
+    }
+
+    if header_str(req.headers(), "Content-Type") != Some("application/offset+octet-stream") {
+        return tus_response(HttpResponse::UnsupportedMediaType())
+            .body("Content-Type must be application/offset+octet-stream.");
+    }
+
+    // The offset is only read, and written to, while holding the upload
+    let id = path.into_inner();
+    let _lock = match UploadLock::acquire(&id) {
+        Some(lock) => lock,
+        None => return locked_response(),
+    };
+    let upload = match find_upload(pool.get_ref(), &id, &username).await {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+
+    // The client must resume exactly where the server left off
+    let offset = header_str(req.headers(), "Upload-Offset").and_then(|v| v.parse::<i64>().ok());
+    if offset != Some(upload.upload_offset) {
+        return tus_response(HttpResponse::Conflict())
+            .insert_header(("Upload-Offset", upload.upload_offset.to_string()))
+            .body("Upload-Offset does not match the current offset.");
+    }
+
+    let file_path = upload_file_path(&upload.id);
+    let mut file = match tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(&file_path)
+        .await
+    {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Failed to open tus upload file: {}", e);
+            return tus_response(HttpResponse::InternalServerError()).body("Error saving upload.");
+        }
+    };
+
+    // Write as much as arrives; if the connection drops, what was received is kept so the
+    // client can resume from there
+    let mut new_offset = upload.upload_offset;
+    let mut too_long = false;
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) => break,
+        };
+
+        if new_offset + chunk.len() as i64 > upload.upload_length {
+            too_long = true;
+            break;
+        }
+
+        if file.write_all(&chunk).await.is_err() {
+            break;
+        }
+        new_offset += chunk.len() as i64;
+    }
+
+    // Only count bytes that have reached the disk
+    if file.sync_all().await.is_err() {
+        new_offset = upload.upload_offset;
+        let _ = file.set_len(new_offset as u64).await;
+    }
+
+    if let Err(e) =
+        sqlx::query("UPDATE tus_uploads SET upload_offset = ?, updated_at = ? WHERE id = ?")
+            .bind(new_offset)
+            .bind(Utc::now().to_rfc3339())
+            .bind(&upload.id)
+            .execute(pool.get_ref())
+            .await
+    {
+        return tus_response(HttpResponse::InternalServerError())
+            .body(format!("Database error: {}", e));
+    }
+
+    if too_long {
+        return tus_response(HttpResponse::BadRequest())
+            .insert_header(("Upload-Offset", new_offset.to_string()))
+            .body("Upload exceeds the declared Upload-Length.");
+    }
+
+    if new_offset == upload.upload_length {
+        if let Err(response) = finish_upload(pool.get_ref(), &storage, &upload).await {
+            return response;
+        }
+    }
+
+    tus_response(HttpResponse::NoContent())
+        .insert_header(("Upload-Offset", new_offset.to_string()))
+        .finish()
+}
+
+// DELETE /tus/{id}: abandon an upload (termination extension)
+pub async fn terminate_upload(
+    path: web::Path<String>,
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    // Extract the username from the cookie
+    let username = match req.cookie("username") {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("User not authenticated"),
+    };
+
+    if let Err(response) = check_version(&req) {
+        return response;
+    }
+
+    let id = path.into_inner();
+    let _lock = match UploadLock::acquire(&id) {
+        Some(lock) => lock,
+        None => return locked_response(),
+    };
+    match find_upload(pool.get_ref(), &id, &username).await {
+        Ok(upload) => {
+            remove_upload(pool.get_ref(), &upload.id).await;
+            tus_response(HttpResponse::NoContent()).finish()
+        }
+        Err(response) => response,
+    }
+}
+
+// Hand a completed upload to the same flow the regular upload endpoints use
+async fn finish_upload(
+    pool: &SqlitePool,
+    storage: &web::Data<dyn Storage>,
+    upload: &TusUpload,
+) -> Result<(), HttpResponse> {
+    let file_path = upload_file_path(&upload.id);
+
+    // The row goes away either way; the file is cleaned up by TempUpload unless it is saved
+    if let Err(e) = sqlx::query("DELETE FROM tus_uploads WHERE id = ?")
+        .bind(&upload.id)
+        .execute(pool)
+        .await
+    {
+        return Err(tus_response(HttpResponse::InternalServerError())
+            .body(format!("Database error: {}", e)));
+    }
+    let received = TempUpload::from_path(file_path.clone());
+
+    let (kind, _) = upload_kind(&upload.kind).unwrap_or((MediaKind::Video, MAX_FILM_SIZE));
+
+    // Check the contents the same way streamed uploads are checked
+    let mut header = Vec::new();
+    if let Err(e) = fs::File::open(&file_path)
+        .and_then(|f| f.take(filetype::SNIFF_LEN as u64).read_to_end(&mut header))
+    {
+        eprintln!("Failed to read tus upload file: {}", e);
+        return Err(tus_response(HttpResponse::InternalServerError()).body("Error saving upload."));
+    }
+    if let Err(e) = filetype::validate(kind, &upload.filename, &header) {
+        return Err(tus_response(HttpResponse::BadRequest()).body(e));
+    }
+
+    // Films and audio are named after their timestamp. Claim one first so uploads finishing
+    // in the same second do not overwrite each other's metadata.
+    let claim_folder = match upload.kind.as_str() {
+        "film" => Some(format!("./user_pages/{}/films", upload.username)),
+        "audio" => Some(format!("./user_pages/{}/audios", upload.username)),
+        _ => None,
+    };
+    let timestamp = match claim_folder.as_deref().map(customize::claim_metadata_file) {
+        Some(Ok(timestamp)) => timestamp,
+        Some(Err(e)) => {
+            eprintln!("Failed to claim a timestamp for a tus upload: {}", e);
+            return Err(
+                tus_response(HttpResponse::InternalServerError()).body("Error saving upload.")
+            );
+        }
+        None => String::new(),
+    };
+
+    let result = match upload.kind.as_str() {
+        "film" => customize::save_film(
+            pool,
+            storage.get_ref(),
+            &upload.username,
+            &upload.title,
+            &upload.filename,
+            received,
+            &timestamp,
+            Vec::new(),
+        )
+        .await
+        .map(|()| {
+            customize::process_film_in_background(
+                pool.clone(),
+                storage.clone(),
+                upload.username.clone(),
+                timestamp.clone(),
+            )
+        }),
+        "audio" => {
+            customize::save_audio(
+                pool,
+                storage.get_ref(),
+                &upload.username,
+                &upload.title,
+                &upload.filename,
+                received,
+                &timestamp,
+            )
+            .await
+        }
+        _ => {
+            let metadata_settings = settings::load_metadata_settings(pool, &upload.username).await;
+            match customize::prepare_gallery_image(received, upload.filename.clone()).await {
+                Ok(image) => {
+                    customize::save_gallery(
+                        pool,
+                        storage.get_ref(),
+                        &upload.username,
+                        &upload.title,
+                        vec![image],
+                        &metadata_settings,
+                    )
+                    .await
+                }
+                Err(response) => Err(response),
+            }
+        }
+    };
+
+    // An upload that was not saved gives its claimed timestamp back
+    if let (Err(_), Some(folder)) = (&result, &claim_folder) {
+        customize::unclaim_metadata_file(folder, &timestamp);
+    }
+
+    result.map_err(|mut response| {
+        response.headers_mut().insert(
+            HeaderName::from_static("tus-resumable"),
+            HeaderValue::from_static(TUS_VERSION),
+        );
+        response
+    })
+}
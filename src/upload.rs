@@ -0,0 +1,131 @@
+use crate::filetype::{self, MediaKind, SNIFF_LEN};
+use actix_multipart::Field;
+use actix_web::HttpResponse;
+use futures::StreamExt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+// A file received into a temporary location next to its final destination. The file is
+// deleted when dropped unless it has been moved into place with `persist`, so a failed
+// upload never leaves a partial file behind.
+pub struct TempUpload {
+    path: PathBuf,
+    size: u64,
+    persisted: bool,
+}
+
+impl TempUpload {
+    // Take ownership of a file received some other way, such as a finished resumable upload
+    pub fn from_path(path: PathBuf) -> TempUpload {
+        TempUpload {
+            path,
+            size: 0,
+            persisted: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Atomically move the finished upload to its final path
+    pub fn persist(mut self, destination: &str) -> std::io::Result<()> {
+        fs::rename(&self.path, destination)?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempUpload {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+// Stream a multipart file field to a temporary file in `folder`, enforcing `max_size` and
+// the user's remaining storage quota as chunks arrive and checking the leading bytes
+// against the expected media kind.
+pub async fn receive_file(
+    field: &mut Field,
+    folder: &str,
+    filename: &str,
+    kind: MediaKind,
+    max_size: u64,
+    quota_remaining: u64,
+) -> Result<TempUpload, HttpResponse> {
+    let mut upload = TempUpload {
+        path: Path::new(folder).join(format!(".{}.upload", Uuid::new_v4())),
+        size: 0,
+        persisted: false,
+    };
+
+    let mut file = match tokio::fs::File::create(&upload.path).await {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Failed to create upload file: {}", e);
+            return Err(HttpResponse::InternalServerError().body("Error saving file."));
+        }
+    };
+
+    // Leading bytes are held back until there are enough of them to sniff the file type
+    let mut header = Vec::with_capacity(SNIFF_LEN);
+    let mut validated = false;
+
+    while let Some(chunk) = field.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) => return Err(HttpResponse::BadRequest().body("Upload was interrupted.")),
+        };
+
+        upload.size += chunk.len() as u64;
+        if upload.size > max_size {
+            return Err(HttpResponse::BadRequest().body(format!(
+                "File size too big (must be under {}MB).",
+                max_size / (1024 * 1024)
+            )));
+        }
+        if upload.size > quota_remaining {
+            return Err(HttpResponse::PayloadTooLarge().body("Storage quota exceeded."));
+        }
+
+        if !validated {
+            header.extend_from_slice(&chunk);
+            if header.len() < SNIFF_LEN {
+                continue;
+            }
+            if let Err(e) = filetype::validate(kind, filename, &header) {
+                return Err(HttpResponse::BadRequest().body(e));
+            }
+            validated = true;
+            if file.write_all(&header).await.is_err() {
+                return Err(HttpResponse::InternalServerError().body("Error saving file."));
+            }
+        } else if file.write_all(&chunk).await.is_err() {
+            return Err(HttpResponse::InternalServerError().body("Error saving file."));
+        }
+    }
+
+    // Files shorter than the sniffing window are checked once the field ends
+    if !validated {
+        if let Err(e) = filetype::validate(kind, filename, &header) {
+            return Err(HttpResponse::BadRequest().body(e));
+        }
+        if file.write_all(&header).await.is_err() {
+            return Err(HttpResponse::InternalServerError().body("Error saving file."));
+        }
+    }
+
+    if file.sync_all().await.is_err() {
+        return Err(HttpResponse::InternalServerError().body("Error saving file."));
+    }
+
+    Ok(upload)
+}
@@ -8,6 +8,21 @@ pub struct User {
     pub has_logged_in: bool,
 }
 
+// What a user tells others about themselves, kept in the profiles table. Empty fields are
+// NULL.
+#[derive(FromRow, Default)]
+pub struct Profile {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub pronouns: Option<String>,
+    pub location: Option<String>,
+    pub website: Option<String>,
+    // URL path of the square avatar image
+    pub avatar: Option<String>,
+    // Who may see the bio, pronouns, location and website, as stored by exhibits::Visibility
+    pub visibility: Option<String>,
+}
+
 pub fn is_valid_username(username: &str) -> bool {
     let re = Regex::new(r"^[a-zA-Z0-9_-]+$").unwrap();
     re.is_match(username)
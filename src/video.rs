@@ -0,0 +1,148 @@
+// Reads duration, dimensions and codec from uploaded video and grabs a poster frame, using
+// the ffprobe and ffmpeg binaries if they are installed. FFPROBE_PATH and FFMPEG_PATH
+// override where they are looked for.
+use serde::Deserialize;
+use std::env;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::{Command, Output};
+
+pub struct VideoDetails {
+    // Length in seconds
+    pub duration: Option<f64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub codec: Option<String>,
+}
+
+// The parts of `ffprobe -print_format json -show_format -show_streams` we use
+#[derive(Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    duration: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+}
+
+// Widest poster frame saved; larger videos are scaled down
+const POSTER_MAX_WIDTH: u32 = 1280;
+
+pub enum ToolError {
+    // The tool is not installed, so there is nothing to do
+    Missing(String),
+    Failed(String),
+}
+
+impl std::fmt::Display for ToolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ToolError::Missing(tool) => write!(f, "{} is not installed", tool),
+            ToolError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+fn run(tool: &str, env_var: &str, args: &[&std::ffi::OsStr]) -> Result<Output, ToolError> {
+    let program = env::var(env_var).unwrap_or_else(|_| tool.to_string());
+    let output = Command::new(&program)
+        .args(args)
+        .output()
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => ToolError::Missing(program.clone()),
+            _ => ToolError::Failed(format!("Failed to run {}: {}", program, e)),
+        })?;
+
+    if !output.status.success() {
+        return Err(ToolError::Failed(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output)
+}
+
+pub fn probe(path: &Path) -> Result<VideoDetails, ToolError> {
+    let output = run(
+        "ffprobe",
+        "FFPROBE_PATH",
+        &[
+            "-v".as_ref(),
+            "error".as_ref(),
+            "-print_format".as_ref(),
+            "json".as_ref(),
+            "-show_format".as_ref(),
+            "-show_streams".as_ref(),
+            path.as_os_str(),
+        ],
+    )?;
+    let probed: ProbeOutput = serde_json::from_slice(&output.stdout)
+        .map_err(|e| ToolError::Failed(format!("Unreadable ffprobe output: {}", e)))?;
+
+    let video = probed
+        .streams
+        .iter()
+        .find(|stream| stream.codec_type.as_deref() == Some("video"))
+        .ok_or_else(|| ToolError::Failed("No video stream found.".to_string()))?;
+
+    // Containers usually know the duration; fall back to the video stream's
+    let duration = probed
+        .format
+        .and_then(|format| format.duration)
+        .or_else(|| video.duration.clone())
+        .and_then(|duration| duration.parse::<f64>().ok())
+        .filter(|duration| duration.is_finite() && *duration > 0.0);
+
+    Ok(VideoDetails {
+        duration,
+        width: video.width,
+        height: video.height,
+        codec: video.codec_name.clone(),
+    })
+}
+
+pub fn ffmpeg(args: &[&std::ffi::OsStr]) -> Result<Output, ToolError> {
+    run("ffmpeg", "FFMPEG_PATH", args)
+}
+
+// Save the frame at `seconds` into the video as a JPEG at `output`
+pub fn extract_poster(path: &Path, seconds: f64, output: &Path) -> Result<(), ToolError> {
+    let seek = format!("{:.3}", seconds);
+    let scale = format!("scale='min({},iw)':-2", POSTER_MAX_WIDTH);
+    ffmpeg(&[
+        "-v".as_ref(),
+        "error".as_ref(),
+        "-y".as_ref(),
+        "-ss".as_ref(),
+        seek.as_ref(),
+        "-i".as_ref(),
+        path.as_os_str(),
+        "-frames:v".as_ref(),
+        "1".as_ref(),
+        "-vf".as_ref(),
+        scale.as_ref(),
+        "-f".as_ref(),
+        "image2".as_ref(),
+        output.as_os_str(),
+    ])?;
+
+    match std::fs::metadata(output) {
+        Ok(metadata) if metadata.len() > 0 => Ok(()),
+        _ => Err(ToolError::Failed(
+            "ffmpeg produced no poster frame.".to_string(),
+        )),
+    }
+}
@@ -88,6 +88,14 @@ async function loginUser() {
   }
 }
 
+// The Content-Security-Policy blocks inline event handlers, so buttons are bound here
+document.querySelectorAll('[data-form]').forEach((button) => {
+  button.addEventListener('click', () => toggleForm(button.dataset.form));
+});
+document.querySelectorAll('[data-click]').forEach((button) => {
+  button.addEventListener('click', () => window[button.dataset.click]());
+});
+
 // Canvas Background Animation
 const canvas = document.getElementById('backgroundCanvas');
 const ctx = canvas.getContext('2d');
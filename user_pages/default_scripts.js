@@ -1,6 +1,6 @@
 async function generateInvite() {
   const response = await fetch('/generate_invite', {
-    method: 'GET',
+    method: 'POST',
     credentials: 'include',
   });
 
@@ -25,6 +25,7 @@ function showFriendsSidebar() {
 
   // Fetch and display friends
   fetchFriends();
+  fetchFeedSubscribers();
 }
 
 function closeFriendsSidebar() {
@@ -58,16 +59,130 @@ function displayFriends(friends) {
   const friendsList = document.getElementById('friendsList');
   friendsList.innerHTML = ''; // Clear existing list
 
-  friends.forEach((friend) => {
+  friends.forEach(({ username: friend, display_name: displayName, avatar }) => {
     const friendItem = document.createElement('li');
+    if (avatar) {
+      const avatarImage = document.createElement('img');
+      avatarImage.src = mediaUrl(`${avatar}?w=48&h=48&fit=cover`);
+      avatarImage.alt = '';
+      avatarImage.classList.add('friend-avatar');
+      friendItem.appendChild(avatarImage);
+    }
     const friendLink = document.createElement('a');
     friendLink.href = `/user_pages/${friend}/my_page.html`;
-    friendLink.textContent = friend;
+    friendLink.textContent = displayName ? `${displayName} (${friend})` : friend;
     friendItem.appendChild(friendLink);
+
+    const podcastButton = document.createElement('button');
+    podcastButton.textContent = 'Podcast';
+    podcastButton.classList.add('feed-button');
+    podcastButton.addEventListener('click', () => showFeedLink(friend, 'podcast'));
+    friendItem.appendChild(podcastButton);
+
+    const atomButton = document.createElement('button');
+    atomButton.textContent = 'Atom';
+    atomButton.classList.add('feed-button');
+    atomButton.addEventListener('click', () => showFeedLink(friend, 'atom'));
+    friendItem.appendChild(atomButton);
+
     friendsList.appendChild(friendItem);
   });
 }
 
+// Show the private feed URL for a friend's exhibit so it can be pasted into a podcast app
+// or feed reader. The URL contains a token unique to this subscriber.
+async function showFeedLink(username, feed) {
+  try {
+    const response = await fetch('/feed_token', {
+      method: 'POST',
+      headers: {
+        'Content-Type': 'application/json',
+      },
+      body: JSON.stringify({ username, feed }),
+      credentials: 'include',
+    });
+
+    if (!response.ok) {
+      const errorText = await response.text();
+      throw new Error(errorText);
+    }
+
+    const data = await response.json();
+    prompt('Copy this private feed URL:', window.location.origin + data.url);
+  } catch (error) {
+    alert('Error getting feed link: ' + error.message);
+  }
+}
+
+// The open feed of this page's public items needs no token
+function showPublicFeedLink() {
+  const username = window.location.pathname.split('/')[2];
+  prompt('Copy this public feed URL:', `${window.location.origin}/atom/${username}`);
+}
+
+async function fetchFeedSubscribers() {
+  try {
+    const response = await fetch('/feed_tokens', {
+      method: 'GET',
+      credentials: 'include',
+    });
+
+    if (!response.ok) {
+      const errorText = await response.text();
+      throw new Error(errorText);
+    }
+
+    const subscriptions = await response.json();
+    displayFeedSubscribers(subscriptions);
+  } catch (error) {
+    alert('Error fetching feed subscribers: ' + error.message);
+  }
+}
+
+function displayFeedSubscribers(subscriptions) {
+  const subscribersList = document.getElementById('feedSubscribers');
+  subscribersList.innerHTML = '';
+
+  subscriptions.forEach((subscription) => {
+    const subscriberItem = document.createElement('li');
+    subscriberItem.textContent = `${subscription.subscriber} (${subscription.feed})`;
+
+    const revokeButton = document.createElement('button');
+    revokeButton.textContent = 'Revoke';
+    revokeButton.classList.add('feed-button');
+    revokeButton.addEventListener('click', () => revokeFeedToken(subscription));
+    subscriberItem.appendChild(revokeButton);
+
+    subscribersList.appendChild(subscriberItem);
+  });
+}
+
+async function revokeFeedToken(subscription) {
+  if (!confirm(`Revoke ${subscription.subscriber}'s ${subscription.feed} feed link?`)) {
+    return;
+  }
+
+  try {
+    const response = await fetch('/revoke_feed_token', {
+      method: 'POST',
+      headers: {
+        'Content-Type': 'application/json',
+      },
+      body: JSON.stringify({ subscriber: subscription.subscriber, feed: subscription.feed }),
+      credentials: 'include',
+    });
+
+    if (!response.ok) {
+      const errorText = await response.text();
+      throw new Error(errorText);
+    }
+
+    fetchFeedSubscribers();
+  } catch (error) {
+    alert('Error revoking feed link: ' + error.message);
+  }
+}
+
 function toggleFriendForm() {
   const addFriendButton = document.getElementById('addFriendButton');
   const friendLinkInput = document.getElementById('friendLinkInput');
@@ -76,7 +191,8 @@ function toggleFriendForm() {
   const showFriendsButton = document.getElementById('showFriendsButton');
 
   // Toggle visibility and animate the buttons
-  if (friendLinkInput.style.display === 'none') {
+  // Hidden by the stylesheet until first shown
+  if (friendLinkInput.style.display !== 'inline-block') {
     friendLinkInput.style.display = 'inline-block';
     submitFriendButton.style.display = 'inline-block';
     showFriendsButton.style.display = 'inline-block';
@@ -166,201 +282,1293 @@ async function uploadFilm() {
     return;
   }
 
-  // Create a FormData object
-  const formData = new FormData();
-  formData.append('filmTitle', filmTitle);
-  formData.append('video', file);
-
+  const fileNameElement = document.getElementById('film-file-name');
+
+  try {
+    // Films go through the resumable endpoint so a dropped connection doesn't restart the upload
+    await resumableUpload(file, { type: 'film', title: filmTitle, filename: file.name }, (percent) => {
+      fileNameElement.textContent = `Uploading: ${percent}%`;
+    });
+
+    alert('Film uploaded successfully!');
+    // Close the form
+    hideFilmForm();
+    // Clear the form
+    document.getElementById('film-title').value = '';
+    fileInput.value = '';
+    fileNameElement.textContent = 'No file selected';
+    // Refresh the films display
+    fetchAllContent();
+  } catch (error) {
+    fileNameElement.textContent = `Selected: ${file.name}`;
+    alert('Error uploading film: ' + error.message);
+  }
+}
+
+// Where to load a media file of this page's owner from: the media origin when the server has
+// one, with the viewer's grant for it, or this site. HLS playlists always come from this site.
+function mediaUrl(path) {
+  const { mediaOrigin, mediaGrant } = document.body.dataset;
+  if (!mediaOrigin || !path.startsWith('/user_pages/')) {
+    return path;
+  }
+  if (!mediaGrant) {
+    return mediaOrigin + path;
+  }
+  const separator = path.includes('?') ? '&' : '?';
+  return `${mediaOrigin}${path}${separator}grant=${encodeURIComponent(mediaGrant)}`;
+}
+
+// Stream the HLS renditions when the server has made them and the browser can play them
+// (natively, or with hls.js); otherwise play the original file
+function attachFilmSource(videoElement, film) {
+  if (film.hls && videoElement.canPlayType('application/vnd.apple.mpegurl')) {
+    videoElement.src = film.hls;
+  } else if (film.hls && window.Hls && Hls.isSupported()) {
+    const hls = new Hls();
+    hls.on(Hls.Events.ERROR, (event, data) => {
+      if (data.fatal) {
+        hls.destroy();
+        videoElement.src = mediaUrl(film.video_path);
+      }
+    });
+    hls.loadSource(film.hls);
+    hls.attachMedia(videoElement);
+  } else {
+    videoElement.src = mediaUrl(film.video_path);
+  }
+}
+
+function appendCaptionTracks(videoElement, film) {
+  // Captions from the media origin are fetched with CORS
+  if (document.body.dataset.mediaOrigin && (film.captions || []).length > 0) {
+    videoElement.crossOrigin = 'anonymous';
+  }
+  (film.captions || []).forEach((caption) => {
+    const track = document.createElement('track');
+    track.kind = 'subtitles';
+    track.src = mediaUrl(caption.path);
+    track.srclang = caption.language;
+    track.label = caption.label;
+    videoElement.appendChild(track);
+  });
+}
+
+// Attach a WebVTT or SRT caption file to a film that has already been uploaded
+function addCaptions(film) {
+  const fileInput = document.createElement('input');
+  fileInput.type = 'file';
+  fileInput.accept = '.vtt, .srt';
+  fileInput.addEventListener('change', async () => {
+    const file = fileInput.files[0];
+    if (!file) {
+      return;
+    }
+
+    const language = prompt('Caption language (e.g. en, fr, pt-BR):');
+    if (!language) {
+      return;
+    }
+    const label = prompt('Label shown in the player:', language) || '';
+
+    const formData = new FormData();
+    formData.append('timestamp', film.timestamp);
+    formData.append('captionLanguage', language.trim());
+    formData.append('captionLabel', label.trim());
+    formData.append('captions', file);
+
+    try {
+      const response = await fetch('/upload_captions', {
+        method: 'POST',
+        body: formData,
+        credentials: 'include',
+      });
+
+      if (!response.ok) {
+        const errorText = await response.text();
+        throw new Error(errorText);
+      }
+
+      fetchAllContent();
+    } catch (error) {
+      alert('Error uploading captions: ' + error.message);
+    }
+  });
+  fileInput.click();
+}
+
+// Size of each PATCH sent to the resumable (tus) upload endpoint
+const TUS_CHUNK_SIZE = 5 * 1024 * 1024;
+
+function encodeTusMetadata(metadata) {
+  return Object.entries(metadata)
+    .map(([key, value]) => `${key} ${btoa(unescape(encodeURIComponent(value)))}`)
+    .join(',');
+}
+
+// Ask the server how much of an upload it already has, or null if it doesn't know it
+async function fetchTusOffset(uploadUrl) {
+  try {
+    const response = await fetch(uploadUrl, {
+      method: 'HEAD',
+      credentials: 'include',
+      headers: { 'Tus-Resumable': '1.0.0' },
+    });
+    return response.ok ? parseInt(response.headers.get('Upload-Offset'), 10) : null;
+  } catch (error) {
+    return null;
+  }
+}
+
+async function resumableUpload(file, metadata, onProgress) {
+  // Remember the upload URL so that retrying the same file resumes instead of restarting
+  const storageKey = `tus:${metadata.type}:${file.name}:${file.size}:${file.lastModified}`;
+  let uploadUrl = localStorage.getItem(storageKey);
+  let offset = uploadUrl ? await fetchTusOffset(uploadUrl) : null;
+
+  if (offset === null) {
+    const response = await fetch('/tus', {
+      method: 'POST',
+      credentials: 'include',
+      headers: {
+        'Tus-Resumable': '1.0.0',
+        'Upload-Length': file.size,
+        'Upload-Metadata': encodeTusMetadata(metadata),
+      },
+    });
+
+    if (!response.ok) {
+      throw new Error(await response.text());
+    }
+
+    uploadUrl = response.headers.get('Location');
+    localStorage.setItem(storageKey, uploadUrl);
+    offset = 0;
+  }
+
+  let retries = 0;
+  while (offset < file.size) {
+    onProgress(Math.floor((offset / file.size) * 100));
+
+    let response;
+    try {
+      response = await fetch(uploadUrl, {
+        method: 'PATCH',
+        credentials: 'include',
+        headers: {
+          'Tus-Resumable': '1.0.0',
+          'Upload-Offset': offset,
+          'Content-Type': 'application/offset+octet-stream',
+        },
+        body: file.slice(offset, offset + TUS_CHUNK_SIZE),
+      });
+    } catch (error) {
+      // Connection trouble: wait, ask the server where it got to and carry on from there
+      if (++retries > 5) {
+        throw error;
+      }
+      await new Promise((resolve) => setTimeout(resolve, 2000 * retries));
+      offset = (await fetchTusOffset(uploadUrl)) ?? offset;
+      continue;
+    }
+
+    // A 409 means the offsets disagree; the response tells us the server's offset
+    if (!response.ok && response.status !== 409) {
+      localStorage.removeItem(storageKey);
+      throw new Error(await response.text());
+    }
+
+    offset = parseInt(response.headers.get('Upload-Offset'), 10);
+    retries = 0;
+  }
+
+  localStorage.removeItem(storageKey);
+  onProgress(100);
+}
+
+async function fetchFilms() {
+  try {
+    const response = await fetch('/get_films', {
+      method: 'GET',
+      credentials: 'include',
+    });
+
+    if (!response.ok) {
+      const errorText = await response.text();
+      alert('Error fetching films: ' + errorText);
+    } else {
+      const films = await response.json();
+      displayFilms(films);
+    }
+  } catch (error) {
+    alert('Error fetching films: ' + error.message);
+  }
+}
+
+function displayFilms(films) {
+  const filmsDiv = document.getElementById('films');
+  filmsDiv.innerHTML = '';
+
+  films.forEach((film) => {
+    const filmSection = document.createElement('section');
+    const filmTitle = document.createElement('h3');
+    filmTitle.textContent = film.title;
+    filmSection.appendChild(filmTitle);
+
+    const videoElement = document.createElement('video');
+    attachFilmSource(videoElement, film);
+    videoElement.controls = true;
+    videoElement.preload = 'metadata';
+    if (film.poster) {
+      videoElement.poster = mediaUrl(film.poster);
+    }
+    appendCaptionTracks(videoElement, film);
+    filmSection.appendChild(videoElement);
+    appendFilmDetails(filmSection, film);
+
+    filmsDiv.appendChild(filmSection);
+  });
+}
+
+function showAudioForm() {
+  document.getElementById('audioForm').classList.add('show');
+  document.getElementById('showAudioButton').classList.add('active');
+}
+
+function hideAudioForm() {
+  document.getElementById('audioForm').classList.remove('show');
+  document.getElementById('showAudioButton').classList.remove('active');
+}
+
+document.getElementById('audio-file').addEventListener('change', function() {
+  const file = this.files[0];
+  const fileNameElement = document.getElementById('audio-file-name');
+  if (file) {
+    fileNameElement.textContent = `Selected: ${file.name}`;
+  } else {
+    fileNameElement.textContent = 'No file selected';
+  }
+});
+
+async function uploadAudio() {
+  const audioTitle = document.getElementById('audio-title').value.trim();
+  const fileInput = document.getElementById('audio-file');
+  const file = fileInput.files[0];
+
+  if (!audioTitle) {
+    alert('Please enter an audio title.');
+    return;
+  }
+
+  if (!file) {
+    alert('Please select an audio file.');
+    return;
+  }
+
+  // Check file size (max 50MB)
+  if (file.size > 50 * 1024 * 1024) {
+    alert(`File "${file.name}" is too big (must be under 50MB).`);
+    return;
+  }
+
+  // Create a FormData object
+  const formData = new FormData();
+  formData.append('audioTitle', audioTitle);
+  formData.append('audio', file);
+
+  try {
+    const response = await fetch('/upload_audio', {
+      method: 'POST',
+      credentials: 'include',
+      body: formData,
+    });
+
+    if (!response.ok) {
+      const errorText = await response.text();
+      alert('Error uploading audio: ' + errorText);
+    } else {
+      alert('Audio uploaded successfully!');
+      // Close the form
+      hideAudioForm();
+      // Clear the form
+      document.getElementById('audio-title').value = '';
+      fileInput.value = '';
+      document.getElementById('audio-file-name').textContent = 'No file selected';
+      // Refresh the audios display
+      fetchAllContent();
+    }
+  } catch (error) {
+    alert('Error uploading audio: ' + error.message);
+  }
+}
+
+function showAlbumForm() {
+  document.getElementById('albumForm').classList.add('show');
+  document.getElementById('showAlbumButton').classList.add('active');
+}
+
+function hideAlbumForm() {
+  document.getElementById('albumForm').classList.remove('show');
+  document.getElementById('showAlbumButton').classList.remove('active');
+}
+
+document.getElementById('album-tracks').addEventListener('change', function() {
+  const trackCount = document.getElementById('album-track-count');
+  trackCount.textContent =
+    this.files.length > 0 ? `${this.files.length} track(s) selected` : 'No tracks selected';
+});
+
+async function uploadAlbum() {
+  const albumTitle = document.getElementById('album-title').value.trim();
+  const description = document.getElementById('album-description').value.trim();
+  const coverInput = document.getElementById('album-cover');
+  const tracksInput = document.getElementById('album-tracks');
+
+  if (!albumTitle) {
+    alert('Please enter an album title.');
+    return;
+  }
+
+  if (tracksInput.files.length === 0) {
+    alert('Please select at least one track.');
+    return;
+  }
+
+  for (const file of tracksInput.files) {
+    if (file.size > 50 * 1024 * 1024) {
+      alert(`File "${file.name}" is too big (must be under 50MB).`);
+      return;
+    }
+  }
+
+  const formData = new FormData();
+  formData.append('albumTitle', albumTitle);
+  formData.append('description', description);
+  if (coverInput.files[0]) {
+    formData.append('cover', coverInput.files[0]);
+  }
+  for (const file of tracksInput.files) {
+    formData.append('tracks', file);
+  }
+
+  try {
+    const response = await fetch('/upload_album', {
+      method: 'POST',
+      credentials: 'include',
+      body: formData,
+    });
+
+    if (!response.ok) {
+      const errorText = await response.text();
+      alert('Error uploading album: ' + errorText);
+    } else {
+      alert('Album uploaded successfully!');
+      hideAlbumForm();
+      document.getElementById('album-title').value = '';
+      document.getElementById('album-description').value = '';
+      coverInput.value = '';
+      tracksInput.value = '';
+      document.getElementById('album-track-count').textContent = 'No tracks selected';
+      fetchAllContent();
+    }
+  } catch (error) {
+    alert('Error uploading album: ' + error.message);
+  }
+}
+
+// Move a track up or down within an album
+async function moveAlbumTrack(album, index, offset) {
+  const order = album.tracks.map((_, i) => i);
+  const target = index + offset;
+  if (target < 0 || target >= order.length) {
+    return;
+  }
+  [order[index], order[target]] = [order[target], order[index]];
+
+  try {
+    const response = await fetch('/reorder_album', {
+      method: 'POST',
+      headers: {
+        'Content-Type': 'application/json',
+      },
+      body: JSON.stringify({ timestamp: album.timestamp, order }),
+      credentials: 'include',
+    });
+
+    if (!response.ok) {
+      const errorText = await response.text();
+      throw new Error(errorText);
+    }
+
+    fetchAllContent();
+  } catch (error) {
+    alert('Error reordering album: ' + error.message);
+  }
+}
+
+// An album's cover, description and track list. Each track plays on into the next.
+function appendAlbum(section, album) {
+  const cover = album.cover_art || (album.tracks.find((track) => track.cover_art) || {}).cover_art;
+  if (cover) {
+    appendAudioDetails(section, { cover_art: cover });
+  }
+
+  if (album.description) {
+    const description = document.createElement('p');
+    description.textContent = album.description;
+    section.appendChild(description);
+  }
+
+  const trackList = document.createElement('ol');
+  trackList.classList.add('album-tracks');
+  const players = [];
+
+  album.tracks.forEach((track, index) => {
+    const trackItem = document.createElement('li');
+
+    const trackTitle = document.createElement('span');
+    trackTitle.textContent = track.title;
+    trackItem.appendChild(trackTitle);
+
+    const upButton = document.createElement('button');
+    upButton.textContent = '↑';
+    upButton.addEventListener('click', () => moveAlbumTrack(album, index, -1));
+    trackItem.appendChild(upButton);
+
+    const downButton = document.createElement('button');
+    downButton.textContent = '↓';
+    downButton.addEventListener('click', () => moveAlbumTrack(album, index, 1));
+    trackItem.appendChild(downButton);
+
+    appendAudioDetails(trackItem, { ...track, cover_art: null });
+
+    const audioElement = document.createElement('audio');
+    audioElement.src = mediaUrl(track.audio_path);
+    audioElement.controls = true;
+    audioElement.preload = 'metadata';
+    trackItem.appendChild(audioElement);
+    players.push(audioElement);
+
+    if (track.waveform) {
+      trackItem.appendChild(createWaveform(audioElement, track));
+    }
+
+    trackList.appendChild(trackItem);
+  });
+
+  players.forEach((player, index) => {
+    player.addEventListener('ended', () => {
+      if (players[index + 1]) {
+        players[index + 1].play();
+      }
+    });
+  });
+
+  section.appendChild(trackList);
+}
+
+async function fetchAudios() {
+  try {
+    const response = await fetch('/get_audios', {
+      method: 'GET',
+      credentials: 'include',
+    });
+
+    if (!response.ok) {
+      const errorText = await response.text();
+      alert('Error fetching audios: ' + errorText);
+    } else {
+      const audios = await response.json();
+      displayAudios(audios);
+    }
+  } catch (error) {
+    alert('Error fetching audios: ' + error.message);
+  }
+}
+
+function displayAudios(audios) {
+  const audiosDiv = document.getElementById('audios');
+  audiosDiv.innerHTML = '';
+
+  audios.forEach((audio) => {
+    const audioSection = document.createElement('section');
+    const audioTitle = document.createElement('h3');
+    audioTitle.textContent = audio.title;
+    audioSection.appendChild(audioTitle);
+
+    appendAudioDetails(audioSection, audio);
+
+    const audioElement = document.createElement('audio');
+    audioElement.src = mediaUrl(audio.audio_path);
+    audioElement.controls = true;
+    audioElement.preload = 'metadata';
+    audioSection.appendChild(audioElement);
+
+    if (audio.waveform) {
+      audioSection.appendChild(createWaveform(audioElement, audio));
+    }
+
+    audiosDiv.appendChild(audioSection);
+  });
+}
+
+// Draw the track's waveform, shading the part already played. Clicking it seeks the player,
+// which only fetches the part of the file it needs.
+function createWaveform(audioElement, audio) {
+  const canvas = document.createElement('canvas');
+  canvas.classList.add('waveform');
+  canvas.width = 600;
+  canvas.height = 80;
+
+  // The finest resolution that still leaves each peak at least a pixel wide
+  const peaks =
+    audio.waveform.filter((resolution) => resolution.length <= canvas.width).pop() ||
+    audio.waveform[0];
+
+  const duration = () => audioElement.duration || audio.duration || 0;
+
+  const draw = () => {
+    const context = canvas.getContext('2d');
+    const progress = duration() ? audioElement.currentTime / duration() : 0;
+    const barWidth = canvas.width / peaks.length;
+
+    context.clearRect(0, 0, canvas.width, canvas.height);
+    peaks.forEach((peak, i) => {
+      const height = Math.max(1, (peak / 255) * canvas.height);
+      context.fillStyle = i / peaks.length < progress ? '#ff00ff' : '#00ffea';
+      context.fillRect(i * barWidth, (canvas.height - height) / 2, Math.max(1, barWidth - 1), height);
+    });
+  };
+
+  canvas.addEventListener('click', (event) => {
+    const rect = canvas.getBoundingClientRect();
+    const fraction = (event.clientX - rect.left) / rect.width;
+    audioElement.currentTime = fraction * duration();
+    draw();
+  });
+  audioElement.addEventListener('timeupdate', draw);
+
+  draw();
+  return canvas;
+}
+
+function formatDuration(seconds) {
+  const minutes = Math.floor(seconds / 60);
+  const rest = Math.floor(seconds % 60);
+  return `${minutes}:${rest.toString().padStart(2, '0')}`;
+}
+
+// Cover art and the details read from the file's tags
+function appendAudioDetails(section, audio) {
+  if (audio.cover_art) {
+    const cover = document.createElement('img');
+    cover.src = mediaUrl(`${audio.cover_art}?w=200&h=200&fit=cover`);
+    cover.alt = 'Cover art';
+    cover.classList.add('cover-art');
+    section.appendChild(cover);
+  }
+
+  const parts = [];
+  if (audio.artist) parts.push(audio.artist);
+  if (audio.album) {
+    parts.push(audio.track_number ? `${audio.album} (track ${audio.track_number})` : audio.album);
+  }
+  if (audio.duration) parts.push(formatDuration(audio.duration));
+
+  if (parts.length > 0) {
+    const details = document.createElement('p');
+    details.classList.add('audio-details');
+    details.textContent = parts.join(' · ');
+    section.appendChild(details);
+  }
+}
+
+// Duration, resolution and codec, when the server could read them
+function appendFilmDetails(section, film) {
+  const parts = [];
+  if (film.duration) parts.push(formatDuration(film.duration));
+  if (film.width && film.height) parts.push(`${film.width}×${film.height}`);
+  if (film.video_codec) parts.push(film.video_codec.toUpperCase());
+
+  if (parts.length > 0) {
+    const details = document.createElement('p');
+    details.classList.add('audio-details');
+    details.textContent = parts.join(' · ');
+    section.appendChild(details);
+  }
+}
+
+function editPage() {
+  const sidebar = document.getElementById('editSidebar');
+  const overlay = document.getElementById('overlay');
+  sidebar.classList.add('show');
+  overlay.classList.add('show');
+
+  // Populate the input fields with current titles
+  const exhibitTitleInput = document.getElementById('edit-exhibit-title');
+  const mainTitleInput = document.getElementById('edit-main-title');
+
+  // Get current titles from the page
+  const exhibitTitle = document.querySelector('header h1');
+  // The intro section holding the main title may be hidden
+  const mainTitle = document.getElementById('main-title');
+
+  exhibitTitleInput.value = exhibitTitle.textContent;
+  if (mainTitle) {
+    mainTitleInput.value = mainTitle.textContent;
+  }
+
+  fetchThemes();
+  fetchProfile();
+  fetchExhibitPages();
+  fetchPageLayout();
+  fetchCustomCss();
+  fetchPageSnapshots();
+  fetchStorageUsage();
+}
+
+async function fetchProfile() {
+  const owner = document.body.dataset.owner;
+  try {
+    const response = await fetch(`/profile/${encodeURIComponent(owner)}`, {
+      method: 'GET',
+      credentials: 'include',
+    });
+    if (!response.ok) {
+      throw new Error(await response.text());
+    }
+    displayProfile(await response.json());
+  } catch (error) {
+    alert('Error fetching profile: ' + error.message);
+  }
+}
+
+function displayProfile(profile) {
+  document.getElementById('profile-display-name').value = profile.display_name || '';
+  document.getElementById('profile-pronouns').value = profile.pronouns || '';
+  document.getElementById('profile-location').value = profile.location || '';
+  document.getElementById('profile-website').value = profile.website || '';
+  document.getElementById('profile-bio').value = profile.bio || '';
+  document.getElementById('profile-visibility').value = profile.visibility || 'friends';
+  displayAvatar(profile.avatar);
+}
+
+function displayAvatar(avatar) {
+  const avatarImage = document.getElementById('profile-avatar');
+  avatarImage.hidden = !avatar;
+  if (avatar) {
+    avatarImage.src = mediaUrl(`${avatar}?w=128&h=128&fit=cover`);
+  } else {
+    avatarImage.removeAttribute('src');
+  }
+}
+
+async function saveProfile() {
+  const profile = {
+    display_name: document.getElementById('profile-display-name').value,
+    pronouns: document.getElementById('profile-pronouns').value,
+    location: document.getElementById('profile-location').value,
+    website: document.getElementById('profile-website').value,
+    bio: document.getElementById('profile-bio').value,
+    visibility: document.getElementById('profile-visibility').value,
+  };
+  try {
+    const response = await fetch('/profile', {
+      method: 'POST',
+      credentials: 'include',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify(profile)
+    });
+    if (!response.ok) {
+      throw new Error(await response.text());
+    }
+    alert('Profile saved.');
+  } catch (error) {
+    alert('Error saving profile: ' + error.message);
+  }
+}
+
+async function uploadAvatar() {
+  const file = document.getElementById('avatar-file').files[0];
+  if (!file) {
+    alert('Please choose an image.');
+    return;
+  }
+  if (file.size > 10 * 1024 * 1024) {
+    alert('Image is too big (must be under 10MB).');
+    return;
+  }
+
+  const formData = new FormData();
+  formData.append('avatar', file);
+  try {
+    const response = await fetch('/upload_avatar', {
+      method: 'POST',
+      credentials: 'include',
+      body: formData,
+    });
+    if (!response.ok) {
+      throw new Error(await response.text());
+    }
+    const { avatar } = await response.json();
+    displayAvatar(avatar);
+    document.getElementById('avatar-file').value = '';
+  } catch (error) {
+    alert('Error uploading avatar: ' + error.message);
+  }
+}
+
+async function removeAvatar() {
+  try {
+    const response = await fetch('/remove_avatar', {
+      method: 'POST',
+      credentials: 'include',
+    });
+    if (!response.ok) {
+      throw new Error(await response.text());
+    }
+    displayAvatar(null);
+  } catch (error) {
+    alert('Error removing avatar: ' + error.message);
+  }
+}
+
+const PAGE_VISIBILITY_LABELS = {
+  private: 'Only me',
+  friends: 'Friends',
+  public: 'Everyone',
+};
+
+async function fetchExhibitPages() {
+  try {
+    const [pagesResponse, contentResponse] = await Promise.all([
+      fetch('/exhibit_pages', { method: 'GET', credentials: 'include' }),
+      fetch('/get_all_content', { method: 'GET', credentials: 'include' }),
+    ]);
+    if (pagesResponse.ok && contentResponse.ok) {
+      displayExhibitPages(await pagesResponse.json(), await contentResponse.json());
+    }
+  } catch (error) {
+    console.error('Error fetching pages:', error);
+  }
+}
+
+function displayExhibitPages(pages, allContent) {
+  const list = document.getElementById('exhibitPages');
+  list.innerHTML = '';
+
+  pages.forEach(page => {
+    const item = document.createElement('li');
+
+    const link = document.createElement('a');
+    link.href = `/user_pages/${encodeURIComponent(document.body.dataset.owner)}/${page.slug}`;
+    link.textContent = page.title;
+    item.appendChild(link);
+
+    const title = document.createElement('input');
+    title.type = 'text';
+    title.value = page.title;
+    item.appendChild(title);
+
+    const visibility = document.createElement('select');
+    Object.entries(PAGE_VISIBILITY_LABELS).forEach(([value, label]) => {
+      const option = document.createElement('option');
+      option.value = value;
+      option.textContent = label;
+      option.selected = value === page.visibility;
+      visibility.appendChild(option);
+    });
+    item.appendChild(visibility);
+
+    // The content items shown on the page
+    const items = document.createElement('select');
+    items.multiple = true;
+    allContent.forEach(content => {
+      const option = document.createElement('option');
+      option.value = `${content.type}/${content.timestamp}`;
+      option.textContent = `${content.type}: ${content.title}`;
+      option.selected = page.items.includes(option.value);
+      items.appendChild(option);
+    });
+    item.appendChild(items);
+
+    const saveButton = document.createElement('button');
+    saveButton.textContent = 'Save';
+    saveButton.addEventListener('click', () => updateExhibitPage({
+      slug: page.slug,
+      title: title.value.trim(),
+      visibility: visibility.value,
+      items: Array.from(items.selectedOptions).map(option => option.value),
+    }));
+    item.appendChild(saveButton);
+
+    const deleteButton = document.createElement('button');
+    deleteButton.textContent = 'Delete';
+    deleteButton.addEventListener('click', () => deleteExhibitPage(page));
+    item.appendChild(deleteButton);
+
+    list.appendChild(item);
+  });
+}
+
+async function sendExhibitPageRequest(url, body) {
+  try {
+    const response = await fetch(url, {
+      method: 'POST',
+      credentials: 'include',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify(body)
+    });
+
+    if (!response.ok) {
+      const errorText = await response.text();
+      alert('Error saving page: ' + errorText);
+      return false;
+    }
+    fetchExhibitPages();
+    return true;
+  } catch (error) {
+    alert('Error saving page: ' + error.message);
+    return false;
+  }
+}
+
+async function createExhibitPage() {
+  const titleInput = document.getElementById('new-page-title');
+  const title = titleInput.value.trim();
+  if (!title) {
+    alert('Please enter a page title.');
+    return;
+  }
+  const visibility = document.getElementById('new-page-visibility').value;
+  if (await sendExhibitPageRequest('/exhibit_pages', { title, visibility })) {
+    titleInput.value = '';
+  }
+}
+
+async function updateExhibitPage(update) {
+  if (await sendExhibitPageRequest('/update_exhibit_page', update)) {
+    alert('Page saved successfully!');
+  }
+}
+
+async function deleteExhibitPage(page) {
+  if (!confirm(`Delete the page "${page.title}"? Its content stays on your main page.`)) {
+    return;
+  }
+  await sendExhibitPageRequest('/delete_exhibit_page', { slug: page.slug });
+}
+
+// The layout being edited, and the content it can refer to
+let pageLayout = null;
+let loadedContent = [];
+
+const LAYOUT_SECTION_NAMES = {
+  intro: 'Intro',
+  bio: 'Bio',
+  avatar: 'Avatar',
+  links: 'Links',
+  pinned: 'Pinned Items',
+  content: 'Content Feed',
+};
+
+// Query string choosing the extra page being viewed, if any
+function pageQuery() {
+  const page = document.body.dataset.page;
+  return page ? `?page=${encodeURIComponent(page)}` : '';
+}
+
+async function fetchPageLayout() {
+  try {
+    const response = await fetch('/page_layout' + pageQuery(), {
+      method: 'GET',
+      credentials: 'include',
+    });
+    if (response.ok) {
+      pageLayout = await response.json();
+      displayPageLayout();
+    }
+  } catch (error) {
+    console.error('Error fetching page layout:', error);
+  }
+}
+
+function displayPageLayout() {
+  const list = document.getElementById('layoutSections');
+  list.innerHTML = '';
+
+  pageLayout.sections.forEach((section, index) => {
+    const item = document.createElement('li');
+
+    const name = document.createElement('strong');
+    name.textContent = LAYOUT_SECTION_NAMES[section.type];
+    item.appendChild(name);
+
+    const visibleLabel = document.createElement('label');
+    visibleLabel.classList.add('checkbox-label');
+    const visible = document.createElement('input');
+    visible.type = 'checkbox';
+    visible.checked = section.visible;
+    visible.addEventListener('change', () => { section.visible = visible.checked; });
+    visibleLabel.appendChild(visible);
+    visibleLabel.append(' Visible');
+    item.appendChild(visibleLabel);
+
+    appendLayoutEditor(item, section);
+
+    [['Up', -1], ['Down', 1]].forEach(([label, offset]) => {
+      const button = document.createElement('button');
+      button.textContent = label;
+      button.disabled = !pageLayout.sections[index + offset];
+      button.addEventListener('click', () => moveLayoutSection(index, offset));
+      item.appendChild(button);
+    });
+
+    if (section.type !== 'content') {
+      const removeButton = document.createElement('button');
+      removeButton.textContent = 'Remove';
+      removeButton.addEventListener('click', () => {
+        pageLayout.sections.splice(index, 1);
+        displayPageLayout();
+      });
+      item.appendChild(removeButton);
+    }
+
+    list.appendChild(item);
+  });
+
+  // Each kind of section can only be added once
+  const addSelect = document.getElementById('layout-add-type');
+  addSelect.innerHTML = '';
+  Object.entries(LAYOUT_SECTION_NAMES).forEach(([type, label]) => {
+    if (!pageLayout.sections.some(section => section.type === type)) {
+      const option = document.createElement('option');
+      option.value = type;
+      option.textContent = label;
+      addSelect.appendChild(option);
+    }
+  });
+}
+
+// Inputs for the fields of each kind of section
+function appendLayoutEditor(item, section) {
+  switch (section.type) {
+    case 'bio': {
+      const text = document.createElement('textarea');
+      text.rows = 4;
+      text.value = section.text;
+      text.addEventListener('input', () => { section.text = text.value; });
+      item.appendChild(text);
+      break;
+    }
+    case 'avatar': {
+      const select = document.createElement('select');
+      loadedContent
+        .filter(content => content.type === 'Gallery')
+        .forEach(gallery => gallery.images.forEach(image => {
+          const option = document.createElement('option');
+          option.value = image;
+          option.textContent = `${gallery.title}: ${image.split('/').pop()}`;
+          option.selected = image === section.image;
+          select.appendChild(option);
+        }));
+      section.image = select.value;
+      select.addEventListener('change', () => { section.image = select.value; });
+      item.appendChild(select);
+      break;
+    }
+    case 'links': {
+      // One "Label | URL" per line
+      const text = document.createElement('textarea');
+      text.rows = 4;
+      text.placeholder = 'My site | https://example.com';
+      text.value = section.links.map(link => `${link.label} | ${link.url}`).join('\n');
+      text.addEventListener('input', () => {
+        section.links = text.value
+          .split('\n')
+          .filter(line => line.trim())
+          .map(line => {
+            const separator = line.lastIndexOf('|');
+            return {
+              label: line.slice(0, separator).trim(),
+              url: line.slice(separator + 1).trim(),
+            };
+          });
+      });
+      item.appendChild(text);
+      break;
+    }
+    case 'pinned': {
+      const select = document.createElement('select');
+      select.multiple = true;
+      loadedContent.forEach(content => {
+        const option = document.createElement('option');
+        option.value = `${content.type}/${content.timestamp}`;
+        option.textContent = `${content.type}: ${content.title}`;
+        option.selected = section.items.includes(option.value);
+        select.appendChild(option);
+      });
+      select.addEventListener('change', () => {
+        section.items = Array.from(select.selectedOptions).map(option => option.value);
+      });
+      item.appendChild(select);
+      break;
+    }
+  }
+}
+
+function moveLayoutSection(index, offset) {
+  const sections = pageLayout.sections;
+  [sections[index], sections[index + offset]] = [sections[index + offset], sections[index]];
+  displayPageLayout();
+}
+
+function addLayoutSection() {
+  const type = document.getElementById('layout-add-type').value;
+  if (!type) {
+    return;
+  }
+  const fields = {
+    bio: { text: '' },
+    avatar: { image: '' },
+    links: { links: [] },
+    pinned: { items: [] },
+  };
+  pageLayout.sections.push({ type, visible: true, ...(fields[type] || {}) });
+  displayPageLayout();
+}
+
+async function savePageLayout() {
   try {
-    const response = await fetch('/upload_film', {
+    const response = await fetch('/page_layout' + pageQuery(), {
       method: 'POST',
       credentials: 'include',
-      body: formData,
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify(pageLayout)
     });
 
     if (!response.ok) {
       const errorText = await response.text();
-      alert('Error uploading film: ' + errorText);
-    } else {
-      alert('Film uploaded successfully!');
-      // Close the form
-      hideFilmForm();
-      // Clear the form
-      document.getElementById('film-title').value = '';
-      fileInput.value = '';
-      document.getElementById('film-file-name').textContent = 'No file selected';
-      // Refresh the films display
-      fetchAllContent();
+      alert('Error saving layout: ' + errorText);
+      return;
     }
+    // The sections are rendered by the server
+    location.reload();
   } catch (error) {
-    alert('Error uploading film: ' + error.message);
+    alert('Error saving layout: ' + error.message);
   }
 }
 
-async function fetchFilms() {
+async function fetchThemes() {
   try {
-    const response = await fetch('/get_films', {
+    const response = await fetch('/themes', {
       method: 'GET',
       credentials: 'include',
     });
-
     if (!response.ok) {
-      const errorText = await response.text();
-      alert('Error fetching films: ' + errorText);
-    } else {
-      const films = await response.json();
-      displayFilms(films);
+      return;
     }
+    const { current, themes } = await response.json();
+    const select = document.getElementById('edit-theme');
+    select.innerHTML = '';
+    themes.forEach(theme => {
+      const option = document.createElement('option');
+      option.value = theme.name;
+      option.textContent = theme.label;
+      option.selected = theme.name === current;
+      select.appendChild(option);
+    });
   } catch (error) {
-    alert('Error fetching films: ' + error.message);
+    console.error('Error fetching themes:', error);
   }
 }
 
-function displayFilms(films) {
-  const filmsDiv = document.getElementById('films');
-  filmsDiv.innerHTML = '';
-
-  films.forEach((film) => {
-    const filmSection = document.createElement('section');
-    const filmTitle = document.createElement('h3');
-    filmTitle.textContent = film.title;
-    filmSection.appendChild(filmTitle);
-
-    const videoElement = document.createElement('video');
-    videoElement.src = film.video_path;
-    videoElement.controls = true;
-    filmSection.appendChild(videoElement);
+async function setTheme(theme) {
+  try {
+    const response = await fetch('/set_theme', {
+      method: 'POST',
+      credentials: 'include',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ theme })
+    });
 
-    filmsDiv.appendChild(filmSection);
-  });
+    if (!response.ok) {
+      const errorText = await response.text();
+      alert('Error changing theme: ' + errorText);
+      return;
+    }
+    // Swap the theme stylesheet so the change shows without a reload
+    document.getElementById('theme-styles').href = `/static/themes/${theme}.css`;
+  } catch (error) {
+    alert('Error changing theme: ' + error.message);
+  }
 }
 
-function showAudioForm() {
-  document.getElementById('audioForm').classList.add('show');
-  document.getElementById('showAudioButton').classList.add('active');
+async function fetchCustomCss() {
+  try {
+    const response = await fetch('/custom_css', {
+      method: 'GET',
+      credentials: 'include',
+    });
+    if (response.ok) {
+      displayCustomCss(await response.json());
+    }
+  } catch (error) {
+    console.error('Error fetching custom CSS:', error);
+  }
 }
 
-function hideAudioForm() {
-  document.getElementById('audioForm').classList.remove('show');
-  document.getElementById('showAudioButton').classList.remove('active');
+function displayCustomCss({ css, versions }) {
+  document.getElementById('edit-custom-css').value = css;
+
+  const list = document.getElementById('customCssVersions');
+  list.innerHTML = '';
+  // The newest version is the current one, so only older ones can be restored
+  versions.slice(1).forEach(version => {
+    const item = document.createElement('li');
+    const saved = new Date(version.created_at).toLocaleString();
+    item.textContent = `Version ${version.version} (${saved}, ${version.size} bytes) `;
+    const restoreButton = document.createElement('button');
+    restoreButton.textContent = 'Restore';
+    restoreButton.onclick = () => rollbackCustomCss(version.version);
+    item.appendChild(restoreButton);
+    list.appendChild(item);
+  });
 }
 
-document.getElementById('audio-file').addEventListener('change', function() {
-  const file = this.files[0];
-  const fileNameElement = document.getElementById('audio-file-name');
-  if (file) {
-    fileNameElement.textContent = `Selected: ${file.name}`;
-  } else {
-    fileNameElement.textContent = 'No file selected';
-  }
-});
-
-async function uploadAudio() {
-  const audioTitle = document.getElementById('audio-title').value.trim();
-  const fileInput = document.getElementById('audio-file');
-  const file = fileInput.files[0];
-
-  if (!audioTitle) {
-    alert('Please enter an audio title.');
-    return;
-  }
-
+function loadCustomCssFile(input) {
+  const file = input.files[0];
   if (!file) {
-    alert('Please select an audio file.');
     return;
   }
+  const reader = new FileReader();
+  reader.onload = () => {
+    document.getElementById('edit-custom-css').value = reader.result;
+  };
+  reader.readAsText(file);
+}
 
-  // Check file size (max 50MB)
-  if (file.size > 50 * 1024 * 1024) {
-    alert(`File "${file.name}" is too big (must be under 50MB).`);
+async function saveCustomCss() {
+  const css = document.getElementById('edit-custom-css').value;
+  await sendCustomCss('/custom_css', { css });
+}
+
+async function rollbackCustomCss(version) {
+  if (!confirm(`Restore version ${version} of your custom CSS?`)) {
     return;
   }
+  await sendCustomCss('/rollback_custom_css', { version });
+}
 
-  // Create a FormData object
-  const formData = new FormData();
-  formData.append('audioTitle', audioTitle);
-  formData.append('audio', file);
-
+async function sendCustomCss(url, body) {
   try {
-    const response = await fetch('/upload_audio', {
+    const response = await fetch(url, {
       method: 'POST',
       credentials: 'include',
-      body: formData,
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify(body)
     });
 
     if (!response.ok) {
       const errorText = await response.text();
-      alert('Error uploading audio: ' + errorText);
-    } else {
-      alert('Audio uploaded successfully!');
-      // Close the form
-      hideAudioForm();
-      // Clear the form
-      document.getElementById('audio-title').value = '';
-      fileInput.value = '';
-      document.getElementById('audio-file-name').textContent = 'No file selected';
-      // Refresh the audios display
-      fetchAllContent();
+      alert('Error saving custom CSS: ' + errorText);
+      return;
     }
+    displayCustomCss(await response.json());
+    // Reload the stylesheet so the change shows straight away
+    const link = document.getElementById('custom-styles');
+    link.href = link.href.split('?')[0] + '?v=' + Date.now();
   } catch (error) {
-    alert('Error uploading audio: ' + error.message);
+    alert('Error saving custom CSS: ' + error.message);
   }
 }
 
-async function fetchAudios() {
+const SNAPSHOT_CHANGES = {
+  titles: 'changing the titles',
+  theme: 'changing the theme',
+  layout: 'changing the layout',
+  'custom CSS': 'changing the custom CSS',
+  restore: 'restoring an earlier version',
+};
+
+async function fetchPageSnapshots() {
   try {
-    const response = await fetch('/get_audios', {
+    const response = await fetch('/page_snapshots', {
       method: 'GET',
       credentials: 'include',
     });
-
-    if (!response.ok) {
-      const errorText = await response.text();
-      alert('Error fetching audios: ' + errorText);
-    } else {
-      const audios = await response.json();
-      displayAudios(audios);
+    if (response.ok) {
+      displayPageSnapshots(await response.json());
     }
   } catch (error) {
-    alert('Error fetching audios: ' + error.message);
+    console.error('Error fetching page history:', error);
   }
 }
 
-function displayAudios(audios) {
-  const audiosDiv = document.getElementById('audios');
-  audiosDiv.innerHTML = '';
+function displayPageSnapshots(snapshots) {
+  const list = document.getElementById('pageSnapshots');
+  list.innerHTML = '';
 
-  audios.forEach((audio) => {
-    const audioSection = document.createElement('section');
-    const audioTitle = document.createElement('h3');
-    audioTitle.textContent = audio.title;
-    audioSection.appendChild(audioTitle);
+  snapshots.forEach(snapshot => {
+    const item = document.createElement('li');
+    const saved = new Date(snapshot.created_at).toLocaleString();
+    item.textContent = `${saved}, before ${SNAPSHOT_CHANGES[snapshot.change] || snapshot.change} `;
 
-    const audioElement = document.createElement('audio');
-    audioElement.src = audio.audio_path;
-    audioElement.controls = true;
-    audioSection.appendChild(audioElement);
+    const previewLink = document.createElement('a');
+    previewLink.href = `/page_snapshots/${snapshot.id}/preview`;
+    previewLink.target = '_blank';
+    previewLink.textContent = 'Preview';
+    item.appendChild(previewLink);
 
-    audiosDiv.appendChild(audioSection);
+    const restoreButton = document.createElement('button');
+    restoreButton.textContent = 'Restore';
+    restoreButton.addEventListener('click', () => restorePageSnapshot(snapshot));
+    item.appendChild(restoreButton);
+
+    list.appendChild(item);
   });
 }
 
-function editPage() {
-  const sidebar = document.getElementById('editSidebar');
-  const overlay = document.getElementById('overlay');
-  sidebar.classList.add('show');
-  overlay.classList.add('show');
+async function restorePageSnapshot(snapshot) {
+  const saved = new Date(snapshot.created_at).toLocaleString();
+  if (!confirm(`Restore your page as it was on ${saved}?`)) {
+    return;
+  }
+  try {
+    const response = await fetch('/restore_page_snapshot', {
+      method: 'POST',
+      credentials: 'include',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ id: snapshot.id })
+    });
 
-  // Populate the input fields with current titles
-  const exhibitTitleInput = document.getElementById('edit-exhibit-title');
-  const mainTitleInput = document.getElementById('edit-main-title');
+    if (!response.ok) {
+      const errorText = await response.text();
+      alert('Error restoring page: ' + errorText);
+      return;
+    }
+    location.reload();
+  } catch (error) {
+    alert('Error restoring page: ' + error.message);
+  }
+}
 
-  // Get current titles from the page
-  const exhibitTitle = document.querySelector('header h1');
-  const mainTitle = document.querySelector('main h2');
+async function fetchStorageUsage() {
+  try {
+    const response = await fetch('/storage', {
+      method: 'GET',
+      credentials: 'include',
+    });
 
-  exhibitTitleInput.value = exhibitTitle.textContent;
-  mainTitleInput.value = mainTitle.textContent;
+    if (response.ok) {
+      const usage = await response.json();
+      const toMB = (bytes) => (bytes / (1024 * 1024)).toFixed(1);
+      document.getElementById('storage-usage').textContent =
+        `Storage used: ${toMB(usage.total)} MB of ${toMB(usage.quota)} MB`;
+    }
+  } catch (error) {
+    console.error('Error fetching storage usage:', error);
+  }
 }
 
 function closeSidebar() {
@@ -384,10 +1592,12 @@ function saveChanges() {
 
   // Update the page titles
   const exhibitTitle = document.querySelector('header h1');
-  const mainTitle = document.querySelector('main h2');
+  const mainTitle = document.getElementById('main-title');
 
   exhibitTitle.textContent = newExhibitTitle;
-  mainTitle.textContent = newMainTitle;
+  if (mainTitle) {
+    mainTitle.textContent = newMainTitle;
+  }
 
   // Close the sidebar
   closeSidebar();
@@ -416,7 +1626,30 @@ async function saveChangesToServer(exhibitTitle, mainTitle) {
   }
 }
 
+// Buttons name the function they run in data-click (or data-change for inputs), as the
+// page's Content-Security-Policy blocks inline event handlers
+function bindPageControls() {
+  document.querySelectorAll('[data-click]').forEach((element) => {
+    element.addEventListener('click', () => window[element.dataset.click]());
+  });
+  document.querySelectorAll('[data-change]').forEach((element) => {
+    element.addEventListener('change', () => window[element.dataset.change]());
+  });
+
+  document.getElementById('edit-theme').addEventListener('change', function() {
+    setTheme(this.value);
+  });
+  document.getElementById('custom-css-file').addEventListener('change', function() {
+    loadCustomCssFile(this);
+  });
+  document.getElementById('overlay').addEventListener('click', () => {
+    closeSidebar();
+    closeFriendsSidebar();
+  });
+}
+
 document.addEventListener("DOMContentLoaded", function() {
+  bindPageControls();
   const header = document.querySelector("header");
   const mainContent = document.querySelector("main");
   const headerHeight = header.offsetHeight;
@@ -437,6 +1670,47 @@ document.addEventListener("DOMContentLoaded", function() {
 function showGalleryForm() {
   document.getElementById('galleryForm').classList.add('show');
   document.getElementById('showGalleryButton').classList.add('active');
+  fetchMetadataSettings();
+}
+
+async function fetchMetadataSettings() {
+  try {
+    const response = await fetch('/get_metadata_settings', {
+      method: 'GET',
+      credentials: 'include',
+    });
+
+    if (response.ok) {
+      const settings = await response.json();
+      document.getElementById('keep-capture-time').checked = settings.keep_capture_time;
+      document.getElementById('keep-camera-model').checked = settings.keep_camera_model;
+    }
+  } catch (error) {
+    console.error('Error fetching metadata settings:', error);
+  }
+}
+
+async function saveMetadataSettings() {
+  const settings = {
+    keep_capture_time: document.getElementById('keep-capture-time').checked,
+    keep_camera_model: document.getElementById('keep-camera-model').checked,
+  };
+
+  try {
+    const response = await fetch('/save_metadata_settings', {
+      method: 'POST',
+      credentials: 'include',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify(settings)
+    });
+
+    if (!response.ok) {
+      const errorText = await response.text();
+      alert('Error saving metadata settings: ' + errorText);
+    }
+  } catch (error) {
+    alert('Error saving metadata settings: ' + error.message);
+  }
 }
 
 function hideGalleryForm() {
@@ -540,7 +1814,7 @@ function displayGalleries(galleries) {
 
     gallery.images.forEach((imagePath) => {
       const imgElement = document.createElement('img');
-      imgElement.src = imagePath;
+      imgElement.src = mediaUrl(imagePath);
       galleryDiv.appendChild(imgElement);
     });
 
@@ -642,8 +1916,13 @@ function displayTextPosts(textPosts) {
 }
 
 async function fetchAllContent() {
+  // Extra pages show only the items linked to them
+  const { owner, page } = document.body.dataset;
+  const url = page
+    ? `/page_content/${encodeURIComponent(owner)}/${encodeURIComponent(page)}`
+    : '/get_all_content';
   try {
-    const response = await fetch('/get_all_content', {
+    const response = await fetch(url, {
       method: 'GET',
       credentials: 'include',
     });
@@ -663,6 +1942,13 @@ async function fetchAllContent() {
 function displayAllContent(contentItems) {
   const contentFeed = document.getElementById('content-feed');
   contentFeed.innerHTML = '';
+  // Kept for the layout editor's avatar and pinned item choices
+  loadedContent = contentItems;
+
+  // Pinned items go in the pinned section, in the order they were pinned
+  const pinnedSection = document.getElementById('pinned-content');
+  const pinnedIds = pinnedSection ? pinnedSection.dataset.items.split(' ') : [];
+  const pinnedSections = new Map();
 
   contentItems.forEach((item) => {
     const contentSection = document.createElement('section');
@@ -687,7 +1973,16 @@ function displayAllContent(contentItems) {
 
         item.images.forEach((imagePath) => {
           const imgElement = document.createElement('img');
-          imgElement.src = imagePath;
+          imgElement.src = mediaUrl(imagePath);
+
+          // Show any camera details the owner chose to keep
+          const details = (item.photo_details || []).find((d) => d.image === imagePath);
+          if (details) {
+            imgElement.title = [details.capture_time, details.camera_model]
+              .filter(Boolean)
+              .join(' · ');
+          }
+
           galleryDiv.appendChild(imgElement);
         });
 
@@ -699,25 +1994,123 @@ function displayAllContent(contentItems) {
         contentSection.appendChild(contentTitle);
 
         const videoElement = document.createElement('video');
-        videoElement.src = item.video_path;
+        attachFilmSource(videoElement, item);
         videoElement.controls = true;
+        videoElement.preload = 'metadata';
+        if (item.poster) {
+          videoElement.poster = mediaUrl(item.poster);
+        }
+        appendCaptionTracks(videoElement, item);
         contentSection.appendChild(videoElement);
+        appendFilmDetails(contentSection, item);
+
+        const captionsButton = document.createElement('button');
+        captionsButton.textContent = 'Add Captions';
+        captionsButton.classList.add('visibility-content');
+        captionsButton.addEventListener('click', () => addCaptions(item));
+        contentSection.appendChild(captionsButton);
         break;
 
       case 'Audio':
         contentTitle.textContent = item.title;
         contentSection.appendChild(contentTitle);
 
+        appendAudioDetails(contentSection, item);
+
         const audioElement = document.createElement('audio');
-        audioElement.src = item.audio_path;
+        audioElement.src = mediaUrl(item.audio_path);
         audioElement.controls = true;
+        audioElement.preload = 'metadata';
         contentSection.appendChild(audioElement);
+
+        if (item.waveform) {
+          contentSection.appendChild(createWaveform(audioElement, item));
+        }
+        break;
+
+      case 'Album':
+        contentTitle.textContent = item.title;
+        contentSection.appendChild(contentTitle);
+
+        appendAlbum(contentSection, item);
         break;
 
       default:
         console.error('Unknown content type:', item.type);
     }
 
-    contentFeed.appendChild(contentSection);
+    const visibilityButton = document.createElement('button');
+    visibilityButton.textContent = item.public ? 'Make Friends-only' : 'Make Public';
+    visibilityButton.classList.add('visibility-content');
+    visibilityButton.addEventListener('click', () => setVisibility(item, !item.public));
+    contentSection.appendChild(visibilityButton);
+
+    const deleteButton = document.createElement('button');
+    deleteButton.textContent = 'Delete';
+    deleteButton.classList.add('delete-content');
+    deleteButton.addEventListener('click', () => deleteContent(item));
+    contentSection.appendChild(deleteButton);
+
+    const itemId = `${item.type}/${item.timestamp}`;
+    if (pinnedIds.includes(itemId)) {
+      pinnedSections.set(itemId, contentSection);
+    } else {
+      contentFeed.appendChild(contentSection);
+    }
+  });
+
+  pinnedIds.forEach(itemId => {
+    if (pinnedSections.has(itemId)) {
+      pinnedSection.appendChild(pinnedSections.get(itemId));
+    }
   });
 }
+
+async function deleteContent(item) {
+  if (!confirm(`Delete "${item.title}"?`)) {
+    return;
+  }
+
+  try {
+    const response = await fetch('/delete_content', {
+      method: 'POST',
+      headers: {
+        'Content-Type': 'application/json',
+      },
+      body: JSON.stringify({ type: item.type, timestamp: item.timestamp }),
+      credentials: 'include',
+    });
+
+    if (!response.ok) {
+      const errorText = await response.text();
+      throw new Error(errorText);
+    }
+
+    fetchAllContent();
+  } catch (error) {
+    alert('Error deleting content: ' + error.message);
+  }
+}
+
+// Public items appear in the open feed and can be seen by anyone with the link
+async function setVisibility(item, isPublic) {
+  try {
+    const response = await fetch('/set_visibility', {
+      method: 'POST',
+      headers: {
+        'Content-Type': 'application/json',
+      },
+      body: JSON.stringify({ type: item.type, timestamp: item.timestamp, public: isPublic }),
+      credentials: 'include',
+    });
+
+    if (!response.ok) {
+      const errorText = await response.text();
+      throw new Error(errorText);
+    }
+
+    fetchAllContent();
+  } catch (error) {
+    alert('Error changing visibility: ' + error.message);
+  }
+}